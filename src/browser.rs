@@ -0,0 +1,175 @@
+use image::{ImageBuffer, Rgb};
+
+use ::data::*;
+use ::color::*;
+use ::manager::sprite::{SECTION_SIZE, SECTION_BYTE_COUNT};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Arrangement {
+    /// tiles one after another, row by row
+    Linear,
+    /// tiles grouped into character sprite frames
+    Sprite,
+}
+
+/// A palette to draw browsed tiles with
+pub struct BrowserPalette {
+    pub name: String,
+    /// None for greyscale
+    pub offset: Option<u64>,
+}
+
+/// Every palette sbrx knows about, greyscale first
+pub fn known_palettes() -> Vec<BrowserPalette> {
+    let mut palettes = vec![BrowserPalette { name: "Greyscale".to_string(), offset: None }];
+    palettes.extend(CHARACTERS.iter().map(|c| BrowserPalette { name: c.name.to_string(), offset: Some(c.palette_offset) }));
+    palettes.extend([
+        ("Phi", PHI_PALETTE),
+        ("Dust cloud", DUST_CLOUD_PALETTE),
+        ("Sonic mine", SONIC_MINE_PALETTE),
+        ("Tails blaster", TAILS_BLASTER_PALETTE),
+        ("Shield", SHIELD_PALETTE),
+    ].iter().map(|&(name, offset)| BrowserPalette { name: name.to_string(), offset: Some(offset as u64) }));
+    palettes
+}
+
+/// Renders any part of the ROM as tiles, for finding graphics that aren't in data.rs yet
+pub struct TileBrowser {
+    rom: Vec<u8>,
+    pub offset: usize,
+    /// 4 or 8
+    pub bpp: usize,
+    /// tiles per row
+    pub width: usize,
+    pub rows: usize,
+    pub arrangement: Arrangement,
+    pub palette_index: usize,
+    color_cache: GBAColorCache,
+}
+
+impl TileBrowser {
+    pub fn new(rom: Vec<u8>) -> TileBrowser {
+        TileBrowser {
+            rom,
+            offset: 0,
+            bpp: 4,
+            width: 16,
+            rows: 16,
+            arrangement: Arrangement::Linear,
+            palette_index: 0,
+            color_cache: GBAColorCache::new(),
+        }
+    }
+
+    pub fn tile_bytes(&self) -> usize {
+        SECTION_BYTE_COUNT * self.bpp / 4
+    }
+
+    pub fn row_bytes(&self) -> usize {
+        match self.arrangement {
+            Arrangement::Linear => self.width * self.tile_bytes(),
+            // a row of sprite frames
+            Arrangement::Sprite => self.frames_per_row() * CHARACTER_SHAPE.section_order().len() * self.tile_bytes(),
+        }
+    }
+
+    pub fn page_bytes(&self) -> usize {
+        match self.arrangement {
+            Arrangement::Linear => self.row_bytes() * self.rows,
+            Arrangement::Sprite => self.row_bytes() * (self.rows / CHARACTER_SHAPE.height()).max(1),
+        }
+    }
+
+    fn frames_per_row(&self) -> usize {
+        (self.width / CHARACTER_SHAPE.width()).max(1)
+    }
+
+    /// Move by a number of bytes, staying inside the ROM
+    pub fn scroll(&mut self, bytes: i64) {
+        let offset = (self.offset as i64 + bytes).max(0) as usize;
+        self.offset = offset.min(self.rom.len().saturating_sub(1));
+    }
+
+    pub fn rom_length(&self) -> usize {
+        self.rom.len()
+    }
+
+    fn palette(&mut self, palettes: &[BrowserPalette]) -> Vec<Color> {
+        let colors = if self.bpp == 8 { 256 } else { 16 };
+        match palettes.get(self.palette_index).and_then(|palette| palette.offset) {
+            Some(offset) => (0..colors).map(|i| {
+                let index = offset as usize + i * 2;
+                let value = match self.rom.get(index..index + 2) {
+                    Some(bytes) => (bytes[1] as i32) << 8 | bytes[0] as i32,
+                    None => 0,
+                };
+                self.color_cache.gba_to_rgb(value)
+            }).collect(),
+            None => (0..colors).map(|i| {
+                let value = (i * 255 / (colors - 1)) as i32;
+                Color { r: value, g: value, b: value }
+            }).collect(),
+        }
+    }
+
+    /// Position of the n-th tile on the page in tiles
+    fn tile_position(&self, tile: usize) -> (usize, usize) {
+        match self.arrangement {
+            Arrangement::Linear => (tile % self.width, tile / self.width),
+            Arrangement::Sprite => {
+                let order = CHARACTER_SHAPE.section_order();
+                let (frame, section) = (tile / order.len(), order[tile % order.len()]);
+                let (frame_x, frame_y) = (frame % self.frames_per_row(), frame / self.frames_per_row());
+                (frame_x * CHARACTER_SHAPE.width() + section % CHARACTER_SHAPE.width(),
+                 frame_y * CHARACTER_SHAPE.height() + section / CHARACTER_SHAPE.width())
+            }
+        }
+    }
+
+    /// ROM offset of the tile at a pixel of the rendered page
+    pub fn offset_at(&self, x: u32, y: u32) -> Option<usize> {
+        let (tx, ty) = (x as usize / SECTION_SIZE, y as usize / SECTION_SIZE);
+        let tiles = self.page_bytes() / self.tile_bytes();
+        (0..tiles).find(|&tile| self.tile_position(tile) == (tx, ty))
+            .map(|tile| self.offset + tile * self.tile_bytes())
+            .filter(|&offset| offset < self.rom.len())
+    }
+
+    /// Size of a rendered page in pixels
+    pub fn pixel_size(&self) -> (u32, u32) {
+        let (width, height) = match self.arrangement {
+            Arrangement::Linear => (self.width, self.rows),
+            Arrangement::Sprite => (self.frames_per_row() * CHARACTER_SHAPE.width(),
+                                    (self.rows / CHARACTER_SHAPE.height()).max(1) * CHARACTER_SHAPE.height()),
+        };
+        ((width * SECTION_SIZE) as u32, (height * SECTION_SIZE) as u32)
+    }
+
+    pub fn render(&mut self, palettes: &[BrowserPalette]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let palette = self.palette(palettes);
+        let (width, height) = self.pixel_size();
+        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(width, height);
+
+        let tile_bytes = self.tile_bytes();
+        for tile in 0..self.page_bytes() / tile_bytes {
+            let start = self.offset + tile * tile_bytes;
+            let bytes = match self.rom.get(start..start + tile_bytes) {
+                Some(bytes) => bytes,
+                None => break,
+            };
+            let (tx, ty) = self.tile_position(tile);
+            for i in 0..SECTION_SIZE * SECTION_SIZE {
+                let index = if self.bpp == 8 {
+                    bytes[i] as usize
+                } else {
+                    // the left pixel is in the low nibble
+                    (bytes[i / 2] >> (4 * (i % 2)) & 0x0F) as usize
+                };
+                let c = palette[index];
+                let (x, y) = (tx * SECTION_SIZE + i % SECTION_SIZE, ty * SECTION_SIZE + i / SECTION_SIZE);
+                image.get_pixel_mut(x as u32, y as u32).data = [c.r as u8, c.g as u8, c.b as u8];
+            }
+        }
+        image
+    }
+}
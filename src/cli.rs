@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use image::open;
+
+use ::data::*;
+use ::engine::*;
+use ::diff::*;
+use ::export::{self, ExportOptions};
+use ::project::Project;
+use ::mods::{self, ModPackage, ModRecord};
+use ::script;
+use ::remap::{PaletteRemap, RemapHistory};
+use ::manager::asset;
+use ::manager::animation::AnimationManager;
+use ::manager::background::BackgroundManager;
+use ::manager::palette::{PaletteManager, variant_name};
+use ::manager::sprite::SpriteManager;
+use ::manager::pointer::describe_reference;
+
+const USAGE: &'static str = "usage:
+    sbrx [rom]                                   open the editor
+    sbrx animations <rom> <character>            list the animations of a character
+    sbrx script-table <rom> <character> [options] show the animation scripts found for a character
+        --confirm <offset>                       mark the table as checked so its scripts can be
+                                                 written
+    sbrx export <rom> <character> [options]      export a spritesheet as a png
+        --animation <name|index>                 only export a single animation
+        --output <file>                          file to write to
+    sbrx export-all <rom> <directory> [options]  export every character's sprites, palettes and
+                                                 metadata
+        --sheets <formats>                       sheet, strips and/or frames, default sheet
+        --palettes <formats>                     pal, jasc and/or bin, default pal
+    sbrx references <rom>                        list the pointers to every known asset
+    sbrx free-space <rom> [options]              list the free space sprites can be moved to
+        --confirm <offset>                       mark a region that looks empty as free, check
+                                                 it isn't used by the game first
+    sbrx palettes <rom> <character>              list every palette variant of a character
+    sbrx remap <rom> <character> <remap>         move palette indices in a character's sprites
+                                                 and palettes: swap <a> <b>, merge <from> <into>
+                                                 or the new position of all 16 indices
+    sbrx background <rom> custom [options]       render a background to a png
+        --output <file>                          file to write to
+    sbrx import-background <rom> custom <png> [options] replace a background with an edited png
+    sbrx diff <rom> <other rom> [options]        list what's different in another rom
+        --frames <directory>                     save changed frames before and after as pngs
+    sbrx project <directory> <base rom>          start a project from a clean rom
+    sbrx build <directory>                       build a project's rom
+    sbrx mods <rom>                              list the mods installed in a rom
+    sbrx install <rom> <mod directory>           install a mod
+        --force                                  even if it changes what another mod changed
+    sbrx uninstall <rom> <mod name>              put back what a mod changed
+    sbrx assets <rom> [kind]                     list every asset sbrx can edit
+    sbrx export-asset <rom> <kind> <asset>       export any asset, like: palette Sonic/1
+        --output <file>                          file to write to
+    sbrx import-asset <rom> <kind> <asset> <file> replace an asset with a file and save it
+    sbrx run <script> <rom>                      run a rhai script on a rom, see script.rs
+
+    no backgrounds have been located in the rom yet, they're opened as \"custom\" with:
+        --tiles <offset> --tile-count <n>        4bpp tile graphics
+        --map <offset> --width <n> --height <n>  text mode tilemap, size in tiles
+        --palette <offset> --banks <n>           16 color palette banks
+        --first-bank <n>                         bank the map uses for the first one, default 0
+        --compressed                             tiles and map are BIOS compressed";
+
+/// Options that are on or off and don't take a value
+const FLAGS: &'static [&'static str] = &["force", "compressed"];
+
+/// Command line arguments split into positional arguments, `--name value` options and `--name` flags
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Args {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if let Some(option) = arg.strip_prefix("--") {
+                let value = match FLAGS.contains(&option) {
+                    true => String::new(),
+                    false => iter.next().cloned().unwrap_or_default(),
+                };
+                options.insert(option.to_string(), value);
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Args { positional, options }
+    }
+
+    fn positional(&self, index: usize, name: &str) -> Result<&str, Error> {
+        self.positional.get(index).map(|s| s.as_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("missing {}\n{}", name, USAGE)))
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|s| s.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+}
+
+/// Run a command, returns None if the arguments don't name a command
+pub fn run(args: &[String]) -> Option<Result<(), Error>> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return None,
+    };
+    let args = Args::parse(&args[1..]);
+    match command {
+        "animations" => Some(list_animations(&args)),
+        "script-table" => Some(script_table(&args)),
+        "export" => Some(export(&args)),
+        "export-all" => Some(export_all(&args)),
+        "references" => Some(list_references(&args)),
+        "free-space" => Some(free_space(&args)),
+        "palettes" => Some(list_palettes(&args)),
+        "remap" => Some(remap_palette(&args)),
+        "background" => Some(export_background(&args)),
+        "import-background" => Some(import_background(&args)),
+        "diff" => Some(diff_roms(&args)),
+        "project" => Some(create_project(&args)),
+        "build" => Some(build_project(&args)),
+        "mods" => Some(list_mods(&args)),
+        "install" => Some(install_mod(&args)),
+        "uninstall" => Some(uninstall_mod(&args)),
+        "assets" => Some(list_assets(&args)),
+        "export-asset" => Some(export_asset(&args)),
+        "import-asset" => Some(import_asset(&args)),
+        "run" => Some(run_script(&args)),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Some(Ok(()))
+        }
+        _ => None,
+    }
+}
+
+fn character_arg(args: &Args, index: usize) -> Result<Character, Error> {
+    let name = args.positional(index, "character")?;
+    find_character(name).ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown character {}", name)))
+}
+
+fn animation_arg(args: &Args, engine: &Engine, character: &Character) -> Result<Option<usize>, Error> {
+    match args.option("animation") {
+        Some(key) => character.find_animation(key, engine.registry.get::<SpriteManager>()?.layout(character).len()).map(Some)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} has no animation {}", character.name, key))),
+        None => Ok(None),
+    }
+}
+
+fn list_animations(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open_read_only(args.positional(0, "rom")?)?;
+    let character = character_arg(args, 1)?;
+    for (index, &(_, frames)) in engine.registry.get::<SpriteManager>()?.layout(&character).iter().enumerate() {
+        let info = character.animation_info(index);
+        println!("{:<16} {:>3} frames {:<8} {}", character.animation_label(index), frames,
+                 if info.looping { "loop" } else { "" }, info.description);
+    }
+    Ok(())
+}
+
+fn script_table(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    let character = character_arg(args, 1)?;
+    let animations = engine.registry.get::<AnimationManager>()?;
+    let (table, confirmed) = animations.table(&character)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}'s animation table wasn't found", character.name)))?;
+
+    if args.option("confirm").is_some() {
+        let offset = number_option(args, "confirm")? as u64;
+        if offset != table {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{}'s animation table is at {:#X}, not {:#X}", character.name, table, offset)));
+        }
+        engine.space_manager.confirm_script_table(&character, table)?;
+        println!("Confirmed {}'s animation table at {:#X}", character.name, table);
+    } else {
+        println!("{}'s animation table is at {:#X}, {}", character.name, table, if confirmed { "confirmed" } else { "not confirmed" });
+    }
+
+    for (index, script) in animations.load_scripts(&character)?.iter().enumerate() {
+        let steps: Vec<String> = script.frames.iter().map(|step| format!("{}:{}", step.frame, step.duration)).collect();
+        println!("{:<16} {:#08X}  {} {}", character.animation_label(index), script.offset.unwrap_or(0), steps.join(" "),
+                 script.loop_start.map_or("end".to_string(), |start| format!("loop {}", start)));
+    }
+    Ok(())
+}
+
+fn export(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open(args.positional(0, "rom")?)?;
+    let character = character_arg(args, 1)?;
+    let animation = animation_arg(args, &engine, &character)?;
+
+    let palette = engine.registry.get_mut::<PaletteManager>()?.load_palette_colors(character.name.to_string());
+    let sprites = engine.registry.get::<SpriteManager>()?;
+    let spritesheet = sprites.load_spritesheet(&character)?;
+
+    let (image, default_output) = match animation {
+        Some(index) => {
+            let label = character.animation_file_label(index);
+            (spritesheet.animations[index].to_img(&palette[..]), format!("{}_{}.png", character.name, label))
+        }
+        None => (spritesheet.to_labelled_img(&palette[..], &character), format!("{}.png", character.name)),
+    };
+
+    let output = args.option("output").map(|s| s.to_string()).unwrap_or(default_output);
+    image.save(&output)?;
+    println!("Saved {}", output);
+    Ok(())
+}
+
+fn export_all(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open_read_only(args.positional(0, "rom")?)?;
+    let directory = Path::new(args.positional(1, "directory")?);
+    let options = ExportOptions::parse(args.option("sheets").unwrap_or("sheet"), args.option("palettes").unwrap_or("pal"))?;
+    export::export_all(&mut engine, directory, &options)?;
+    Ok(())
+}
+
+fn list_references(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open(args.positional(0, "rom")?)?;
+    for target in engine.pointer_manager.targets.iter() {
+        let references: Vec<String> = engine.references_to(target.offset).iter().map(describe_reference).collect();
+        println!("{:<28} {:#08X}  {}", target.name, target.offset,
+                 if references.is_empty() { "-".to_string() } else { references.join(", ") });
+    }
+    Ok(())
+}
+
+fn free_space(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    if args.option("confirm").is_some() {
+        let (offset, length) = engine.space_manager.confirm(number_option(args, "confirm")? as u64)?;
+        println!("Confirmed {:#X} bytes at {:#X} as free", length, offset);
+    }
+    for &(offset, length) in engine.space_manager.free.iter() {
+        println!("free      {:#08X}  {:#X} bytes", offset, length);
+    }
+    for &(offset, length) in engine.space_manager.candidates.iter() {
+        println!("unchecked {:#08X}  {:#X} bytes", offset, length);
+    }
+    Ok(())
+}
+
+fn list_palettes(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open(args.positional(0, "rom")?)?;
+    let character = character_arg(args, 1)?;
+    let palettes = engine.registry.get::<PaletteManager>()?;
+    for variant in 0..palettes.variant_count(&character) {
+        let offset = palettes.variant_offset(&character, variant).unwrap_or(0);
+        let colors: Vec<String> = palettes.load_palette_i32(variant_name(&character, variant))
+            .iter().map(|color| format!("{:04X}", color)).collect();
+        println!("{:<3} {:#08X}  {}", variant, offset, colors.join(" "));
+    }
+    // nothing points to these, they might not be palettes at all so they're never written
+    for &offset in palettes.unconfirmed.get(character.name).map_or(&[][..], |offsets| &offsets[..]) {
+        println!("?   {:#08X}  looks like a variant but nothing points to it", offset);
+    }
+    Ok(())
+}
+
+fn remap_palette(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    let character = character_arg(args, 1)?;
+    args.positional(2, "remap")?;
+    let remap = PaletteRemap::parse(&args.positional[2..].join(" "))?;
+
+    RemapHistory::new().apply(&mut engine, &character, &remap)?;
+    engine.save_all()?;
+    println!("Remapped {}", character.name);
+    Ok(())
+}
+
+/// A number option, either decimal or hex with a 0x prefix
+fn number_option(args: &Args, name: &str) -> Result<usize, Error> {
+    let value = args.option(name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("missing --{}\n{}", name, USAGE)))?;
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        usize::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<usize>()
+    };
+    parsed.map_err(|_| Error::new(ErrorKind::InvalidInput, format!("--{} {} isn't a number", name, value)))
+}
+
+fn background_arg(args: &Args, index: usize) -> Result<Background, Error> {
+    let name = args.positional(index, "background")?;
+    if let Some(background) = find_background(name) {
+        return Ok(background);
+    }
+    if name != "custom" {
+        return Err(Error::new(ErrorKind::InvalidInput, format!(
+            "unknown background {}, none are known by name yet so open it as custom with its offsets\n{}", name, USAGE)));
+    }
+    Ok(Background {
+        name: "custom",
+        tiles_offset: number_option(args, "tiles")? as u64,
+        tile_count: number_option(args, "tile-count").unwrap_or(0),
+        map_offset: number_option(args, "map")? as u64,
+        width: number_option(args, "width")?,
+        height: number_option(args, "height")?,
+        palette_offset: number_option(args, "palette")? as u64,
+        palette_banks: number_option(args, "banks").unwrap_or(1),
+        first_bank: number_option(args, "first-bank").unwrap_or(0),
+        compressed: args.flag("compressed"),
+    })
+}
+
+fn export_background(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open(args.positional(0, "rom")?)?;
+    let background = background_arg(args, 1)?;
+    let mut backgrounds = engine.registry.get_mut::<BackgroundManager>()?;
+    backgrounds.open_background(&background)?;
+
+    let output = args.option("output").map(|s| s.to_string()).unwrap_or(format!("{}.png", background.name));
+    backgrounds.load_background(&background)?.to_img().save(&output)?;
+    println!("Saved {}", output);
+    Ok(())
+}
+
+fn import_background(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    let background = background_arg(args, 1)?;
+    let image = open(args.positional(2, "png")?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?
+        .to_rgb();
+
+    engine.before_write()?;
+    let mut backgrounds = engine.registry.get_mut::<BackgroundManager>()?;
+    backgrounds.open_background(&background)?;
+    backgrounds.load_background_mut(&background)?.import_img(&image)?;
+    backgrounds.write_background(&background, &engine.space_manager.assets)?;
+    println!("Wrote {} to the ROM", background.name);
+    Ok(())
+}
+
+fn diff_roms(args: &Args) -> Result<(), Error> {
+    let before = Engine::open_read_only(args.positional(0, "rom")?)?;
+    let after = Engine::open_read_only(args.positional(1, "other rom")?)?;
+    let diff = RomDiff::compare(&before, &after)?;
+    for line in diff.report() {
+        println!("{}", line);
+    }
+
+    if let Some(directory) = args.option("frames") {
+        create_dir_all(directory)?;
+        for sprites in diff.sprites.iter() {
+            let character = sprites.character;
+            let palette_a = before.registry.get_mut::<PaletteManager>()?.load_palette_colors(character.name.to_string());
+            let palette_b = after.registry.get_mut::<PaletteManager>()?.load_palette_colors(character.name.to_string());
+            let (sprites_a, sprites_b) = (before.registry.get::<SpriteManager>()?, after.registry.get::<SpriteManager>()?);
+            let (sheet_a, sheet_b) = (sprites_a.load_spritesheet(&character)?, sprites_b.load_spritesheet(&character)?);
+            for &(animation, frame) in sprites.frames.iter() {
+                let image = before_after(&sheet_a.animations[animation].frames[frame], &palette_a[..],
+                                         &sheet_b.animations[animation].frames[frame], &palette_b[..]);
+                let label = character.animation_file_label(animation);
+                image.save(Path::new(directory).join(format!("{}_{}_{}.png", character.name, label, frame)))?;
+            }
+        }
+        println!("Saved changed frames to {}", directory);
+    }
+    Ok(())
+}
+
+fn create_project(args: &Args) -> Result<(), Error> {
+    let directory = Path::new(args.positional(0, "directory")?);
+    Project::create(directory, Path::new(args.positional(1, "base rom")?))?;
+    Ok(())
+}
+
+fn build_project(args: &Args) -> Result<(), Error> {
+    Project::load(Path::new(args.positional(0, "directory")?))?.build()
+}
+
+fn list_mods(args: &Args) -> Result<(), Error> {
+    let record = ModRecord::load(args.positional(0, "rom")?)?;
+    if record.mods.is_empty() {
+        println!("No mods installed");
+    }
+    for installed in record.mods.iter() {
+        println!("{:<24} {}", installed.name, installed.assets.join(", "));
+    }
+    Ok(())
+}
+
+fn install_mod(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    let package = ModPackage::load(Path::new(args.positional(1, "mod directory")?))?;
+    mods::install(&mut engine, &package, args.flag("force"))
+}
+
+fn uninstall_mod(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    mods::uninstall(&mut engine, args.positional(1, "mod name")?)
+}
+
+fn list_assets(args: &Args) -> Result<(), Error> {
+    let engine = Engine::open_read_only(args.positional(0, "rom")?)?;
+    let managers = match args.positional.get(1) {
+        Some(kind) => vec![engine.registry.find(kind)?],
+        None => engine.registry.managers(),
+    };
+    for manager in managers {
+        let manager = manager.borrow();
+        let assets = manager.assets(&engine);
+        let files = manager.extension().map_or("can't be exported".to_string(), |extension| format!(".{} files", extension));
+        println!("{} ({} assets, {})", manager.kind(), assets.len(), files);
+        for asset in assets.iter() {
+            println!("    {}", asset);
+        }
+    }
+    Ok(())
+}
+
+fn export_asset(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open_read_only(args.positional(0, "rom")?)?;
+    let manager = engine.registry.find(args.positional(1, "kind")?)?;
+    let manager = manager.borrow();
+    let asset = args.positional(2, "asset")?;
+    let default_output = format!("{}.{}", asset.replace('/', "."), manager.extension().unwrap_or("bin"));
+    let output = args.option("output").map(|s| s.to_string()).unwrap_or(default_output);
+    manager.export(&mut engine, asset, Path::new(&output))?;
+    println!("Saved {}", output);
+    Ok(())
+}
+
+fn import_asset(args: &Args) -> Result<(), Error> {
+    let mut engine = Engine::open(args.positional(0, "rom")?)?;
+    let manager = engine.registry.find(args.positional(1, "kind")?)?;
+    let asset = args.positional(2, "asset")?;
+    let kind = {
+        let mut manager = manager.borrow_mut();
+        manager.import(&mut engine, asset, Path::new(args.positional(3, "file")?))?;
+        manager.kind()
+    };
+    engine.save_all()?;
+    println!("Wrote {} to the ROM", asset::full_name(asset, kind));
+    Ok(())
+}
+
+fn run_script(args: &Args) -> Result<(), Error> {
+    let script = Path::new(args.positional(0, "script")?);
+    let engine = Engine::open(args.positional(1, "rom")?)?;
+    let (engine, result) = script::run_file(engine, script, |text| println!("{}", text));
+    result?;
+    let unsaved = engine.unsaved();
+    if !unsaved.is_empty() {
+        println!("The script didn't save: {}", unsaved.join(", "));
+    }
+    Ok(())
+}
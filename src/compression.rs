@@ -0,0 +1,554 @@
+// GBA BIOS compression formats
+//
+// Every format starts with a 4 byte header: bits 4-7 are the type, bits 8-31 the decompressed size
+// and for Huffman bits 0-3 are the number of bits per symbol (4 or 8).
+//
+//     LZ77    0x10  flag byte (MSB first) for every 8 blocks, 1 = 2 byte back reference
+//                   (length - 3 in the top 4 bits, distance - 1 in the other 12), 0 = literal byte
+//     Huffman 0x20  tree size, node table, then the codes packed MSB first into little endian words
+//     RLE     0x30  flag byte, top bit set = next byte repeated (flag & 0x7F) + 3 times,
+//                   otherwise (flag & 0x7F) + 1 literal bytes follow
+//
+// The BIOS' VRAM variants write 16 bits at a time, so back references have to reach at least 2
+// bytes back. The LZ77 encoder never uses a distance of 1, so its output works with both.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+pub const LZ77: u8 = 0x10;
+pub const HUFFMAN: u8 = 0x20;
+pub const RLE: u8 = 0x30;
+
+const LZ77_WINDOW: usize = 0x1000;
+const LZ77_MIN_LENGTH: usize = 3;
+const LZ77_MAX_LENGTH: usize = 0x12;
+/// VRAM safe distance, see above
+const LZ77_MIN_DISTANCE: usize = 2;
+
+const RLE_MIN_RUN: usize = 3;
+const RLE_MAX_RUN: usize = 0x82;
+const RLE_MAX_LITERALS: usize = 0x80;
+
+/// node offsets in the Huffman tree are 6 bits
+const HUFFMAN_MAX_OFFSET: usize = 0x3F;
+const HUFFMAN_LEAF_0: u8 = 0x80;
+const HUFFMAN_LEAF_1: u8 = 0x40;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn header(kind: u8, size: usize) -> Vec<u8> {
+    vec![kind, size as u8, (size >> 8) as u8, (size >> 16) as u8]
+}
+
+/// pad compressed data to a whole number of words
+fn align(mut data: Vec<u8>) -> Vec<u8> {
+    while data.len() % 4 != 0 {
+        data.push(0);
+    }
+    data
+}
+
+/// (type, decompressed size) of compressed data
+pub fn read_header(data: &[u8]) -> Result<(u8, usize), Error> {
+    if data.len() < 4 {
+        return Err(invalid("compressed data is missing its header"));
+    }
+    let size = data[1] as usize | (data[2] as usize) << 8 | (data[3] as usize) << 16;
+    Ok((data[0], size))
+}
+
+/// Decompress data in any of the supported formats, returns the data and the number of bytes read
+pub fn decompress(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let (kind, _) = read_header(data)?;
+    match kind & 0xF0 {
+        LZ77 => decompress_lz77(data),
+        HUFFMAN => decompress_huffman(data),
+        RLE => decompress_rle(data),
+        _ => Err(invalid(&format!("unknown compression type {:#X}", kind))),
+    }
+}
+
+/// Compress data in the format named by a header's type byte, so edited data can be stored the
+/// same way it was read
+pub fn compress(data: &[u8], kind: u8) -> Result<Vec<u8>, Error> {
+    match kind & 0xF0 {
+        LZ77 => Ok(compress_lz77(data)),
+        HUFFMAN => compress_huffman(data, (kind & 0x0F) as usize),
+        RLE => Ok(compress_rle(data)),
+        _ => Err(invalid(&format!("unknown compression type {:#X}", kind))),
+    }
+}
+
+/// Decompress data stored at an offset in the ROM, returns the data and its compressed length
+pub fn decompress_at(rom: &[u8], offset: usize) -> Result<(Vec<u8>, usize), Error> {
+    if offset >= rom.len() {
+        return Err(invalid(&format!("{:#X} is outside of the ROM", offset)));
+    }
+    decompress(&rom[offset..])
+}
+
+pub fn decompress_lz77(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let (kind, size) = read_header(data)?;
+    if kind != LZ77 {
+        return Err(invalid("not LZ77 compressed"));
+    }
+    let truncated = || invalid("LZ77 data ends early");
+
+    let mut out = Vec::with_capacity(size);
+    let mut position = 4;
+    while out.len() < size {
+        let flags = *data.get(position).ok_or_else(truncated)?;
+        position += 1;
+        for bit in (0..8).rev() {
+            if out.len() >= size {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                out.push(*data.get(position).ok_or_else(truncated)?);
+                position += 1;
+                continue;
+            }
+
+            let (a, b) = match (data.get(position), data.get(position + 1)) {
+                (Some(&a), Some(&b)) => (a as usize, b as usize),
+                _ => return Err(truncated()),
+            };
+            position += 2;
+            let length = (a >> 4) + LZ77_MIN_LENGTH;
+            let distance = ((a & 0x0F) << 8 | b) + 1;
+            if distance > out.len() {
+                return Err(invalid("LZ77 reference before the start of the data"));
+            }
+            // references can overlap the bytes they produce, so copy one at a time
+            for _ in 0..length.min(size - out.len()) {
+                let byte = out[out.len() - distance];
+                out.push(byte);
+            }
+        }
+    }
+    Ok((out, position))
+}
+
+pub fn compress_lz77(data: &[u8]) -> Vec<u8> {
+    let mut out = header(LZ77, data.len());
+    let mut position = 0;
+    while position < data.len() {
+        let flag_index = out.len();
+        out.push(0);
+        for bit in (0..8).rev() {
+            if position >= data.len() {
+                break;
+            }
+
+            // longest match in the window, the earliest one wins
+            let mut best = (0, 0);
+            let max_length = LZ77_MAX_LENGTH.min(data.len() - position);
+            for distance in LZ77_MIN_DISTANCE..LZ77_WINDOW.min(position) + 1 {
+                let start = position - distance;
+                let length = (0..max_length).take_while(|&i| data[start + i] == data[position + i]).count();
+                if length > best.0 {
+                    best = (length, distance);
+                    if length == max_length {
+                        break;
+                    }
+                }
+            }
+
+            let (length, distance) = best;
+            if length >= LZ77_MIN_LENGTH {
+                out[flag_index] |= 1 << bit;
+                out.push(((length - LZ77_MIN_LENGTH) << 4 | (distance - 1) >> 8) as u8);
+                out.push((distance - 1) as u8);
+                position += length;
+            } else {
+                out.push(data[position]);
+                position += 1;
+            }
+        }
+    }
+    align(out)
+}
+
+pub fn decompress_rle(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let (kind, size) = read_header(data)?;
+    if kind != RLE {
+        return Err(invalid("not RLE compressed"));
+    }
+    let truncated = || invalid("RLE data ends early");
+
+    let mut out = Vec::with_capacity(size);
+    let mut position = 4;
+    while out.len() < size {
+        let flag = *data.get(position).ok_or_else(truncated)? as usize;
+        position += 1;
+        if flag & 0x80 != 0 {
+            let byte = *data.get(position).ok_or_else(truncated)?;
+            position += 1;
+            for _ in 0..((flag & 0x7F) + RLE_MIN_RUN).min(size - out.len()) {
+                out.push(byte);
+            }
+        } else {
+            let length = (flag & 0x7F) + 1;
+            if position + length > data.len() {
+                return Err(truncated());
+            }
+            let length = length.min(size - out.len());
+            out.extend_from_slice(&data[position..position + length]);
+            position += (flag & 0x7F) + 1;
+        }
+    }
+    Ok((out, position))
+}
+
+pub fn compress_rle(data: &[u8]) -> Vec<u8> {
+    let mut out = header(RLE, data.len());
+    let mut literals: Vec<u8> = Vec::new();
+
+    fn flush(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+        if !literals.is_empty() {
+            out.push((literals.len() - 1) as u8);
+            out.extend(literals.drain(..));
+        }
+    }
+
+    let mut position = 0;
+    while position < data.len() {
+        let byte = data[position];
+        let run = data[position..].iter().take(RLE_MAX_RUN).take_while(|&&b| b == byte).count();
+        if run >= RLE_MIN_RUN {
+            flush(&mut out, &mut literals);
+            out.push(0x80 | (run - RLE_MIN_RUN) as u8);
+            out.push(byte);
+            position += run;
+        } else {
+            literals.push(byte);
+            if literals.len() == RLE_MAX_LITERALS {
+                flush(&mut out, &mut literals);
+            }
+            position += 1;
+        }
+    }
+    flush(&mut out, &mut literals);
+    align(out)
+}
+
+pub fn decompress_huffman(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    let (kind, size) = read_header(data)?;
+    let bits = (kind & 0x0F) as usize;
+    if kind & 0xF0 != HUFFMAN || (bits != 4 && bits != 8) {
+        return Err(invalid("not Huffman compressed"));
+    }
+    let truncated = || invalid("Huffman data ends early");
+
+    let tree_size = *data.get(4).ok_or_else(truncated)? as usize;
+    let root = 5;
+    let mut position = 4 + (tree_size + 1) * 2;
+
+    let mut out = Vec::with_capacity(size);
+    let mut pending: u8 = 0;
+    let mut pending_bits = 0;
+
+    let mut node = root;
+    let mut node_byte = *data.get(root).ok_or_else(truncated)?;
+    'words: while out.len() < size {
+        let word = match data.get(position..position + 4) {
+            Some(word) => word[0] as u32 | (word[1] as u32) << 8 | (word[2] as u32) << 16 | (word[3] as u32) << 24,
+            None => return Err(truncated()),
+        };
+        position += 4;
+
+        for bit in (0..32).rev() {
+            let right = (word >> bit) & 1 == 1;
+            let child = (node & !1) + (node_byte & 0x3F) as usize * 2 + 2 + right as usize;
+            let is_leaf = node_byte & if right { HUFFMAN_LEAF_1 } else { HUFFMAN_LEAF_0 } != 0;
+            let value = *data.get(child).ok_or_else(truncated)?;
+
+            if !is_leaf {
+                node = child;
+                node_byte = value;
+                continue;
+            }
+
+            // 4 bit symbols fill the low nibble first
+            pending |= value << pending_bits;
+            pending_bits += bits;
+            if pending_bits == 8 {
+                out.push(pending);
+                pending = 0;
+                pending_bits = 0;
+                if out.len() >= size {
+                    break 'words;
+                }
+            }
+            node = root;
+            node_byte = data[root];
+        }
+    }
+    Ok((out, position))
+}
+
+enum HuffmanNode {
+    Leaf(u8),
+    Branch(Box<HuffmanNode>, Box<HuffmanNode>),
+}
+
+impl HuffmanNode {
+    fn codes(&self, prefix: Vec<bool>, codes: &mut HashMap<u8, Vec<bool>>) {
+        match *self {
+            HuffmanNode::Leaf(value) => { codes.insert(value, prefix); }
+            HuffmanNode::Branch(ref zero, ref one) => {
+                let mut zero_prefix = prefix.clone();
+                zero_prefix.push(false);
+                zero.codes(zero_prefix, codes);
+                let mut one_prefix = prefix;
+                one_prefix.push(true);
+                one.codes(one_prefix, codes);
+            }
+        }
+    }
+
+    /// number of branches in the tree, each one needs a pair in the node table
+    fn branches(&self) -> usize {
+        match *self {
+            HuffmanNode::Leaf(_) => 0,
+            HuffmanNode::Branch(ref zero, ref one) => 1 + zero.branches() + one.branches(),
+        }
+    }
+}
+
+fn build_huffman_tree(symbols: &[u8]) -> HuffmanNode {
+    let mut counts: HashMap<u8, usize> = HashMap::new();
+    for &symbol in symbols.iter() {
+        *counts.entry(symbol).or_insert(0) += 1;
+    }
+    // a tree needs at least two leaves
+    while counts.len() < 2 {
+        let unused = (0..=255u8).find(|symbol| !counts.contains_key(symbol)).unwrap();
+        counts.insert(unused, 0);
+    }
+
+    let mut nodes: Vec<(usize, HuffmanNode)> = counts.into_iter()
+        .map(|(symbol, count)| (count, HuffmanNode::Leaf(symbol)))
+        .collect();
+    while nodes.len() > 1 {
+        // least frequent last, ties broken by symbol so the output doesn't depend on hash order
+        nodes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| first_symbol(&b.1).cmp(&first_symbol(&a.1))));
+        let (one_count, one) = nodes.pop().unwrap();
+        let (zero_count, zero) = nodes.pop().unwrap();
+        nodes.push((zero_count + one_count, HuffmanNode::Branch(Box::new(zero), Box::new(one))));
+    }
+    nodes.pop().unwrap().1
+}
+
+fn first_symbol(node: &HuffmanNode) -> u8 {
+    match *node {
+        HuffmanNode::Leaf(value) => value,
+        HuffmanNode::Branch(ref zero, _) => first_symbol(zero),
+    }
+}
+
+/// Lay the tree out as a node table. A branch's children have to be stored in a pair at most 63
+/// pairs after its own, so branches close to that limit are placed first and otherwise the ones
+/// with the smallest subtrees go first to keep the number of waiting branches low.
+fn serialize_huffman_tree(root: &HuffmanNode) -> Result<Vec<u8>, Error> {
+    let pair_count = root.branches();
+    let mut table = vec![0u8; 1 + pair_count * 2];
+
+    // (table index of the node, pair it's in, branch waiting for its children)
+    let mut waiting: Vec<(usize, usize, &HuffmanNode)> = vec![(0, 0, root)];
+    for pair in 0..pair_count {
+        let urgent = waiting.iter().enumerate()
+            .filter(|&(_, &(_, parent_pair, _))| parent_pair + HUFFMAN_MAX_OFFSET < pair + waiting.len())
+            .min_by_key(|&(_, &(_, parent_pair, _))| parent_pair)
+            .map(|(i, _)| i);
+        let next = urgent.unwrap_or_else(|| waiting.iter().enumerate()
+            .min_by_key(|&(_, &(_, parent_pair, node))| (node.branches(), parent_pair))
+            .map(|(i, _)| i)
+            .unwrap());
+        let (index, parent_pair, node) = waiting.remove(next);
+
+        // the root is stored alone before the first pair, treat it as pair 0
+        let offset = if index == 0 { pair } else { pair - parent_pair - 1 };
+        if offset > HUFFMAN_MAX_OFFSET {
+            return Err(invalid("Huffman tree is too unbalanced to store"));
+        }
+        table[index] |= offset as u8;
+
+        if let HuffmanNode::Branch(ref zero, ref one) = *node {
+            for (side, child) in [zero, one].iter().enumerate() {
+                let child_index = 1 + pair * 2 + side;
+                match ***child {
+                    HuffmanNode::Leaf(value) => {
+                        table[index] |= if side == 0 { HUFFMAN_LEAF_0 } else { HUFFMAN_LEAF_1 };
+                        table[child_index] = value;
+                    }
+                    HuffmanNode::Branch(_, _) => waiting.push((child_index, pair, child)),
+                }
+            }
+        }
+    }
+    Ok(table)
+}
+
+/// Huffman encode with 4 or 8 bit symbols
+pub fn compress_huffman(data: &[u8], bits: usize) -> Result<Vec<u8>, Error> {
+    let symbols: Vec<u8> = match bits {
+        4 => data.iter().flat_map(|&byte| vec![byte & 0x0F, byte >> 4]).collect(),
+        8 => data.to_vec(),
+        _ => return Err(invalid("Huffman symbols have to be 4 or 8 bits")),
+    };
+
+    let tree = build_huffman_tree(&symbols[..]);
+    let table = serialize_huffman_tree(&tree)?;
+    let mut codes = HashMap::new();
+    tree.codes(Vec::new(), &mut codes);
+
+    let mut out = header(HUFFMAN | bits as u8, data.len());
+    // the tree size byte and the table have to end on a word boundary
+    let mut tree_bytes = vec![0u8];
+    tree_bytes.extend(table);
+    while (4 + tree_bytes.len()) % 4 != 0 {
+        tree_bytes.push(0);
+    }
+    tree_bytes[0] = (tree_bytes.len() / 2 - 1) as u8;
+    out.extend(tree_bytes);
+
+    let mut word: u32 = 0;
+    let mut word_bits = 0;
+    for symbol in symbols.iter() {
+        for &bit in codes[symbol].iter() {
+            word |= (bit as u32) << (31 - word_bits);
+            word_bits += 1;
+            if word_bits == 32 {
+                out.extend_from_slice(&[word as u8, (word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8]);
+                word = 0;
+                word_bits = 0;
+            }
+        }
+    }
+    if word_bits > 0 {
+        out.extend_from_slice(&[word as u8, (word >> 8) as u8, (word >> 16) as u8, (word >> 24) as u8]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// deterministic bytes that don't compress well
+    fn noise(length: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    /// runs and repeats like in tiles and maps
+    fn tiles(length: usize) -> Vec<u8> {
+        (0..length).map(|i| match (i / 32) % 4 {
+            0 => 0,
+            1 => (i % 7) as u8,
+            2 => 0x11,
+            _ => (i / 3) as u8,
+        }).collect()
+    }
+
+    fn samples() -> Vec<Vec<u8>> {
+        vec![Vec::new(), vec![0x42], b"ab".to_vec(), vec![0; 1000], tiles(0x2000), noise(0x800, 1), noise(3, 2)]
+    }
+
+    #[test]
+    fn lz77_round_trip() {
+        for data in samples() {
+            let compressed = compress_lz77(&data);
+            assert_eq!(compressed.len() % 4, 0);
+            let (decompressed, read) = decompress_lz77(&compressed).unwrap();
+            assert_eq!(decompressed, data);
+            assert!(read <= compressed.len());
+        }
+    }
+
+    #[test]
+    fn lz77_known_vectors() {
+        assert_eq!(compress_lz77(b"abcabcabc"), vec![0x10, 9, 0, 0, 0x10, b'a', b'b', b'c', 0x30, 0x02, 0, 0]);
+        // a distance of 1 is valid outside of VRAM even though the encoder never uses it
+        assert_eq!(decompress_lz77(&[0x10, 5, 0, 0, 0x40, b'x', 0x10, 0x00]).unwrap(), (b"xxxxx".to_vec(), 8));
+    }
+
+    #[test]
+    fn lz77_never_references_one_byte_back() {
+        let compressed = compress_lz77(&vec![7; 100]);
+        let (_, size) = read_header(&compressed).unwrap();
+        assert_eq!(size, 100);
+        // two literals, then 18 byte references to 2 bytes back
+        assert_eq!(&compressed[4..9], &[0x3F, 7, 7, 0xF0, 0x01]);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        for data in samples() {
+            let compressed = compress_rle(&data);
+            assert_eq!(compressed.len() % 4, 0);
+            assert_eq!(decompress_rle(&compressed).unwrap().0, data);
+        }
+        // runs and literal blocks longer than a single flag can hold
+        let data: Vec<u8> = vec![1; 300].into_iter().chain(noise(300, 3)).collect();
+        assert_eq!(decompress_rle(&compress_rle(&data)).unwrap().0, data);
+    }
+
+    #[test]
+    fn rle_known_vectors() {
+        assert_eq!(compress_rle(b"aaaaab"), vec![0x30, 6, 0, 0, 0x82, b'a', 0x00, b'b']);
+        assert_eq!(decompress_rle(&[0x30, 6, 0, 0, 0x82, b'a', 0x00, b'b']).unwrap(), (b"aaaaab".to_vec(), 8));
+    }
+
+    #[test]
+    fn huffman_round_trip() {
+        for &bits in [4, 8].iter() {
+            for data in samples() {
+                let compressed = compress_huffman(&data, bits).unwrap();
+                assert_eq!(compressed.len() % 4, 0);
+                assert_eq!(decompress_huffman(&compressed).unwrap().0, data, "{} bit symbols", bits);
+            }
+        }
+        // every byte value, with very different frequencies
+        let data: Vec<u8> = (0..=255u8).flat_map(|byte| vec![byte; 1 + (byte as usize * byte as usize) % 40]).collect();
+        assert_eq!(decompress_huffman(&compress_huffman(&data, 8).unwrap()).unwrap().0, data);
+    }
+
+    #[test]
+    fn huffman_known_vectors() {
+        // b is 0 and a is 1, both leaves of the root
+        let compressed = vec![0x28, 2, 0, 0, 0x01, 0xC0, b'b', b'a', 0x00, 0x00, 0x00, 0x80];
+        assert_eq!(compress_huffman(b"ab", 8).unwrap(), compressed);
+        assert_eq!(decompress_huffman(&compressed).unwrap(), (b"ab".to_vec(), 12));
+        assert!(compress_huffman(b"ab", 2).is_err());
+    }
+
+    #[test]
+    fn compress_uses_the_header_type() {
+        for &kind in [LZ77, RLE, HUFFMAN | 4, HUFFMAN | 8].iter() {
+            let data = tiles(0x400);
+            let compressed = compress(&data, kind).unwrap();
+            assert_eq!(compressed[0], kind);
+            assert_eq!(decompress(&compressed).unwrap().0, data);
+        }
+        assert!(compress(b"abc", 0x40).is_err());
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        assert!(read_header(&[0x10, 1]).is_err());
+        assert!(decompress(&[0x50, 1, 0, 0, 0]).is_err());
+        assert!(decompress_lz77(&[0x10, 8, 0, 0, 0x00, b'a']).is_err());
+        // reference before the start
+        assert!(decompress_lz77(&[0x10, 4, 0, 0, 0x80, 0x00, 0x05]).is_err());
+        assert!(decompress_rle(&[0x30, 8, 0, 0, 0x03, b'a']).is_err());
+        assert!(decompress_huffman(&[0x28, 2, 0, 0, 0x01, 0xC0, b'b', b'a']).is_err());
+        assert!(decompress_at(&[0x10, 0, 0, 0], 4).is_err());
+    }
+}
@@ -1,5 +1,7 @@
 // Data for Sonic Battle
 
+use ::manager::sprite::{FrameShape, Obj, ObjShape};
+
 pub const PHI_PALETTE: i32 = 0x47AB78;
 pub const DUST_CLOUD_PALETTE: i32 = 0xBF2058;
 pub const SONIC_MINE_PALETTE: i32 = 0xBF20D8;
@@ -13,8 +15,98 @@ pub struct Character {
     pub text_offsets: (i32, i32),
     pub sprite_offset: i32,
     pub sprite_frames: &'static [i32],
+    pub animations: &'static [AnimationInfo],
+    /// how each sprite frame is put together from OAM objects
+    pub frame_shape: FrameShape,
+}
+
+impl Character {
+    /// Metadata for an animation, unnamed animations get an empty name
+    pub fn animation_info(&self, index: usize) -> AnimationInfo {
+        match self.animations.get(index) {
+            Some(info) => *info,
+            None => UNNAMED_ANIMATION,
+        }
+    }
+
+    /// Label used in the GUI and exported spritesheets, e.g. "02 walk". Names that are only
+    /// inferred get a question mark, "02 walk?".
+    pub fn animation_label(&self, index: usize) -> String {
+        let info = self.animation_info(index);
+        match (info.name.is_empty(), info.inferred) {
+            (true, _) => format!("{:02}", index),
+            (false, true) => format!("{:02} {}?", index, info.name),
+            (false, false) => format!("{:02} {}", index, info.name),
+        }
+    }
+
+    /// The label as it's used in file names, "02_walk"
+    pub fn animation_file_label(&self, index: usize) -> String {
+        let info = self.animation_info(index);
+        if info.name.is_empty() {
+            format!("{:02}", index)
+        } else {
+            format!("{:02}_{}", index, info.name)
+        }
+    }
+
+    /// Find an animation by name or by index, `animation_count` is how many animations the
+    /// character has in the ROM (see SpriteManager::layout)
+    pub fn find_animation(&self, key: &str, animation_count: usize) -> Option<usize> {
+        if let Ok(index) = key.parse::<usize>() {
+            if index < animation_count {
+                return Some(index);
+            }
+            return None;
+        }
+        self.animations.iter()
+            .position(|info| info.name.eq_ignore_ascii_case(key))
+            .filter(|&index| index < animation_count)
+    }
+}
+
+/// Find a character by name, ignoring case
+pub fn find_character(name: &str) -> Option<Character> {
+    CHARACTERS.iter().find(|c| c.name.eq_ignore_ascii_case(name)).cloned()
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub looping: bool,
+    /// the name was worked out from the frame counts and wasn't checked in the game
+    pub inferred: bool,
+}
+
+const UNNAMED_ANIMATION: AnimationInfo = AnimationInfo { name: "", description: "", looping: false, inferred: false };
+
+// None of these names were checked in the game, they're inferred from the frame counts. Most
+// movesets start with three 8 frame animations, each followed by a 4 frame one, which looks like
+// idle, walk and run loops with a turn after each. A character only gets the names for as much of
+// that pattern as its counts follow: Shadow (8, 4, 28, 12), Cream (8, 4, 20, 4) and Chaos
+// (8, 4, 8, 8) only the first two. Everything else stays unnamed until it's identified.
+const IDLE: AnimationInfo = AnimationInfo { name: "idle", description: "Standing still", looping: true, inferred: true };
+const IDLE_TURN: AnimationInfo = AnimationInfo { name: "idle_turn", description: "Turning around while standing", looping: false, inferred: true };
+const WALK: AnimationInfo = AnimationInfo { name: "walk", description: "Walking", looping: true, inferred: true };
+const WALK_TURN: AnimationInfo = AnimationInfo { name: "walk_turn", description: "Turning around while walking", looping: false, inferred: true };
+const RUN: AnimationInfo = AnimationInfo { name: "run", description: "Running", looping: true, inferred: true };
+const RUN_TURN: AnimationInfo = AnimationInfo { name: "run_turn", description: "Turning around while running", looping: false, inferred: true };
+
+const BASIC_ANIMATIONS: [AnimationInfo; 6] = [IDLE, IDLE_TURN, WALK, WALK_TURN, RUN, RUN_TURN];
+
+/// Character sprites are 48x48, drawn as a 32x48 block (32x32 + 32x16) with a 16x48 strip
+/// (16x32 + 16x16) to its right. It's the only shape that's been checked against the ROM, so every
+/// character uses it; effects, portraits and the like need their own once they're located.
+pub const CHARACTER_SHAPE: FrameShape = FrameShape {
+    objs: &[
+        Obj { shape: ObjShape::Square, size: 2, x: 0, y: 0 },
+        Obj { shape: ObjShape::Wide, size: 2, x: 0, y: 4 },
+        Obj { shape: ObjShape::Tall, size: 2, x: 4, y: 0 },
+        Obj { shape: ObjShape::Square, size: 1, x: 4, y: 4 },
+    ],
+};
+
 pub const CHARACTERS: [Character; 10] = [
     SONIC_DATA, KNUCKLES_DATA, TAILS_DATA, SHADOW_DATA, ROUGE_DATA,
     AMY_DATA, E102_DATA, CREAM_DATA, CHAOS_DATA, EGGMAN_DATA
@@ -27,6 +119,8 @@ pub const SONIC_DATA: Character = Character {
     sprite_offset: 0x47AFD8,
     sprite_frames: &[8, 4, 8, 4, 8, 4, 4, 4, 8, 4, 8, 8, 8, 8, 16, 12, 12, 8, 12, 8, 8, 16, 8, 12,
         8, 8, 4, 8, 4, 4, 8, 8, 4, 8, 4, 8, 4, 4],
+    animations: &BASIC_ANIMATIONS,
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const KNUCKLES_DATA: Character = Character {
@@ -36,6 +130,8 @@ pub const KNUCKLES_DATA: Character = Character {
     sprite_offset: 0x4CADF8,
     sprite_frames: &[8, 4, 8, 4, 8, 4, 4, 4, 8, 4, 8, 8, 8, 12, 16, 12, 12, 8, 12, 8, 8, 8, 8, 8,
         12, 8, 8, 4, 8, 8, 12, 8, 4, 8, 4, 4, 8, 8, 4, 8, 4, 4, 8, 4, 4],
+    animations: &BASIC_ANIMATIONS,
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const TAILS_DATA: Character = Character {
@@ -45,6 +141,8 @@ pub const TAILS_DATA: Character = Character {
     sprite_offset: 0x528418,
     sprite_frames: &[8, 4, 8, 4, 8, 4, 4, 4, 8, 4, 8, 8, 8, 8, 28, 12, 12, 8, 8, 8, 8, 20, 8, 20,
         16, 8, 8, 4, 8, 8, 8, 8, 4, 8, 8, 4, 8, 8, 8, 8, 8, 4, 4],
+    animations: &BASIC_ANIMATIONS,
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const SHADOW_DATA: Character = Character {
@@ -54,6 +152,8 @@ pub const SHADOW_DATA: Character = Character {
     sprite_offset: 0x58D838,
     sprite_frames: &[8, 4, 28, 12, 8, 4, 4, 8, 8, 8, 4, 8, 8, 8, 12, 24, 16, 20, 8, 4, 8, 12, 12,
         8, 24, 8, 12, 8, 4, 8, 4, 4, 8, 8, 12, 4, 4, 4, 4, 4],
+    animations: &[IDLE, IDLE_TURN],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const ROUGE_DATA: Character = Character {
@@ -63,6 +163,8 @@ pub const ROUGE_DATA: Character = Character {
     sprite_offset: 0x5F3E58,
     sprite_frames: &[8, 4, 8, 4, 8, 4, 8, 4, 8, 4, 8, 12, 16, 12, 8, 8, 12, 8, 4, 12, 8, 4, 8, 4,
         4, 8, 8, 12, 4, 4, 4, 4, 4],
+    animations: &BASIC_ANIMATIONS,
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const AMY_DATA: Character = Character {
@@ -72,6 +174,8 @@ pub const AMY_DATA: Character = Character {
     sprite_offset: 0x636478,
     sprite_frames: &[8, 4, 8, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 8, 8, 16, 16, 8, 8, 12, 12, 8, 8, 8,
         12, 8, 4, 8, 8, 8, 8, 8, 4, 4, 8, 4, 4],
+    animations: &BASIC_ANIMATIONS,
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const E102_DATA: Character = Character {
@@ -81,6 +185,8 @@ pub const E102_DATA: Character = Character {
     sprite_offset: 0x681A98,
     sprite_frames: &[8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4, 8, 8, 8, 12, 16, 12, 12, 8, 12, 8, 8, 16,
         12, 12, 16, 12, 12, 28, 4, 4, 20, 40, 4, 8, 4, 4, 4, 4, 8, 4, 4, 8, 4, 8, 4, 4],
+    animations: &[IDLE, IDLE_TURN, WALK, WALK_TURN],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const CREAM_DATA: Character = Character {
@@ -90,6 +196,8 @@ pub const CREAM_DATA: Character = Character {
     sprite_offset: 0x6F6AB8,
     sprite_frames: &[8, 4, 20, 4, 4, 8, 8, 12, 8, 8, 8, 16, 8, 12, 8, 16, 12, 4, 16, 12, 4, 8, 4,
         4],
+    animations: &[IDLE, IDLE_TURN],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const CHAOS_DATA: Character = Character {
@@ -99,6 +207,8 @@ pub const CHAOS_DATA: Character = Character {
     sprite_offset: 0x7336D8,
     sprite_frames: &[8, 4, 8, 8, 12, 4, 8, 8, 4, 8, 8, 12, 16, 16, 8, 8, 8, 8, 20, 8, 8, 12, 8, 4,
         8, 8, 8, 8, 8, 4, 4, 8, 4, 4],
+    animations: &[IDLE, IDLE_TURN],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const EMERL_DATA: Character = Character {
@@ -107,6 +217,8 @@ pub const EMERL_DATA: Character = Character {
     text_offsets: (0x206104, 0x20B131),
     sprite_offset: 0x787D18,
     sprite_frames: &[-1],
+    animations: &[],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub const EGGMAN_DATA: Character = Character {
@@ -115,14 +227,87 @@ pub const EGGMAN_DATA: Character = Character {
     text_offsets: (-1, -1),
     sprite_offset: 0x7822F8,
     sprite_frames: &[4, 4, 4, 4, 4],
+    animations: &[],
+    frame_shape: CHARACTER_SHAPE,
 };
 
 pub fn compute_sprite_offsets(character: &Character) -> Vec<(i32, i32)> {
     let mut data = Vec::new();
     let mut o = 0;
     for frame in character.sprite_frames.iter() {
-        data.push((character.sprite_offset + 0x480 * o, *frame));
+        data.push((character.sprite_offset + character.frame_shape.byte_count() as i32 * o, *frame));
         o += *frame;
     }
     data
 }
+
+/// A tiled background, 4bpp tiles drawn through a text mode tilemap
+#[derive(Copy, Clone)]
+pub struct Background {
+    pub name: &'static str,
+    pub tiles_offset: u64,
+    /// number of tiles, compressed tiles store their own size instead
+    pub tile_count: usize,
+    pub map_offset: u64,
+    /// size of the map in tiles
+    pub width: usize,
+    pub height: usize,
+    pub palette_offset: u64,
+    /// number of 16 color palette banks the tilemap uses
+    pub palette_banks: usize,
+    /// bank number the map uses for the bank at `palette_offset`, like 8 for a background drawn
+    /// with banks 8-15
+    pub first_bank: usize,
+    /// tiles and map are stored with one of the BIOS compression formats
+    pub compressed: bool,
+}
+
+/// Stage, menu and title screen backgrounds. None have been located in the ROM yet, so there are no
+/// backgrounds to pick by name and none in the editor; they can only be opened with explicit
+/// offsets from the command line. Entries get added here once they're found.
+pub const BACKGROUNDS: &'static [Background] = &[];
+
+/// Find a background by name, ignoring case
+pub fn find_background(name: &str) -> Option<Background> {
+    BACKGROUNDS.iter().find(|b| b.name.eq_ignore_ascii_case(name)).cloned()
+}
+
+/// A part of the ROM that belongs to a known asset
+#[derive(Clone, Debug)]
+pub struct AssetRegion {
+    pub name: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where every asset in this file is and how big it is. The sprites' lengths come from
+/// `sprite_frames` here, `Engine::asset_map` measures them from the layouts in the ROM.
+pub fn asset_map() -> Vec<AssetRegion> {
+    let region = |name: String, offset: u64, length: u64| AssetRegion { name, offset, length };
+    let mut assets = Vec::new();
+    for character in CHARACTERS.iter() {
+        let frames: i32 = character.sprite_frames.iter().sum();
+        assets.push(region(format!("{} palette", character.name), character.palette_offset, 32));
+        assets.push(region(format!("{} sprites", character.name), character.sprite_offset as u64,
+                           frames as u64 * character.frame_shape.byte_count() as u64));
+        // E-102, Chaos and Eggman have no text
+        if character.text_offsets.0 >= 0 {
+            assets.push(region(format!("{} text", character.name), character.text_offsets.0 as u64,
+                               (character.text_offsets.1 - character.text_offsets.0) as u64));
+        }
+    }
+    for &(name, offset) in [("Phi", PHI_PALETTE), ("Dust cloud", DUST_CLOUD_PALETTE), ("Sonic mine", SONIC_MINE_PALETTE),
+                            ("Tails blaster", TAILS_BLASTER_PALETTE), ("Shield", SHIELD_PALETTE)].iter() {
+        assets.push(region(format!("{} palette", name), offset as u64, 32));
+    }
+    for background in BACKGROUNDS.iter() {
+        // named like the writes in BackgroundManager::write_background, which may touch their own blocks
+        assets.push(region(format!("{} background palette", background.name), background.palette_offset, background.palette_banks as u64 * 32));
+        // compressed data only knows its size once it's been read
+        if !background.compressed {
+            assets.push(region(format!("{} background tiles", background.name), background.tiles_offset, background.tile_count as u64 * 32));
+            assets.push(region(format!("{} background map", background.name), background.map_offset, (background.width * background.height * 2) as u64));
+        }
+    }
+    assets
+}
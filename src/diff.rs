@@ -0,0 +1,289 @@
+use std::io::Error;
+use image::{ImageBuffer, Rgb};
+
+use ::data::*;
+use ::color::*;
+use ::engine::*;
+use ::rom::*;
+use ::manager::asset::full_name;
+use ::manager::palette::{PaletteManager, variant_name};
+use ::manager::sprite::{Animation, Frame, SpriteManager};
+
+/*
+ * ROM diffs
+ *
+ * Compares two ROMs asset by asset: every palette variant color by color, sprites frame by frame
+ * and everything else in the asset map byte by byte. Whatever differs outside of the known assets
+ * is listed as raw ranges.
+ */
+
+/// differences closer together than this are reported as one range
+const MERGE_DISTANCE: usize = 16;
+
+/// Colors that changed in a palette, (index, before, after) in GBA encoding
+pub struct PaletteDiff {
+    pub character: Character,
+    /// 0 for the main palette
+    pub variant: usize,
+    pub colors: Vec<(usize, i32, i32)>,
+}
+
+/// Frames that changed in a character's sprites
+pub struct SpriteDiff {
+    pub character: Character,
+    /// (animation, frame) of every frame that's in both ROMs but different
+    pub frames: Vec<(usize, usize)>,
+    /// (animation, frames before, frames after) of animations that gained or lost frames
+    pub frame_counts: Vec<(usize, usize, usize)>,
+}
+
+pub struct RomDiff {
+    pub palettes: Vec<PaletteDiff>,
+    pub sprites: Vec<SpriteDiff>,
+    /// other known assets that differ, (name, bytes changed)
+    pub assets: Vec<(String, usize)>,
+    /// (offset, length) of differences outside of every known asset
+    pub unexplained: Vec<(u64, u64)>,
+    /// size of both ROMs
+    pub lengths: (usize, usize),
+}
+
+impl RomDiff {
+    /// Compare the ROM opened in `before` with the one in `after`
+    pub fn compare(before: &Engine, after: &Engine) -> Result<RomDiff, Error> {
+        let rom_a = read_rom(&mut before.file.lock().unwrap())?;
+        let rom_b = read_rom(&mut after.file.lock().unwrap())?;
+        let (palettes_a, palettes_b) = (before.registry.get::<PaletteManager>()?, after.registry.get::<PaletteManager>()?);
+        let (sprites_a, sprites_b) = (before.registry.get::<SpriteManager>()?, after.registry.get::<SpriteManager>()?);
+        let mut diff = RomDiff {
+            palettes: Vec::new(),
+            sprites: Vec::new(),
+            assets: Vec::new(),
+            unexplained: Vec::new(),
+            lengths: (rom_a.len(), rom_b.len()),
+        };
+
+        // bytes that belong to an asset, differences there are explained by the asset
+        let mut explained = vec![false; rom_a.len().max(rom_b.len())];
+        let mut explain = |offset: u64, length: u64| {
+            let start = (offset as usize).min(explained.len());
+            let end = (offset as usize + length as usize).min(explained.len());
+            for byte in explained[start..end].iter_mut() {
+                *byte = true;
+            }
+        };
+
+        // assets compared in more detail than byte by byte
+        let mut detailed = Vec::new();
+        for character in CHARACTERS.iter() {
+            // variants are compared where both ROMs have them at the same offset
+            for variant in 0..palettes_a.variant_count(character).min(palettes_b.variant_count(character)) {
+                let offset = palettes_a.variant_offset(character, variant);
+                if offset.is_none() || offset != palettes_b.variant_offset(character, variant) {
+                    continue;
+                }
+                let name = variant_name(character, variant);
+                let (palette_a, palette_b) = (palettes_a.load_palette_i32(name.clone()), palettes_b.load_palette_i32(name.clone()));
+                let colors: Vec<(usize, i32, i32)> = palette_a.iter().zip(palette_b.iter()).enumerate()
+                    .filter(|&(_, (a, b))| a != b)
+                    .map(|(i, (&a, &b))| (i, a, b))
+                    .collect();
+                if !colors.is_empty() {
+                    diff.palettes.push(PaletteDiff { character: *character, variant, colors });
+                }
+                detailed.push(full_name(&name, "palette"));
+            }
+            explain(character.palette_offset, 32);
+            detailed.push(full_name(character.name, "sprites"));
+
+            let frame_byte_count = character.frame_shape.byte_count() as u64;
+            for sprites in [&sprites_a, &sprites_b].iter() {
+                for &(offset, frames) in sprites.layout(character).iter() {
+                    explain(offset as u64, frames as u64 * frame_byte_count);
+                }
+            }
+            if let (Ok(sheet_a), Ok(sheet_b)) = (sprites_a.load_spritesheet(character), sprites_b.load_spritesheet(character)) {
+                let mut sprite_diff = SpriteDiff { character: *character, frames: Vec::new(), frame_counts: Vec::new() };
+                for index in 0..sheet_a.animations.len().max(sheet_b.animations.len()) {
+                    let (animation_a, animation_b) = (sheet_a.animations.get(index), sheet_b.animations.get(index));
+                    // an animation only one of the ROMs has went from or to no frames
+                    let frame_count = |animation: Option<&Animation>| animation.map_or(0, |animation| animation.frames.len());
+                    if frame_count(animation_a) != frame_count(animation_b) {
+                        sprite_diff.frame_counts.push((index, frame_count(animation_a), frame_count(animation_b)));
+                    }
+                    if let (Some(animation_a), Some(animation_b)) = (animation_a, animation_b) {
+                        for (frame, (a, b)) in animation_a.frames.iter().zip(animation_b.frames.iter()).enumerate() {
+                            if !same_frame(a, b) {
+                                sprite_diff.frames.push((index, frame));
+                            }
+                        }
+                    }
+                }
+                if !sprite_diff.frames.is_empty() || !sprite_diff.frame_counts.is_empty() {
+                    diff.sprites.push(sprite_diff);
+                }
+            }
+        }
+
+        // each ROM has its own asset map, an asset is counted where the first ROM has it
+        let mut compared = Vec::new();
+        for asset in before.space_manager.assets.iter().chain(after.space_manager.assets.iter()) {
+            explain(asset.offset, asset.length);
+            if compared.contains(&asset.name) {
+                continue;
+            }
+            compared.push(asset.name.clone());
+            let changed = (asset.offset..asset.offset + asset.length)
+                .filter(|&i| rom_a.get(i as usize) != rom_b.get(i as usize))
+                .count();
+            if changed > 0 && !detailed.contains(&asset.name) {
+                diff.assets.push((asset.name.clone(), changed));
+            }
+        }
+
+        // moved assets are explained too
+        for engine in [before, after].iter() {
+            for &(offset, length) in engine.space_manager.allocations.values() {
+                explain(offset, length);
+            }
+        }
+
+        for i in 0..explained.len() {
+            if explained[i] || rom_a.get(i) == rom_b.get(i) {
+                continue;
+            }
+            match diff.unexplained.last_mut() {
+                Some(last) if i - (last.0 + last.1) as usize <= MERGE_DISTANCE => last.1 = i as u64 + 1 - last.0,
+                _ => diff.unexplained.push((i as u64, 1)),
+            }
+        }
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.palettes.is_empty() && self.sprites.is_empty() && self.assets.is_empty()
+            && self.unexplained.is_empty() && self.lengths.0 == self.lengths.1
+    }
+
+    /// The differences as lines of text
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.is_empty() {
+            lines.push("The ROMs are the same".to_string());
+            return lines;
+        }
+        if self.lengths.0 != self.lengths.1 {
+            lines.push(format!("size changed from {:#X} to {:#X}", self.lengths.0, self.lengths.1));
+        }
+        for palette in self.palettes.iter() {
+            let colors: Vec<String> = palette.colors.iter().map(|&(i, a, b)| format!("{} {:04X}->{:04X}", i, a, b)).collect();
+            lines.push(format!("{}: {}", full_name(&variant_name(&palette.character, palette.variant), "palette"), colors.join(", ")));
+        }
+        for sprites in self.sprites.iter() {
+            let character = sprites.character;
+            if !sprites.frames.is_empty() {
+                let frames: Vec<String> = sprites.frames.iter()
+                    .map(|&(animation, frame)| format!("{} {}", character.animation_label(animation), frame))
+                    .collect();
+                lines.push(format!("{} sprites: {} frames changed ({})", character.name, frames.len(), frames.join(", ")));
+            }
+            for &(animation, a, b) in sprites.frame_counts.iter() {
+                lines.push(format!("{} {}: {} frames -> {}", character.name, character.animation_label(animation), a, b));
+            }
+        }
+        for &(ref name, changed) in self.assets.iter() {
+            lines.push(format!("{}: {} bytes changed", name, changed));
+        }
+        if !self.unexplained.is_empty() {
+            lines.push(format!("{} other ranges changed:", self.unexplained.len()));
+            for &(offset, length) in self.unexplained.iter() {
+                lines.push(format!("  {:#X}-{:#X} ({} bytes)", offset, offset + length, length));
+            }
+        }
+        lines
+    }
+
+    /// Changed frames of a character, if any
+    pub fn frames(&self, character: &Character) -> &[(usize, usize)] {
+        match self.sprites.iter().find(|sprites| sprites.character.name == character.name) {
+            Some(sprites) => &sprites.frames[..],
+            None => &[],
+        }
+    }
+}
+
+fn same_frame(a: &Frame, b: &Frame) -> bool {
+    a.sections.len() == b.sections.len() && a.sections.iter().zip(b.sections.iter()).all(|(a, b)| a.bytes == b.bytes)
+}
+
+/// A frame before and after, next to each other, each drawn with its own ROM's palette
+pub fn before_after(a: &Frame, palette_a: &[Color], b: &Frame, palette_b: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    // a column of background between the two
+    let gap = 2;
+    let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel((a.width() + gap + b.width()) as u32, a.height().max(b.height()) as u32, Rgb { data: [255, 255, 255] });
+    for &(offset, frame, palette) in [(0, a, palette_a), (a.width() + gap, b, palette_b)].iter() {
+        for y in 0..frame.height() {
+            for x in 0..frame.width() {
+                let c = palette[frame.get_pixel(x, y) as usize];
+                image.get_pixel_mut((offset + x) as u32, y as u32).data = [c.r as u8, c.g as u8, c.b as u8];
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rom::tests::{noise_rom, TempRom};
+
+    /// somewhere no asset in the map is
+    const UNKNOWN: usize = 0xFF0000;
+
+    #[test]
+    fn differences_are_sorted_by_asset() {
+        let rom_a = noise_rom();
+        let mut rom_b = rom_a.clone();
+        let character = CHARACTERS[0];
+        assert!(!asset_map().iter().any(|asset| asset.offset <= UNKNOWN as u64 + 0x200 && UNKNOWN as u64 <= asset.offset + asset.length));
+
+        // color 3 of the main palette, the first byte of the first frame and three unknown bytes,
+        // two of them close enough to be one range
+        let color = character.palette_offset as usize + 3 * 2;
+        rom_b[color] = 0x34;
+        rom_b[color + 1] = 0x12;
+        rom_b[character.sprite_offset as usize] ^= 0xFF;
+        for &offset in [UNKNOWN, UNKNOWN + MERGE_DISTANCE, UNKNOWN + 0x100].iter() {
+            rom_b[offset] ^= 0xFF;
+        }
+
+        let (temp_a, temp_b) = (TempRom::new("diff-a", &rom_a), TempRom::new("diff-b", &rom_b));
+        let before = Engine::open_read_only(&temp_a.path).unwrap();
+        let after = Engine::open_read_only(&temp_b.path).unwrap();
+        let diff = RomDiff::compare(&before, &after).unwrap();
+
+        assert_eq!(diff.palettes.len(), 1);
+        assert_eq!(diff.palettes[0].character.name, character.name);
+        assert_eq!(diff.palettes[0].variant, 0);
+        let old = (rom_a[color] as i32 | (rom_a[color + 1] as i32) << 8) & 0x7FFF;
+        assert_eq!(diff.palettes[0].colors, vec![(3, old, 0x1234)]);
+
+        assert_eq!(diff.sprites.len(), 1);
+        assert_eq!(diff.frames(&character), &[(0, 0)]);
+        assert!(diff.sprites[0].frame_counts.is_empty());
+
+        assert!(diff.assets.is_empty(), "{:?}", diff.assets);
+        assert_eq!(diff.unexplained, vec![(UNKNOWN as u64, MERGE_DISTANCE as u64 + 1), (UNKNOWN as u64 + 0x100, 1)]);
+
+        // an animation only the second ROM has
+        {
+            let mut sprites = after.registry.get_mut::<SpriteManager>().unwrap();
+            let spritesheet = sprites.load_spritesheet_mut(&character).unwrap();
+            let added = spritesheet.animations.last().unwrap().clone();
+            spritesheet.animations.push(added);
+        }
+        let diff = RomDiff::compare(&before, &after).unwrap();
+        let animations = after.registry.get::<SpriteManager>().unwrap().frame_counts(&character);
+        assert_eq!(diff.sprites[0].frame_counts, vec![(animations.len() - 1, 0, *animations.last().unwrap())]);
+    }
+}
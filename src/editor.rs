@@ -0,0 +1,258 @@
+use ::manager::sprite::*;
+
+/// undo steps kept per editor
+const MAX_UNDO: usize = 100;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Tool {
+    Pencil,
+    Fill,
+    Eyedropper,
+    Line,
+    Rect,
+}
+
+pub const TOOLS: [(Tool, &'static str); 5] = [
+    (Tool::Pencil, "Pencil"),
+    (Tool::Fill, "Fill"),
+    (Tool::Eyedropper, "Pick"),
+    (Tool::Line, "Line"),
+    (Tool::Rect, "Rect"),
+];
+
+/// Edits the pixels of single frames in a spritesheet
+pub struct FrameEditor {
+    pub tool: Tool,
+    pub color_index: u8,
+    /// where the current stroke started
+    anchor: Option<(usize, usize)>,
+    /// last point of a pencil stroke
+    last: Option<(usize, usize)>,
+    /// the frame before the current stroke, lines and rectangles are redrawn on top of it
+    before: Option<Frame>,
+    /// (animation, frame, contents before the edit)
+    undo: Vec<(usize, usize, Frame)>,
+}
+
+impl FrameEditor {
+    pub fn new() -> FrameEditor {
+        FrameEditor {
+            tool: Tool::Pencil,
+            color_index: 1,
+            anchor: None,
+            last: None,
+            before: None,
+            undo: Vec::new(),
+        }
+    }
+
+    /// Start a stroke at (x, y), returns true if the frame changed
+    pub fn press(&mut self, spritesheet: &mut Spritesheet, animation: usize, frame: usize, x: usize, y: usize) -> bool {
+        let target = match frame_mut(spritesheet, animation, frame) {
+            Some(target) => target,
+            None => return false,
+        };
+
+        if self.tool == Tool::Eyedropper {
+            self.color_index = target.get_pixel(x, y);
+            return false;
+        }
+
+        self.undo.push((animation, frame, target.clone()));
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.anchor = Some((x, y));
+        self.last = Some((x, y));
+        self.before = Some(target.clone());
+
+        match self.tool {
+            Tool::Fill => flood_fill(target, x, y, self.color_index),
+            _ => target.set_pixel(x, y, self.color_index),
+        }
+        true
+    }
+
+    /// Continue the stroke to (x, y), returns true if the frame changed
+    pub fn drag(&mut self, spritesheet: &mut Spritesheet, animation: usize, frame: usize, x: usize, y: usize) -> bool {
+        let anchor = match self.anchor {
+            Some(anchor) => anchor,
+            None => return false,
+        };
+        let target = match frame_mut(spritesheet, animation, frame) {
+            Some(target) => target,
+            None => return false,
+        };
+
+        match self.tool {
+            Tool::Pencil => {
+                let last = self.last.unwrap_or(anchor);
+                draw_line(target, last, (x, y), self.color_index);
+                self.last = Some((x, y));
+            }
+            Tool::Line | Tool::Rect => {
+                if let Some(ref before) = self.before {
+                    *target = before.clone();
+                }
+                if self.tool == Tool::Line {
+                    draw_line(target, anchor, (x, y), self.color_index);
+                } else {
+                    draw_rect(target, anchor, (x, y), self.color_index);
+                }
+            }
+            Tool::Fill | Tool::Eyedropper => return false,
+        }
+        true
+    }
+
+    /// End the current stroke
+    pub fn release(&mut self) {
+        self.anchor = None;
+        self.last = None;
+        self.before = None;
+    }
+
+    /// Revert the last stroke, returns the (animation, frame) that changed
+    pub fn undo(&mut self, spritesheet: &mut Spritesheet) -> Option<(usize, usize)> {
+        self.release();
+        let (animation, frame, contents) = self.undo.pop()?;
+        let target = frame_mut(spritesheet, animation, frame)?;
+        *target = contents;
+        Some((animation, frame))
+    }
+
+    /// Forget the undo steps, after the spritesheet was changed some other way
+    pub fn clear_history(&mut self) {
+        self.release();
+        self.undo.clear();
+    }
+}
+
+fn frame_mut(spritesheet: &mut Spritesheet, animation: usize, frame: usize) -> Option<&mut Frame> {
+    spritesheet.animations.get_mut(animation).and_then(|animation| animation.frames.get_mut(frame))
+}
+
+/// Bresenham's line
+fn draw_line(frame: &mut Frame, from: (usize, usize), to: (usize, usize), color_index: u8) {
+    let (mut x, mut y) = (from.0 as i32, from.1 as i32);
+    let (x1, y1) = (to.0 as i32, to.1 as i32);
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let sx = if x < x1 { 1 } else { -1 };
+    let sy = if y < y1 { 1 } else { -1 };
+    let mut error = dx + dy;
+    loop {
+        frame.set_pixel(x as usize, y as usize, color_index);
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = error * 2;
+        if e2 >= dy {
+            error += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+}
+
+fn draw_rect(frame: &mut Frame, from: (usize, usize), to: (usize, usize), color_index: u8) {
+    let (left, right) = (from.0.min(to.0), from.0.max(to.0));
+    let (top, bottom) = (from.1.min(to.1), from.1.max(to.1));
+    draw_line(frame, (left, top), (right, top), color_index);
+    draw_line(frame, (left, bottom), (right, bottom), color_index);
+    draw_line(frame, (left, top), (left, bottom), color_index);
+    draw_line(frame, (right, top), (right, bottom), color_index);
+}
+
+/// Fill the area of same colored pixels around (x, y)
+fn flood_fill(frame: &mut Frame, x: usize, y: usize, color_index: u8) {
+    let target = frame.get_pixel(x, y);
+    if target == color_index {
+        return;
+    }
+    let size = frame.width();
+    let mut stack = vec![(x, y)];
+    while let Some((x, y)) = stack.pop() {
+        if frame.get_pixel(x, y) != target {
+            continue;
+        }
+        frame.set_pixel(x, y, color_index);
+        if x > 0 { stack.push((x - 1, y)); }
+        if x + 1 < size { stack.push((x + 1, y)); }
+        if y > 0 { stack.push((x, y - 1)); }
+        if y + 1 < frame.height() { stack.push((x, y + 1)); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::data::CHARACTER_SHAPE;
+
+    /// a spritesheet with one blank frame
+    fn blank() -> Spritesheet {
+        let mut spritesheet = Spritesheet::new(CHARACTER_SHAPE);
+        let mut animation = Animation::new();
+        animation.frames.push(Frame::decode(&CHARACTER_SHAPE, &vec![0; CHARACTER_SHAPE.byte_count()]));
+        spritesheet.animations.push(animation);
+        spritesheet
+    }
+
+    fn pixels(frame: &Frame) -> Vec<u8> {
+        (0..frame.height()).flat_map(|y| (0..frame.width()).map(move |x| (x, y))).map(|(x, y)| frame.get_pixel(x, y)).collect()
+    }
+
+    #[test]
+    fn flood_fill_stops_at_other_indices() {
+        let mut frame = blank().animations[0].frames[0].clone();
+        for y in 0..frame.height() {
+            frame.set_pixel(4, y, 2);
+        }
+        flood_fill(&mut frame, 0, 0, 3);
+        for y in 0..frame.height() {
+            assert_eq!((frame.get_pixel(3, y), frame.get_pixel(4, y), frame.get_pixel(5, y)), (3, 2, 0));
+        }
+    }
+
+    #[test]
+    fn lines_reach_both_ends_in_every_direction() {
+        let lines = [((1, 2), (9, 5)), ((9, 5), (1, 2)), ((1, 5), (9, 2)), ((9, 2), (1, 5)),
+                     ((2, 1), (5, 9)), ((5, 9), (2, 1)), ((5, 1), (2, 9)), ((2, 9), (5, 1))];
+        for &(from, to) in lines.iter() {
+            let mut frame = blank().animations[0].frames[0].clone();
+            draw_line(&mut frame, from, to, 1);
+            assert_eq!((frame.get_pixel(from.0, from.1), frame.get_pixel(to.0, to.1)), (1, 1));
+            // one pixel for every step along the longer side
+            assert_eq!(pixels(&frame).iter().filter(|&&pixel| pixel == 1).count(), 9);
+        }
+    }
+
+    #[test]
+    fn lines_are_redrawn_from_the_frame_before_the_stroke() {
+        let mut spritesheet = blank();
+        let mut editor = FrameEditor::new();
+        editor.tool = Tool::Line;
+        editor.press(&mut spritesheet, 0, 0, 0, 0);
+        editor.drag(&mut spritesheet, 0, 0, 6, 0);
+        editor.drag(&mut spritesheet, 0, 0, 0, 6);
+        let frame = &spritesheet.animations[0].frames[0];
+        assert_eq!((frame.get_pixel(6, 0), frame.get_pixel(0, 6)), (0, 1));
+        assert_eq!(pixels(frame).iter().filter(|&&pixel| pixel == 1).count(), 7);
+    }
+
+    #[test]
+    fn undo_puts_back_the_frame() {
+        let mut spritesheet = blank();
+        let original = pixels(&spritesheet.animations[0].frames[0]);
+        let mut editor = FrameEditor::new();
+        assert!(editor.press(&mut spritesheet, 0, 0, 3, 3));
+        editor.release();
+        assert!(pixels(&spritesheet.animations[0].frames[0]) != original);
+        assert_eq!(editor.undo(&mut spritesheet), Some((0, 0)));
+        assert!(pixels(&spritesheet.animations[0].frames[0]) == original);
+        assert_eq!(editor.undo(&mut spritesheet), None);
+    }
+}
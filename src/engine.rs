@@ -1,33 +1,237 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::*;
 use std::time::Instant;
 use std::result::Result;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use data::*;
+use error::*;
 use manager::*;
+use rom::*;
+use settings::Settings;
 
 pub struct Engine {
     pub file: Arc<Mutex<File>>,
-    pub palette_manager: Box<palette::PaletteManager>,
-    pub sprite_manager: Box<sprite::SpriteManager>,
+    /// path of the ROM, None if the engine was made from an open file
+    pub path: Option<String>,
+    pub space_manager: Box<space::SpaceManager>,
+    pub pointer_manager: Box<pointer::PointerManager>,
+    pub backup_manager: Box<backup::BackupManager>,
+    /// every kind of asset, with the assets they've read
+    pub registry: asset::AssetRegistry,
 }
 
 impl Engine {
     pub fn new(file: Arc<Mutex<File>>) -> Engine {
+        Engine::with_path(file, None, Settings::default())
+    }
+
+    /// Open a ROM for editing and load everything in it
+    pub fn open(path: &str) -> Result<Engine, SbrxError> {
+        Engine::open_with_settings(path, Settings::load()?)
+    }
+
+    pub fn open_with_settings(path: &str, settings: Settings) -> Result<Engine, SbrxError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut engine = Engine::with_path(Arc::new(Mutex::new(file)), Some(path.to_string()), settings);
+        engine.start()?;
+        Ok(engine)
+    }
+
+    /// Open a ROM only to look at it, like the second ROM of a diff
+    pub fn open_read_only(path: &str) -> Result<Engine, SbrxError> {
+        let file = File::open(path)?;
+        let mut engine = Engine::with_path(Arc::new(Mutex::new(file)), Some(path.to_string()), Settings::default());
+        engine.start()?;
+        Ok(engine)
+    }
+
+    fn with_path(file: Arc<Mutex<File>>, path: Option<String>, settings: Settings) -> Engine {
+        // moved assets are recorded next to the ROM
+        let record_path = path.as_ref().map(|path| format!("{}.sbrx", path));
         Engine {
             file: file.clone(),
-            palette_manager: Box::new(palette::PaletteManager::new(file.clone())),
-            sprite_manager: Box::new(sprite::SpriteManager::new(file.clone())),
+            space_manager: Box::new(space::SpaceManager::new(file.clone(), record_path)),
+            pointer_manager: Box::new(pointer::PointerManager::new(file.clone())),
+            backup_manager: Box::new(backup::BackupManager::new(file.clone(), path.as_ref().map(|path| path.as_str()), settings)),
+            registry: asset::AssetRegistry::with_defaults(file.clone()),
+            path,
         }
     }
 
-    pub fn start(&mut self) -> Result<(), Error> {
+    pub fn start(&mut self) -> Result<(), SbrxError> {
         let engine_timer = Instant::now();
-        self.palette_manager.read_palettes()?;
-        println!("Palette ROM loading: {:?}", engine_timer.elapsed());
-        self.sprite_manager.read_sprites()?;
-        println!("Sprite ROM loading: {:?}", engine_timer.elapsed());
+        self.space_manager.read_free_space()?;
+        println!("Free space scanning: {:?}", engine_timer.elapsed());
+        for manager in self.registry.managers() {
+            let mut manager = manager.borrow_mut();
+            manager.read(self)?;
+            println!("{} ROM loading: {:?}", manager.kind(), engine_timer.elapsed());
+        }
+        self.refresh_references()?;
+        println!("Pointer scanning: {:?}", engine_timer.elapsed());
+        Ok(())
+    }
+
+    /// Every pointer to an offset in the ROM
+    pub fn references_to(&self, offset: u64) -> &[pointer::Reference] {
+        self.pointer_manager.references_to(offset)
+    }
+
+    /// Work out the asset map again and scan for pointers to it, after assets were read or moved
+    pub fn refresh_references(&mut self) -> Result<(), SbrxError> {
+        let assets = self.asset_map()?;
+        self.space_manager.set_assets(assets);
+        self.pointer_manager.build_index(&self.space_manager.assets, &*self.registry.get::<sprite::SpriteManager>()?)
+    }
+
+    /// Where every asset in this ROM is. The sprites go as far as their animations do in the
+    /// layouts that were read, animations moved to free space are allocations instead. Every
+    /// palette variant the game points to is in it too.
+    pub fn asset_map(&self) -> Result<Vec<AssetRegion>, SbrxError> {
+        let sprites = self.registry.get::<sprite::SpriteManager>()?;
+        let palettes = self.registry.get::<palette::PaletteManager>()?;
+        let mut assets = asset_map();
+        for asset in assets.iter_mut() {
+            if let Some(character) = CHARACTERS.iter().find(|character| asset.name == asset::full_name(character.name, "sprites")) {
+                asset.length = sprites.extent(character, &self.space_manager);
+            }
+        }
+        for character in CHARACTERS.iter() {
+            for variant in 1..palettes.variant_count(character) {
+                if let Some(offset) = palettes.variant_offset(character, variant) {
+                    let name = asset::full_name(&palette::variant_name(character, variant), "palette");
+                    assets.push(AssetRegion { name, offset, length: 32 });
+                }
+            }
+        }
+        Ok(assets)
+    }
+
+    /// Everything that was changed but not written to the ROM yet
+    pub fn unsaved(&self) -> Vec<String> {
+        let mut unsaved = Vec::new();
+        for manager in self.registry.managers() {
+            let manager = manager.borrow();
+            for asset in manager.assets(self).iter().filter(|asset| manager.is_dirty(self, asset)) {
+                unsaved.push(manager.describe_changes(self, asset));
+            }
+        }
+        unsaved
+    }
+
+    /// If a character has changes that weren't written yet
+    pub fn is_character_dirty(&self, character: &Character) -> bool {
+        self.registry.managers().iter().any(|manager| {
+            let manager = manager.borrow();
+            manager.assets(self).iter().any(|asset| manager.belongs_to(asset, character) && manager.is_dirty(self, asset))
+        })
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.registry.managers().iter().any(|manager| {
+            let manager = manager.borrow();
+            manager.assets(self).iter().any(|asset| manager.is_dirty(self, asset))
+        })
+    }
+
+    /// Write every change to the ROM, only the bytes that are different are written. Returns how
+    /// many bytes were written.
+    pub fn save_all(&mut self) -> Result<usize, SbrxError> {
+        if !self.is_dirty() {
+            return Ok(0);
+        }
+        self.before_write()?;
+
+        let written = self.write_assets(|_, _| true)?;
+        self.refresh_references()?;
+        println!("Saved everything, {} bytes written", written);
+        Ok(written)
+    }
+
+    /// Write a character's changes to the ROM and read its assets back. Returns how many bytes
+    /// were written.
+    pub fn write_character(&mut self, character: &Character) -> Result<usize, SbrxError> {
+        self.before_write()?;
+        let written = self.write_assets(|manager, asset| manager.belongs_to(asset, character))?;
+        self.refresh_references()?;
+        for manager in self.registry.managers() {
+            let mut manager = manager.borrow_mut();
+            for asset in manager.assets(self) {
+                if manager.belongs_to(&asset, character) {
+                    manager.load(self, &asset)?;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write the changed assets that are picked, kind by kind in the registry's order
+    fn write_assets<F>(&mut self, pick: F) -> Result<usize, SbrxError>
+        where F: Fn(&dyn asset::AssetManager, &str) -> bool {
+        let mut written = 0;
+        for manager in self.registry.managers() {
+            let mut manager = manager.borrow_mut();
+            for asset in manager.assets(self) {
+                if pick(&*manager, &asset) && manager.is_dirty(self, &asset) {
+                    written += manager.write(self, &asset)?;
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    /// A character's text block as raw bytes, None if the character has no text
+    pub fn read_text(&self, character: &Character) -> Result<Option<Vec<u8>>, SbrxError> {
+        if character.text_offsets.0 < 0 {
+            return Ok(None);
+        }
+        let mut bytes = vec![0; (character.text_offsets.1 - character.text_offsets.0) as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(character.text_offsets.0 as u64))?;
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Write a character's text block to the ROM straight away, it can't grow past its block.
+    /// Returns how many bytes were written.
+    pub fn write_text(&mut self, character: &Character, bytes: &[u8]) -> Result<usize, SbrxError> {
+        if character.text_offsets.0 < 0 {
+            return Err(SbrxError::Unsupported(format!("{} has no text", character.name)));
+        }
+        let offset = character.text_offsets.0 as u64;
+        let length = (character.text_offsets.1 - character.text_offsets.0) as u64;
+        let mut file = self.file.lock().unwrap();
+        check_write(&asset::full_name(character.name, "text"), offset, bytes.len() as u64, (offset, length), rom_length(&file)?,
+                    &self.space_manager.assets)?;
+        Ok(write_changes(&mut file, offset, bytes)?)
+    }
+
+    /// Throw away every change that wasn't written yet
+    pub fn revert_all(&mut self) -> Result<(), SbrxError> {
+        for manager in self.registry.managers() {
+            let mut manager = manager.borrow_mut();
+            for asset in manager.assets(self) {
+                if manager.is_dirty(self, &asset) {
+                    manager.load(self, &asset)?;
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Back up the ROM if it's due, call before writing anything
+    pub fn before_write(&mut self) -> Result<(), SbrxError> {
+        self.backup_manager.before_write()
+    }
+
+    /// Put a backup back and read everything again
+    pub fn restore_backup(&mut self, backup: &Path) -> Result<(), SbrxError> {
+        self.backup_manager.restore(backup)?;
+        self.start()
+    }
 }
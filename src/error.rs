@@ -0,0 +1,78 @@
+use std::error;
+use std::fmt;
+use std::io;
+use image::ImageError;
+
+/*
+ * Errors
+ *
+ * Everything the engine and its managers can fail with. Code that only deals with files (the CLI,
+ * projects, mods) keeps using io::Error, SbrxError converts into one so `?` works there too.
+ */
+
+#[derive(Debug)]
+pub enum SbrxError {
+    /// reading or writing a file failed
+    Io(io::Error),
+    /// the ROM isn't laid out like it should be, like a table or a block that can't be read
+    InvalidRom(String),
+    /// an image couldn't be read or isn't laid out like a spritesheet or background
+    ImageFormat(String),
+    /// an image has more colors than fit in a palette
+    PaletteOverflow { colors: usize, max: usize },
+    /// a write would go past the end of the ROM, outside of its asset or into another asset
+    BoundsViolation(String),
+    UnknownCharacter(String),
+    /// an asset that isn't in the ROM, like a background or palette variant
+    UnknownAsset(String),
+    /// something an asset doesn't support, like exporting animation scripts
+    Unsupported(String),
+}
+
+impl fmt::Display for SbrxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SbrxError::Io(ref error) => write!(f, "{}", error),
+            SbrxError::PaletteOverflow { colors, max } => write!(f, "the image has {} colors but a palette only has room for {}", colors, max),
+            SbrxError::UnknownCharacter(ref name) => write!(f, "unknown character {}", name),
+            SbrxError::InvalidRom(ref message) | SbrxError::ImageFormat(ref message) | SbrxError::BoundsViolation(ref message)
+                | SbrxError::UnknownAsset(ref message) | SbrxError::Unsupported(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for SbrxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SbrxError::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SbrxError {
+    fn from(error: io::Error) -> SbrxError {
+        SbrxError::Io(error)
+    }
+}
+
+impl From<ImageError> for SbrxError {
+    fn from(error: ImageError) -> SbrxError {
+        match error {
+            ImageError::IoError(error) => SbrxError::Io(error),
+            error => SbrxError::ImageFormat(error.to_string()),
+        }
+    }
+}
+
+impl From<SbrxError> for io::Error {
+    fn from(error: SbrxError) -> io::Error {
+        let kind = match error {
+            SbrxError::Io(error) => return error,
+            SbrxError::InvalidRom(_) | SbrxError::ImageFormat(_) | SbrxError::PaletteOverflow { .. } => io::ErrorKind::InvalidData,
+            SbrxError::BoundsViolation(_) | SbrxError::UnknownCharacter(_) | SbrxError::UnknownAsset(_) => io::ErrorKind::InvalidInput,
+            SbrxError::Unsupported(_) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error.to_string())
+    }
+}
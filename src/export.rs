@@ -0,0 +1,198 @@
+use std::fs::{self, create_dir_all};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use ::data::*;
+use ::color::*;
+use ::engine::*;
+use ::manager::palette::{PaletteManager, variant_name};
+use ::manager::sprite::SpriteManager;
+use ::project::format_palette;
+
+/*
+ * Exporting everything
+ *
+ * Writes every character's sprites, palettes and metadata to a directory, laid out like a project
+ * so a default export can be built again:
+ *
+ *     sprites/<character>.png             labelled spritesheet (sheet)
+ *     sprites/<character>/<label>.png     one image per animation, frames stacked (strips)
+ *     sprites/<character>/<label>_<n>.png one image per frame (frames)
+ *     palettes/<character>.pal            16 GBA colors as hex, variants as <character>.<n>.pal (pal)
+ *     palettes/<character>.jasc.pal       JASC-PAL, the format most paint programs import (jasc)
+ *     palettes/<character>.bin            32 bytes, the same as in the ROM (bin)
+ *     metadata/<character>.txt            offsets and animations
+ */
+
+/// How spritesheets are exported
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SheetFormat {
+    Sheet,
+    Strips,
+    Frames,
+}
+
+impl SheetFormat {
+    pub fn parse(name: &str) -> Result<SheetFormat, Error> {
+        match name {
+            "sheet" => Ok(SheetFormat::Sheet),
+            "strips" => Ok(SheetFormat::Strips),
+            "frames" => Ok(SheetFormat::Frames),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown sheet format {}, use sheet, strips or frames", name))),
+        }
+    }
+}
+
+/// How palettes are exported
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PaletteFormat {
+    Pal,
+    Jasc,
+    Bin,
+}
+
+impl PaletteFormat {
+    pub fn parse(name: &str) -> Result<PaletteFormat, Error> {
+        match name {
+            "pal" => Ok(PaletteFormat::Pal),
+            "jasc" => Ok(PaletteFormat::Jasc),
+            "bin" => Ok(PaletteFormat::Bin),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown palette format {}, use pal, jasc or bin", name))),
+        }
+    }
+}
+
+pub struct ExportOptions {
+    pub sheets: Vec<SheetFormat>,
+    pub palettes: Vec<PaletteFormat>,
+    pub metadata: bool,
+}
+
+impl ExportOptions {
+    /// Formats as comma-separated lists, like "sheet,frames"
+    pub fn parse(sheets: &str, palettes: &str) -> Result<ExportOptions, Error> {
+        Ok(ExportOptions {
+            sheets: list(sheets).map(SheetFormat::parse).collect::<Result<Vec<_>, Error>>()?,
+            palettes: list(palettes).map(PaletteFormat::parse).collect::<Result<Vec<_>, Error>>()?,
+            metadata: true,
+        })
+    }
+}
+
+impl Default for ExportOptions {
+    /// What a project needs
+    fn default() -> ExportOptions {
+        ExportOptions {
+            sheets: vec![SheetFormat::Sheet],
+            palettes: vec![PaletteFormat::Pal],
+            metadata: true,
+        }
+    }
+}
+
+fn list<'a>(text: &'a str) -> impl Iterator<Item = &'a str> {
+    text.split(',').map(|name| name.trim()).filter(|name| !name.is_empty())
+}
+
+/// Export every character, returns how many files were written
+pub fn export_all(engine: &mut Engine, directory: &Path, options: &ExportOptions) -> Result<usize, Error> {
+    let mut written = 0;
+    for character in CHARACTERS.iter() {
+        written += export_character(engine, directory, character, options)?;
+    }
+    println!("Exported {} files to {}", written, directory.display());
+    Ok(written)
+}
+
+pub fn export_character(engine: &mut Engine, directory: &Path, character: &Character, options: &ExportOptions) -> Result<usize, Error> {
+    let mut written = 0;
+    let sprites = directory.join("sprites");
+    let mut palette_manager = engine.registry.get_mut::<PaletteManager>()?;
+    let sprite_manager = engine.registry.get::<SpriteManager>()?;
+    let palette = palette_manager.load_palette_colors(character.name.to_string());
+
+    for format in options.sheets.iter() {
+        if *format == SheetFormat::Sheet {
+            sprite_manager.save_spritesheet(&mut palette_manager, character, &sprites)?;
+            written += 1;
+            continue;
+        }
+
+        let animations = sprites.join(character.name);
+        create_dir_all(&animations)?;
+        let spritesheet = sprite_manager.load_spritesheet(character)?;
+        for (index, animation) in spritesheet.animations.iter().enumerate() {
+            let label = character.animation_file_label(index);
+            if *format == SheetFormat::Strips {
+                animation.to_img(&palette[..]).save(animations.join(format!("{}.png", label)))?;
+                written += 1;
+            } else {
+                for (frame, image) in animation.get_frames(&palette[..]).iter().enumerate() {
+                    image.save(animations.join(format!("{}_{}.png", label, frame)))?;
+                    written += 1;
+                }
+            }
+        }
+    }
+
+    if !options.palettes.is_empty() {
+        let palettes = directory.join("palettes");
+        create_dir_all(&palettes)?;
+        for variant in 0..palette_manager.variant_count(character) {
+            let name = if variant == 0 { character.name.to_string() } else { format!("{}.{}", character.name, variant) };
+            let colors = palette_manager.load_palette_i32(variant_name(character, variant));
+            for format in options.palettes.iter() {
+                let (file, contents) = match *format {
+                    PaletteFormat::Pal => (format!("{}.pal", name), format_palette(&colors[..]).into_bytes()),
+                    PaletteFormat::Jasc => (format!("{}.jasc.pal", name), format_jasc(&colors[..]).into_bytes()),
+                    PaletteFormat::Bin => (format!("{}.bin", name), colors.iter().flat_map(|&color| vec![color as u8, (color >> 8) as u8]).collect()),
+                };
+                fs::write(palettes.join(file), contents)?;
+                written += 1;
+            }
+        }
+    }
+
+    if options.metadata {
+        let metadata = directory.join("metadata");
+        create_dir_all(&metadata)?;
+        fs::write(metadata.join(format!("{}.txt", character.name)), format_metadata(&palette_manager, &sprite_manager, character))?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// A palette as JASC-PAL, 8 bit RGB
+fn format_jasc(colors: &[i32]) -> String {
+    let mut cache = GBAColorCache::new();
+    let mut text = format!("JASC-PAL\r\n0100\r\n{}\r\n", colors.len());
+    for &color in colors.iter() {
+        let color = cache.gba_to_rgb(color);
+        text.push_str(&format!("{} {} {}\r\n", color.r, color.g, color.b));
+    }
+    text
+}
+
+/// Where a character's assets are and what its animations are
+fn format_metadata(palette_manager: &PaletteManager, sprite_manager: &SpriteManager, character: &Character) -> String {
+    let shape = character.frame_shape;
+    let mut text = format!("# {}, exported by sbrx\nname = {}\nframe_size = {}x{}\n",
+                           character.name, character.name, shape.pixel_width(), shape.pixel_height());
+    let variants: Vec<String> = (0..palette_manager.variant_count(character))
+        .filter_map(|variant| palette_manager.variant_offset(character, variant))
+        .map(|offset| format!("{:#08X}", offset))
+        .collect();
+    text.push_str(&format!("palettes = {}\n", variants.join(", ")));
+    if character.text_offsets.0 >= 0 {
+        text.push_str(&format!("text = {:#08X}-{:#08X}\n", character.text_offsets.0, character.text_offsets.1));
+    }
+
+    // animation = <label>, <frames>, <offset>, loop|once
+    let frame_counts = sprite_manager.frame_counts(character);
+    for (index, &(offset, _)) in sprite_manager.layout(character).iter().enumerate() {
+        let frames = frame_counts.get(index).cloned().unwrap_or(0);
+        let looping = if character.animation_info(index).looping { "loop" } else { "once" };
+        text.push_str(&format!("animation = {}, {}, {:#08X}, {}\n", character.animation_label(index), frames, offset, looping));
+    }
+    text
+}
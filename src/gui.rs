@@ -3,21 +3,104 @@ extern crate nfd;
 use conrod;
 use self::nfd::Response;
 use self::engine::*;
-use std::sync::{Arc, Mutex};
-use image::open;
+use image::{open, GenericImage};
 use glium;
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
 
 use self::super::*;
 use self::super::data::*;
+use self::super::preview::*;
+use self::super::manager::sprite::{Frame, SpriteManager};
+use self::super::manager::palette::{PaletteManager, variant_name};
+use self::super::manager::animation::{AnimationManager, AnimationScript};
+use self::super::editor::*;
+use self::super::view::*;
+use self::super::browser::*;
+use self::super::diff::*;
+use self::super::color::Color;
+use self::super::error::*;
+use self::super::export::*;
+use self::super::remap::*;
+use self::super::script;
+use self::super::rom::read_rom;
+use self::super::manager::pointer::describe_reference;
 
 pub const WINDOW_WIDTH: u32 = 800;
 pub const WINDOW_HEIGHT: u32 = 600;
 
+/// the animation preview is drawn at 2x
+const PREVIEW_SCALE: u32 = 2;
+
+/// room around the previewed frame for the script's offsets
+const PREVIEW_MARGIN: u32 = 8;
+
+/// the pixel editor is drawn at 6x
+const EDITOR_SCALE: u32 = 6;
+
+/// size of the spritesheet view
+const VIEW_WIDTH: u32 = 740;
+const VIEW_HEIGHT: u32 = 400;
+
+/// zoom of the tile browser
+const BROWSER_SCALE: u32 = 3;
+
+/// lines of the ROM diff shown under the buttons
+const DIFF_LINES: usize = 30;
+
+/// lines of script output shown in the console
+const SCRIPT_LINES: usize = 20;
+
+/// formats Export All can write
+const EXPORT_SHEETS: [(&'static str, SheetFormat); 3] = [("Sheets", SheetFormat::Sheet), ("Strips", SheetFormat::Strips), ("Frames", SheetFormat::Frames)];
+const EXPORT_PALETTES: [(&'static str, PaletteFormat); 3] = [("Hex .pal", PaletteFormat::Pal), ("JASC .pal", PaletteFormat::Jasc), ("Raw .bin", PaletteFormat::Bin)];
+
 pub struct GuiState {
     chosen_file: String,
+    /// the last thing that went wrong, shown under the ROM's name
+    status: String,
     selected_character_index: Option<usize>,
+    selected_animation_index: Option<usize>,
     engine: Option<Engine>,
     spritesheet: Option<conrod::image::Id>,
+    sheet: Option<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    view: SheetView,
+    player: Option<AnimationPlayer>,
+    preview: Option<conrod::image::Id>,
+    editor: Option<FrameEditor>,
+    editor_image: Option<conrod::image::Id>,
+    onion_frames: usize,
+    onion_opacity: f32,
+    compare_a: Option<(usize, usize)>,
+    compare_b: Option<(usize, usize)>,
+    compare_mode: CompareMode,
+    compare: Option<conrod::image::Id>,
+    browser: Option<TileBrowser>,
+    browser_palettes: Vec<BrowserPalette>,
+    browser_offset: String,
+    browser_image: Option<conrod::image::Id>,
+    /// which of the selected character's palettes is shown and edited
+    palette_variant: usize,
+    remap_text: String,
+    remap_history: RemapHistory,
+    /// another ROM to compare with and what's different in it
+    diff_rom: Option<Engine>,
+    diff: Option<RomDiff>,
+    /// index into the selected character's changed frames
+    diff_frame: Option<usize>,
+    diff_image: Option<conrod::image::Id>,
+    /// something that would throw away unsaved changes and is waiting for an answer
+    pending: Option<Pending>,
+    quit: bool,
+    /// formats picked for Export All, indices into EXPORT_SHEETS and EXPORT_PALETTES
+    export_sheets: usize,
+    export_palettes: usize,
+    /// the script console is open
+    script_open: bool,
+    script_text: String,
+    /// what scripts printed, shared with the running script
+    script_output: Rc<RefCell<Vec<String>>>,
 }
 
 impl GuiState {
@@ -25,11 +108,64 @@ impl GuiState {
         GuiState {
             engine,
             selected_character_index: None,
+            selected_animation_index: None,
             chosen_file: "no ROM open".to_string(),
+            status: String::new(),
             spritesheet: None,
+            sheet: None,
+            view: SheetView::new(VIEW_WIDTH, VIEW_HEIGHT),
+            player: None,
+            preview: None,
+            editor: None,
+            editor_image: None,
+            onion_frames: 0,
+            onion_opacity: 0.5,
+            compare_a: None,
+            compare_b: None,
+            compare_mode: CompareMode::SideBySide,
+            compare: None,
+            browser: None,
+            browser_palettes: known_palettes(),
+            browser_offset: String::new(),
+            browser_image: None,
+            palette_variant: 0,
+            remap_text: String::new(),
+            remap_history: RemapHistory::new(),
+            diff_rom: None,
+            diff: None,
+            diff_frame: None,
+            diff_image: None,
+            pending: None,
+            quit: false,
+            export_sheets: 0,
+            export_palettes: 0,
+            script_open: false,
+            script_text: String::new(),
+            script_output: Rc::new(RefCell::new(Vec::new())),
         }
     }
 
+    /// Forget everything that was read from the ROM before it was opened or restored
+    pub fn reset_selection(&mut self) {
+        self.selected_character_index = None;
+        self.selected_animation_index = None;
+        self.palette_variant = 0;
+        self.player = None;
+        self.editor = None;
+        self.compare_a = None;
+        self.compare_b = None;
+        self.browser = None;
+        self.diff_rom = None;
+        self.diff = None;
+        self.diff_frame = None;
+    }
+
+    /// Show an error in the window instead of only on the console
+    pub fn report(&mut self, message: String) {
+        println!("{}", message);
+        self.status = message;
+    }
+
     pub fn get_character(&self) -> Option<Character> {
         if let Some(index) = self.selected_character_index {
             Some(CHARACTERS[index])
@@ -38,13 +174,333 @@ impl GuiState {
         }
     }
 
+    /// Pixel size of the selected character's frames
+    pub fn frame_size(&self) -> (u32, u32) {
+        let shape = self.get_character().map_or(CHARACTER_SHAPE, |character| character.frame_shape);
+        (shape.pixel_width() as u32, shape.pixel_height() as u32)
+    }
+
     pub fn insert_image(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>, image: ImageBuffer<Rgb<u8>, Vec<u8>>) {
-        let rgba = image::ImageRgb8(image).to_rgba();
-        let dimensions = rgba.dimensions();
-        let raw_image = glium::texture::RawImage2d::from_raw_rgba_reversed(&rgba.into_raw(), dimensions);
-        let texture = glium::texture::Texture2d::new(display, raw_image).unwrap();
-        self.spritesheet = Some(image_map.insert(texture))
+        self.view.clamp(image.width(), image.height());
+        self.sheet = Some(image);
+        self.update_view(display, image_map);
+    }
+
+    /// Render the visible part of the spritesheet
+    pub fn update_view(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let (frame_width, frame_height) = self.frame_size();
+        let o_image = self.sheet.as_ref().map(|sheet| self.view.render(sheet, frame_width, frame_height));
+        if let Some(image) = o_image {
+            self.spritesheet = Some(upload_image(display, image_map, self.spritesheet, image));
+        }
+    }
+
+    /// Render the selected character's spritesheet again after it changed
+    pub fn refresh_spritesheet(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let o_image = match (self.get_character(), &mut self.engine) {
+            (Some(character), &mut Some(ref mut engine)) => spritesheet_image(engine, &character, self.palette_variant).ok(),
+            _ => None,
+        };
+
+        if let Some(image) = o_image {
+            self.insert_image(display, image_map, image);
+        }
+        self.update_preview(display, image_map);
+    }
+
+    /// Script of the animation being previewed
+    pub fn preview_script(&self) -> Option<AnimationScript> {
+        match (self.get_character(), &self.player, &self.engine) {
+            (Some(character), &Some(ref player), &Some(ref engine)) => {
+                let animations = engine.registry.get::<AnimationManager>().ok()?;
+                let script = animations.load_scripts(&character).ok()?.get(player.animation_index).cloned();
+                script
+            }
+            _ => None,
+        }
     }
+
+    /// (animation, frame) shown in the preview, which is also the frame being edited
+    pub fn editing_frame(&self) -> Option<(usize, usize)> {
+        match (&self.player, self.preview_script()) {
+            (&Some(ref player), Some(script)) => player.current(&script).map(|step| (player.animation_index, step.frame as usize)),
+            _ => None,
+        }
+    }
+
+    /// Render the frame being edited
+    pub fn update_editor(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let editing = self.editing_frame();
+        let o_image = match (self.get_character(), editing, &mut self.engine) {
+            (Some(character), Some((animation_index, frame_index)), &mut Some(ref mut engine)) => {
+                let palette = palette_colors(engine, &character, self.palette_variant).unwrap_or_default();
+                engine.registry.get::<SpriteManager>().ok().and_then(|sprites| {
+                    sprites.load_spritesheet(&character).ok()
+                        .and_then(|spritesheet| spritesheet.animations.get(animation_index))
+                        .and_then(|animation| animation.frames.get(frame_index))
+                        .map(|frame| frame.to_image(&palette[..]))
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(image) = o_image {
+            let (width, height) = image.dimensions();
+            let scaled = image::imageops::resize(&image, width * EDITOR_SCALE, height * EDITOR_SCALE, image::FilterType::Nearest);
+            self.editor_image = Some(upload_image(display, image_map, self.editor_image, scaled));
+        }
+    }
+
+    /// Quit right away if everything is saved, otherwise ask first
+    pub fn request_quit(&mut self) {
+        self.request(Pending::Quit);
+    }
+
+    /// Do something that replaces the ROM right away if everything is saved, otherwise ask first
+    fn request(&mut self, action: Pending) {
+        if self.engine.as_ref().map_or(false, |engine| engine.is_dirty()) {
+            self.pending = Some(action);
+        } else {
+            self.perform(action);
+        }
+    }
+
+    fn perform(&mut self, action: Pending) {
+        self.pending = None;
+        match action {
+            Pending::Quit => self.quit = true,
+            Pending::OpenRom => self.open_rom(),
+            Pending::RestoreBackup => self.restore_backup(),
+        }
+    }
+
+    fn open_rom(&mut self) {
+        match nfd::dialog().filter("gba").open() {
+            Ok(Response::Okay(file_name)) => {
+                println!("File path = {:?}", file_name);
+                match engine::Engine::open(&file_name) {
+                    Ok(engine) => {
+                        self.chosen_file = file_name.clone();
+                        self.status.clear();
+                        self.engine = Some(engine);
+                        self.reset_selection();
+                    }
+                    Err(error) => self.report(format!("Couldn't open {}: {}", file_name, error)),
+                }
+            }
+            Ok(Response::Cancel) => println!("User canceled"),
+            Ok(_) => (),
+            Err(error) => self.report(format!("Couldn't open the file dialog: {}", error)),
+        }
+    }
+
+    fn restore_backup(&mut self) {
+        let restored = if let Some(ref mut engine) = self.engine {
+            let backup_dir = engine.backup_manager.backup_dir().to_string();
+            match nfd::dialog().filter("gba").default_path(&backup_dir).open() {
+                Ok(Response::Okay(backup)) => engine.restore_backup(Path::new(&backup))
+                    .map(|_| true)
+                    .map_err(|error| format!("Couldn't restore {}: {}", backup, error)),
+                Ok(_) => Ok(false),
+                Err(error) => Err(format!("Couldn't open the backup dialog: {}", error)),
+            }
+        } else {
+            Err("Open a ROM before restoring a backup".to_string())
+        };
+        match restored {
+            Ok(true) => self.reset_selection(),
+            Ok(false) => (),
+            Err(message) => self.report(message),
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// True while the preview is playing and the UI has to keep redrawing
+    pub fn is_animating(&self) -> bool {
+        self.player.as_ref().map_or(false, |player| player.playing)
+    }
+
+    /// Render the comparison of the two chosen frames
+    pub fn update_compare(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let mode = self.compare_mode;
+        let o_image = match (self.get_character(), self.compare_a, self.compare_b, &mut self.engine) {
+            (Some(character), Some(a), Some(b), &mut Some(ref mut engine)) => {
+                let palette = palette_colors(engine, &character, self.palette_variant).unwrap_or_default();
+                let sprites = engine.registry.get::<SpriteManager>().ok();
+                sprites.as_ref().and_then(|sprites| sprites.load_spritesheet(&character).ok()).and_then(|spritesheet| {
+                    let frame_a = spritesheet.animations.get(a.0).and_then(|animation| animation.frames.get(a.1))?;
+                    let frame_b = spritesheet.animations.get(b.0).and_then(|animation| animation.frames.get(b.1))?;
+                    Some(compare_frames(frame_a, frame_b, mode, &palette[..]))
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(image) = o_image {
+            let (width, height) = image.dimensions();
+            let scaled = image::imageops::resize(&image, width * PREVIEW_SCALE, height * PREVIEW_SCALE, image::FilterType::Nearest);
+            self.compare = Some(upload_image(display, image_map, self.compare, scaled));
+        }
+    }
+
+    /// Render the chosen changed frame before and after
+    pub fn update_diff(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let o_image = match (self.get_character(), &self.diff, self.diff_frame, &mut self.engine, &mut self.diff_rom) {
+            (Some(character), &Some(ref diff), Some(index), &mut Some(ref mut before), &mut Some(ref mut after)) => {
+                let palette_a = palette_colors(before, &character, 0).unwrap_or_default();
+                let palette_b = palette_colors(after, &character, 0).unwrap_or_default();
+                let sprites_a = before.registry.get::<SpriteManager>().ok();
+                let sprites_b = after.registry.get::<SpriteManager>().ok();
+                diff.frames(&character).get(index).and_then(|&(animation, frame)| {
+                    let a = sprites_a.as_ref()?.load_spritesheet(&character).ok()?.animations.get(animation)?.frames.get(frame)?;
+                    let b = sprites_b.as_ref()?.load_spritesheet(&character).ok()?.animations.get(animation)?.frames.get(frame)?;
+                    Some(before_after(a, &palette_a[..], b, &palette_b[..]))
+                })
+            }
+            _ => None,
+        };
+
+        match o_image {
+            Some(image) => {
+                let (width, height) = image.dimensions();
+                let scaled = image::imageops::resize(&image, width * PREVIEW_SCALE, height * PREVIEW_SCALE, image::FilterType::Nearest);
+                self.diff_image = Some(upload_image(display, image_map, self.diff_image, scaled));
+            }
+            None => self.diff_frame = None,
+        }
+    }
+
+    /// Render the page of the ROM shown in the tile browser
+    pub fn update_browser(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let o_image = match self.browser {
+            Some(ref mut browser) => {
+                self.browser_offset = format!("{:X}", browser.offset);
+                Some(browser.render(&self.browser_palettes[..]))
+            }
+            None => None,
+        };
+
+        if let Some(image) = o_image {
+            let (width, height) = image.dimensions();
+            let scaled = image::imageops::resize(&image, width * BROWSER_SCALE, height * BROWSER_SCALE, image::FilterType::Nearest);
+            self.browser_image = Some(upload_image(display, image_map, self.browser_image, scaled));
+        }
+    }
+
+    /// Render the current frame of the previewed animation with the current palette, moved by the
+    /// script's offset
+    pub fn update_preview(&mut self, display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>) {
+        let script = self.preview_script();
+        let (onion_frames, onion_opacity) = (self.onion_frames, self.onion_opacity);
+        let o_image = match (self.get_character(), &self.player, &mut self.engine, script) {
+            (Some(character), &Some(ref player), &mut Some(ref mut engine), Some(script)) => {
+                let palette = palette_colors(engine, &character, self.palette_variant).unwrap_or_default();
+                let step = player.current(&script).cloned();
+                let sprites = engine.registry.get::<SpriteManager>().ok();
+                let frame_image = sprites.as_ref().and_then(|sprites| sprites.load_spritesheet(&character).ok())
+                    .and_then(|spritesheet| spritesheet.animations.get(player.animation_index))
+                    .and_then(|animation| {
+                        // onion skin layers follow the script's order, not the order of the frames
+                        let frame_at = |step: usize| script.frames.get(step).and_then(|step| animation.frames.get(step.frame as usize));
+                        let current = frame_at(player.step_index)?;
+                        let before: Vec<&Frame> = (1..onion_frames + 1)
+                            .filter_map(|distance| player.step_index.checked_sub(distance))
+                            .filter_map(|step| frame_at(step))
+                            .collect();
+                        let after: Vec<&Frame> = (1..onion_frames + 1)
+                            .filter_map(|distance| frame_at(player.step_index + distance))
+                            .collect();
+                        Some(onion_skin(current, &before[..], &after[..], onion_opacity, &palette[..]))
+                    });
+
+                frame_image.map(|frame_image| {
+                    let step = step.unwrap();
+                    let background = Rgb { data: [palette[0].r as u8, palette[0].g as u8, palette[0].b as u8] };
+                    let mut image = ImageBuffer::from_pixel(frame_image.width() + PREVIEW_MARGIN * 2, frame_image.height() + PREVIEW_MARGIN * 2, background);
+                    let x = (PREVIEW_MARGIN as i32 + step.x as i32).max(0).min((PREVIEW_MARGIN * 2) as i32);
+                    let y = (PREVIEW_MARGIN as i32 + step.y as i32).max(0).min((PREVIEW_MARGIN * 2) as i32);
+                    image.copy_from(&frame_image, x as u32, y as u32);
+                    image
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(image) = o_image {
+            let (width, height) = image.dimensions();
+            let scaled = image::imageops::resize(&image, width * PREVIEW_SCALE, height * PREVIEW_SCALE, image::FilterType::Nearest);
+            self.preview = Some(upload_image(display, image_map, self.preview, scaled));
+        }
+    }
+}
+
+/// Things that throw away unsaved changes, they're asked about first
+#[derive(Copy, Clone)]
+enum Pending {
+    Quit,
+    OpenRom,
+    RestoreBackup,
+}
+
+impl Pending {
+    fn label(&self) -> &'static str {
+        match *self {
+            Pending::Quit => "Quit",
+            Pending::OpenRom => "Open ROM",
+            Pending::RestoreBackup => "Restore Backup",
+        }
+    }
+}
+
+/// Changes to the step of the animation script being previewed
+enum ScriptEdit {
+    Longer,
+    Shorter,
+    Earlier,
+    Later,
+}
+
+/// Upload an image as a texture, replacing the texture at `id` if there is one
+fn upload_image(display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>, id: Option<conrod::image::Id>, image: ImageBuffer<Rgb<u8>, Vec<u8>>) -> conrod::image::Id {
+    let rgba = image::ImageRgb8(image).to_rgba();
+    let dimensions = rgba.dimensions();
+    let raw_image = glium::texture::RawImage2d::from_raw_rgba_reversed(&rgba.into_raw(), dimensions);
+    let texture = glium::texture::Texture2d::new(display, raw_image).unwrap();
+    match id {
+        Some(id) => {
+            image_map.replace(id, texture);
+            id
+        }
+        None => image_map.insert(texture),
+    }
+}
+
+/// Write a character's changes to the ROM and read them back, returns the spritesheet as it's now
+/// in the ROM
+fn write_character(engine: &mut Engine, character: &Character, palette_variant: usize) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, SbrxError> {
+    let mut timer = Instant::now();
+    let written = engine.write_character(character)?;
+    println!("Write character to rom: {}, {} bytes ({:?})", character.name, written, timer.elapsed());
+
+    timer = Instant::now();
+    let image = spritesheet_image(engine, character, palette_variant)?;
+    println!("Converting {} spritesheet to an image ({:?})", character.name, timer.elapsed());
+    Ok(image)
+}
+
+/// One of a character's palette variants as colors
+fn palette_colors(engine: &Engine, character: &Character, palette_variant: usize) -> Result<Vec<Color>, SbrxError> {
+    Ok(engine.registry.get_mut::<PaletteManager>()?.load_palette_colors(variant_name(character, palette_variant)))
+}
+
+/// A character's spritesheet as an image in one of its palette variants
+fn spritesheet_image(engine: &Engine, character: &Character, palette_variant: usize) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, SbrxError> {
+    let palette = palette_colors(engine, character, palette_variant)?;
+    let sprites = engine.registry.get::<SpriteManager>()?;
+    Ok(sprites.load_spritesheet(character)?.to_img(&palette[..]))
 }
 
 pub fn theme() -> conrod::Theme {
@@ -80,17 +536,90 @@ widget_ids! {
 
         file_chooser_button,
         file_chooser_text,
+        status_text,
+        backup_restore,
+        export_all,
+        export_sheets,
+        export_palettes,
+        save_all,
+        unsaved_text,
+        quit_save,
+        quit_discard,
+        quit_cancel,
+
+        diff_toggle,
+        diff_text,
+        diff_frames,
+        diff_image,
 
         character_dropdown,
+        animation_dropdown,
+        animation_description,
         spritesheet_upload,
         spritesheet_save,
         spritesheet_write,
         spritesheet,
+        view_zoom_out,
+        view_zoom_in,
+        view_grid,
+        view_hover,
+
+        preview_image,
+        preview_play,
+        preview_step,
+        preview_loop,
+        preview_speed,
+        preview_frame,
+
+        script_longer,
+        script_shorter,
+        script_earlier,
+        script_later,
+        script_write,
+
+        editor_toggle,
+        editor_image,
+        editor_tools[],
+        editor_swatches[],
+        editor_undo,
+
+        onion_frames,
+        onion_opacity,
+        compare_set_a,
+        compare_set_b,
+        compare_mode,
+        compare_text,
+        compare_image,
+
+        browser_toggle,
+        browser_offset,
+        browser_page_back,
+        browser_row_back,
+        browser_row_forward,
+        browser_page_forward,
+        browser_bpp,
+        browser_narrower,
+        browser_wider,
+        browser_arrangement,
+        browser_palette,
+        browser_image,
+        browser_hover,
+
+        references,
+        palette_variants,
+        remap_text,
+        remap_undo,
+
+        script_toggle,
+        script_text,
+        script_run,
+        script_load,
+        script_output,
     }
 }
 
 pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::texture::Texture2d>, ui: &mut conrod::UiCell, ids: &Ids, app: &mut GuiState) {
-    use conrod::{widget, Colorable, Labelable, Positionable, Sizeable, Widget};
+    use conrod::{widget, Borderable, Colorable, Labelable, Positionable, Sizeable, Widget};
     use std::iter::once;
 
     const MARGIN: conrod::Scalar = 30.0;
@@ -119,35 +648,70 @@ pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::t
         .w_h(70.0, 25.0)
         .set(ids.file_chooser_button, ui)
         {
-            let result = nfd::dialog().filter("gba").open().unwrap_or_else(|e| {
-                panic!(e);
-            });
-            match result {
-                Response::Okay(file_name) => {
-                    println!("File path = {:?}", file_name);
-                    app.chosen_file = file_name.clone();
-                    let file_result = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(file_name);
-                    match file_result {
-                        Ok(file) => {
-                            let mut engine = engine::Engine::new(Arc::new(Mutex::new(file)));
-                            if let Ok(_) = engine.start() {
-                                app.engine = Some(engine);
-                                app.selected_character_index = None;
-                            }
-                        }
-                        Err(error) => {
-                            println!("Error occurred while opening file: {}", error);
+            app.request(Pending::OpenRom);
+        }
+
+    for _press in widget::Button::new()
+        .label("Restore Backup")
+        .small_font(ui)
+        .left_from(ids.file_chooser_button, 10.0)
+        .align_middle_y_of(ids.file_chooser_button)
+        .w_h(100.0, 25.0)
+        .set(ids.backup_restore, ui)
+        {
+            app.request(Pending::RestoreBackup);
+        }
+
+    let sheet_labels: Vec<String> = EXPORT_SHEETS.iter().map(|&(label, _)| label.to_string()).collect();
+    let palette_labels: Vec<String> = EXPORT_PALETTES.iter().map(|&(label, _)| label.to_string()).collect();
+    for _press in widget::Button::new()
+        .label("Export All")
+        .small_font(ui)
+        .left_from(ids.backup_restore, 10.0)
+        .align_middle_y_of(ids.file_chooser_button)
+        .w_h(75.0, 25.0)
+        .set(ids.export_all, ui)
+        {
+            if let Some(ref mut engine) = app.engine {
+                let options = ExportOptions {
+                    sheets: vec![EXPORT_SHEETS[app.export_sheets].1],
+                    palettes: vec![EXPORT_PALETTES[app.export_palettes].1],
+                    metadata: true,
+                };
+                match nfd::open_pick_folder(None) {
+                    Ok(Response::Okay(directory)) => {
+                        if let Err(error) = export_all(engine, Path::new(&directory), &options) {
+                            app.report(format!("Couldn't export to {}: {}", directory, error));
                         }
                     }
+                    Ok(_) => println!("User canceled"),
+                    Err(error) => app.report(format!("Couldn't open the folder dialog: {}", error)),
                 }
-                Response::Cancel => println!("User canceled"),
-                _ => (),
+            } else {
+                app.report("Open a ROM before exporting".to_string());
             }
         }
 
+    for selected_index in widget::DropDownList::new(sheet_labels.as_slice(), Some(app.export_sheets))
+        .small_font(ui)
+        .left_from(ids.export_all, 5.0)
+        .align_middle_y_of(ids.file_chooser_button)
+        .w_h(70.0, 25.0)
+        .set(ids.export_sheets, ui)
+        {
+            app.export_sheets = selected_index;
+        }
+
+    for selected_index in widget::DropDownList::new(palette_labels.as_slice(), Some(app.export_palettes))
+        .small_font(ui)
+        .left_from(ids.export_sheets, 5.0)
+        .align_middle_y_of(ids.file_chooser_button)
+        .w_h(85.0, 25.0)
+        .set(ids.export_palettes, ui)
+        {
+            app.export_palettes = selected_index;
+        }
+
     widget::Text::new(&app.chosen_file)
         .bottom_right_of(ids.file_chooser_button)
         .font_size(10)
@@ -155,34 +719,153 @@ pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::t
         .align_right()
         .set(ids.file_chooser_text, ui);
 
+    widget::Text::new(&app.status)
+        .font_size(10)
+        .w(300.0)
+        .down_from(ids.file_chooser_text, 5.0)
+        .align_right_of(ids.file_chooser_button)
+        .align_right()
+        .color(conrod::color::LIGHT_RED)
+        .set(ids.status_text, ui);
+
     //
     // Spritesheets
     //
 
-    for selected_index in widget::DropDownList::new(CHARACTERS.iter().map(|c| c.name).collect::<Vec<&str>>().as_slice(), app.selected_character_index)
+    // unsaved characters are marked with a *
+    let character_labels: Vec<String> = CHARACTERS.iter().map(|c| match app.engine {
+        Some(ref engine) if engine.is_character_dirty(c) => format!("{} *", c.name),
+        _ => c.name.to_string(),
+    }).collect();
+    for selected_index in widget::DropDownList::new(character_labels.as_slice(), app.selected_character_index)
         .small_font(ui)
         .bottom_left_of(ids.subtitle)
         .down(50.0)
-        .w_h(60.0, 25.0)
+        .w_h(75.0, 25.0)
         .set(ids.character_dropdown, ui)
         {
             // Change character
             app.selected_character_index = Some(selected_index);
+            app.selected_animation_index = None;
+            app.player = None;
+            app.editor = None;
+            app.compare_a = None;
+            app.compare_b = None;
+            app.diff_frame = None;
+            app.palette_variant = 0;
 
             let o_image = (if let Some(ref mut engine) = app.engine {
                 println!("Loading character data");
                 let character = CHARACTERS[selected_index];
-                let palette = engine.palette_manager.load_palette_colors(character.name.to_string());
-                Some(engine.sprite_manager.load_spritesheet(&character).unwrap().to_img(&palette[..]))
+                Some(spritesheet_image(engine, &character, app.palette_variant))
             } else {
                 None
             });
 
-            if let Some(image) = o_image {
-                app.insert_image(display, image_map, image);
+            match o_image {
+                Some(Ok(image)) => app.insert_image(display, image_map, image),
+                Some(Err(error)) => app.report(format!("Couldn't load the spritesheet: {}", error)),
+                None => (),
             }
         }
 
+    if let Some(character) = app.get_character() {
+        let frame_counts = match app.engine {
+            Some(ref engine) => match engine.registry.get::<SpriteManager>() {
+                Ok(sprites) => sprites.frame_counts(&character),
+                Err(_) => character.sprite_frames.iter().map(|&frames| frames as usize).collect(),
+            },
+            None => character.sprite_frames.iter().map(|&frames| frames as usize).collect(),
+        };
+        let labels: Vec<String> = (0..frame_counts.len()).map(|i| character.animation_label(i)).collect();
+        for selected_index in widget::DropDownList::new(labels.as_slice(), app.selected_animation_index)
+            .small_font(ui)
+            .right_from(ids.character_dropdown, 10.0)
+            .align_middle_y_of(ids.character_dropdown)
+            .w_h(110.0, 25.0)
+            .scrollbar_on_top()
+            .max_visible_items(12)
+            .set(ids.animation_dropdown, ui)
+            {
+                app.selected_animation_index = Some(selected_index);
+                let script = match app.engine {
+                    Some(ref engine) => engine.registry.get::<AnimationManager>().ok()
+                        .and_then(|animations| animations.load_scripts(&character).ok().and_then(|scripts| scripts.get(selected_index).cloned())),
+                    None => None,
+                };
+                app.player = script.map(|script| AnimationPlayer::new(selected_index, &script));
+                app.update_preview(display, image_map);
+            }
+
+        if let Some(animation_index) = app.selected_animation_index {
+            let info = character.animation_info(animation_index);
+            let description = format!("{} frames{}  {}", frame_counts[animation_index],
+                                      if info.looping { ", looping" } else { "" }, info.description);
+            widget::Text::new(&description)
+                .font_size(12)
+                .right_from(ids.animation_dropdown, 10.0)
+                .align_middle_y_of(ids.animation_dropdown)
+                .set(ids.animation_description, ui);
+        }
+    }
+
+    // what points at the selected character's assets
+    if let (Some(character), &Some(ref engine)) = (app.get_character(), &app.engine) {
+        let layout = engine.registry.get::<SpriteManager>().map(|sprites| sprites.layout(&character)).unwrap_or_default();
+        let palette_offset = engine.registry.get::<PaletteManager>().ok()
+            .and_then(|palettes| palettes.variant_offset(&character, app.palette_variant))
+            .unwrap_or(character.palette_offset);
+        let mut targets = vec![("palette".to_string(), palette_offset)];
+        if let Some(&(offset, _)) = layout.first() {
+            targets.push(("sprites".to_string(), offset as u64));
+        }
+        if let Some(&(offset, _)) = app.selected_animation_index.and_then(|index| layout.get(index)) {
+            targets.push((format!("animation {}", app.selected_animation_index.unwrap()), offset as u64));
+        }
+
+        let lines: Vec<String> = targets.iter().map(|&(ref name, offset)| {
+            let references = engine.references_to(offset);
+            let mut list: Vec<String> = references.iter().take(3).map(describe_reference).collect();
+            if references.len() > 3 {
+                list.push(format!("{} more", references.len() - 3));
+            }
+            if list.is_empty() {
+                list.push("nothing".to_string());
+            }
+            format!("{} {:#X} referenced from {}", name, offset, list.join(", "))
+        }).collect();
+
+        widget::Text::new(&lines.join("\n"))
+            .font_size(11)
+            .right_from(ids.spritesheet_save, 150.0)
+            .align_top_of(ids.spritesheet_save)
+            .set(ids.references, ui);
+    }
+
+    // other colorways of the selected character
+    let variant_count = match (app.get_character(), &app.engine) {
+        (Some(character), &Some(ref engine)) => engine.registry.get::<PaletteManager>().map_or(0, |palettes| palettes.variant_count(&character)),
+        _ => 0,
+    };
+    if variant_count > 1 {
+        let labels: Vec<String> = (0..variant_count).map(|variant| match variant {
+            0 => "Main palette".to_string(),
+            _ => format!("Palette {}", variant),
+        }).collect();
+        for selected_index in widget::DropDownList::new(labels.as_slice(), Some(app.palette_variant))
+            .small_font(ui)
+            .right_from(ids.spritesheet_save, 10.0)
+            .align_middle_y_of(ids.spritesheet_save)
+            .w_h(120.0, 25.0)
+            .set(ids.palette_variants, ui)
+            {
+                app.palette_variant = selected_index;
+                app.refresh_spritesheet(display, image_map);
+                app.update_editor(display, image_map);
+                app.update_compare(display, image_map);
+            }
+    }
+
     for _press in widget::Button::new()
         .label("Upload Spritesheet")
         .small_font(ui)
@@ -192,39 +875,49 @@ pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::t
         .set(ids.spritesheet_upload, ui)
         {
             println!("Upload Spritesheet");
+            let mut uploaded = false;
             if let Some(character) = app.get_character() {
+                let palette_variant = app.palette_variant;
                 if let Some(ref mut engine) = app.engine {
-                    let result = nfd::dialog().filter("png").open().unwrap_or_else(|e| {
-                        panic!(e);
-                    });
-                    match result {
-                        Response::Okay(file_name) => {
+                    let result = match nfd::dialog().filter("png").open() {
+                        Ok(Response::Okay(file_name)) => {
                             println!("File path = {:?}", file_name);
-                            app.chosen_file = file_name.clone();
-                            let dynamic_image = open(file_name.clone()).ok().expect("Couldn't open image");
-                            let mut image = match dynamic_image {
-                                ImageRgb8(mut image) => {
-                                    image
-                                }
-                                ImageRgba8(mut image) => {
+                            let image = match open(file_name.clone()) {
+                                Ok(ImageRgb8(image)) => Ok(image),
+                                Ok(ImageRgba8(image)) => {
                                     let converted_image: ImageBuffer<Rgb<u8>, Vec<u8>> = image.convert();
-                                    converted_image
-                                }
-                                _ => {
-                                    panic!("Couldn't open image {:?}", file_name.clone());
+                                    Ok(converted_image)
                                 }
+                                Ok(_) => Err(SbrxError::ImageFormat(format!("{} isn't an RGB or RGBA image", file_name))),
+                                Err(error) => Err(SbrxError::from(error)),
                             };
-
-                            let (spritesheet, palette) = manager::sprite::Spritesheet::from_img(&mut image, &character).unwrap();
-                            engine.sprite_manager.spritesheets.insert(character.name.to_string(), spritesheet);
-                            engine.palette_manager.store_palette_colors(character.name.to_string(), palette);
+                            image.and_then(|mut image| {
+                                let mut palettes = engine.registry.get_mut::<PaletteManager>()?;
+                                engine.registry.get_mut::<SpriteManager>()?
+                                    .store_image_with_palette(&mut palettes, &mut image, &character, variant_name(&character, palette_variant))
+                            }).map_err(|error| format!("Couldn't upload {}: {}", file_name, error))
+                        }
+                        Ok(Response::Cancel) => {
+                            println!("User canceled");
+                            Ok(())
+                        }
+                        Ok(_) => Ok(()),
+                        Err(error) => Err(format!("Couldn't open the file dialog: {}", error)),
+                    };
+                    match result {
+                        Ok(()) => {
                             println!("Converted & stored spritesheet");
+                            uploaded = true;
                         }
-                        Response::Cancel => println!("User canceled"),
-                        _ => (),
+                        Err(message) => app.report(message),
                     }
                 }
             }
+
+            if uploaded {
+                // show the import so it can be checked before it's written to the ROM
+                app.refresh_spritesheet(display, image_map);
+            }
         }
 
     for _press in widget::Button::new()
@@ -236,26 +929,36 @@ pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::t
         {
             println!("Save Spritesheet to File");
             if let Some(character) = app.get_character() {
-                if let Some(ref mut engine) = app.engine {
-                    let palette = engine.palette_manager.load_palette_colors(character.name.to_string());
-                    let image = { engine.sprite_manager.load_spritesheet(&character).unwrap().to_img(&palette[..]) };
-
-                    let result = nfd::dialog_save().filter("png").open().unwrap_or_else(|e| {
-                        panic!(e);
-                    });
-
-                    match result {
-                        Response::Okay(file_name) => {
-                            let file = if file_name.ends_with(".png") {
-                                file_name
-                            } else {
-                                format!("{}.png", file_name)
-                            };
-                            image.save(file).unwrap();
+                let result = if let Some(ref mut engine) = app.engine {
+                    let palette = palette_colors(engine, &character, app.palette_variant).unwrap_or_default();
+                    let image = engine.registry.get::<SpriteManager>()
+                        .and_then(|sprites| Ok(sprites.load_spritesheet(&character)?.to_labelled_img(&palette[..], &character)));
+                    match image {
+                        Ok(image) => {
+                            match nfd::dialog_save().filter("png").open() {
+                                Ok(Response::Okay(file_name)) => {
+                                    let file = if file_name.ends_with(".png") {
+                                        file_name
+                                    } else {
+                                        format!("{}.png", file_name)
+                                    };
+                                    image.save(&file).map_err(|error| format!("Couldn't save {}: {}", file, error))
+                                }
+                                Ok(Response::Cancel) => {
+                                    println!("User canceled");
+                                    Ok(())
+                                }
+                                Ok(_) => Ok(()),
+                                Err(error) => Err(format!("Couldn't open the file dialog: {}", error)),
+                            }
                         }
-                        Response::Cancel => println!("User canceled"),
-                        _ => (),
+                        Err(error) => Err(format!("Couldn't load the spritesheet: {}", error)),
                     }
+                } else {
+                    Ok(())
+                };
+                if let Err(message) = result {
+                    app.report(message);
                 }
             }
         }
@@ -269,37 +972,972 @@ pub fn gui(display: &glium::Display, image_map: &mut conrod::image::Map<glium::t
         {
             println!("Write Spritesheet to ROM");
             if let Some(character) = app.get_character() {
-                if let Some(image) = if let Some(ref mut engine) = app.engine {
-                    let total_timer = Instant::now();
-                    let mut timer = Instant::now();
-                    engine.palette_manager.write_palette(&character).unwrap();
-                    engine.sprite_manager.write_spritesheet(&character).unwrap();
-                    println!("Write character to rom: {} ({:?})", character.name, timer.elapsed());
-
-                    timer = Instant::now();
-                    engine.palette_manager.read_palette(&character).unwrap();
-                    engine.sprite_manager.read_sprite(&character).unwrap();
-                    println!("Reading {} sprites & palette from ROM ({:?})", character.name, timer.elapsed());
-
-                    timer = Instant::now();
-                    let palette = engine.palette_manager.load_palette_colors(character.name.to_string());
-                    let image = { engine.sprite_manager.load_spritesheet(&character).unwrap().to_img(&palette[..]) };
-                    println!("Converting {} spritesheet to an image ({:?})", character.name, timer.elapsed());
-                    Some(image)
-                } else { None } {
-                    // update display
-                    app.insert_image(display, image_map, image);
+                let written = if let Some(ref mut engine) = app.engine {
+                    Some(write_character(engine, &character, app.palette_variant))
+                } else { None };
+                match written {
+                    Some(Ok(image)) => {
+                        // update display
+                        app.insert_image(display, image_map, image);
+                        app.update_preview(display, image_map);
+                    }
+                    Some(Err(error)) => app.report(format!("Couldn't write {}: {}", character.name, error)),
+                    None => (),
+                }
+            }
+        }
+
+    let unsaved = app.engine.as_ref().map_or(Vec::new(), |engine| engine.unsaved());
+    let save_label = if unsaved.is_empty() { "Save All".to_string() } else { format!("Save All ({})", unsaved.len()) };
+    let mut saved = false;
+    for _press in widget::Button::new()
+        .label(&save_label)
+        .small_font(ui)
+        .right_from(ids.spritesheet_write, 135.0)
+        .align_middle_y_of(ids.spritesheet_write)
+        .w_h(90.0, 25.0)
+        .set(ids.save_all, ui)
+        {
+            if let Some(ref mut engine) = app.engine {
+                match engine.save_all() {
+                    Ok(_) => saved = true,
+                    Err(error) => app.report(format!("Couldn't save: {}", error)),
+                }
+            }
+        }
+
+    if let Some(action) = app.pending {
+        widget::Text::new(&format!("Unsaved changes: {}", unsaved.join(", ")))
+            .font_size(12)
+            .w(300.0)
+            .down_from(ids.status_text, 10.0)
+            .align_right_of(ids.file_chooser_button)
+            .set(ids.unsaved_text, ui);
+
+        for _press in widget::Button::new()
+            .label(&format!("Save and {}", action.label()))
+            .small_font(ui)
+            .down_from(ids.unsaved_text, 5.0)
+            .align_right_of(ids.unsaved_text)
+            .w_h(130.0, 25.0)
+            .set(ids.quit_save, ui)
+            {
+                let saved = app.engine.as_mut().map_or(Ok(0), |engine| engine.save_all());
+                match saved {
+                    Ok(_) => app.perform(action),
+                    Err(error) => app.report(format!("Couldn't save: {}", error)),
+                }
+            }
+
+        for _press in widget::Button::new()
+            .label(&format!("{} Without Saving", action.label()))
+            .small_font(ui)
+            .left_from(ids.quit_save, 5.0)
+            .align_middle_y_of(ids.quit_save)
+            .w_h(160.0, 25.0)
+            .set(ids.quit_discard, ui)
+            {
+                app.perform(action);
+            }
+
+        for _press in widget::Button::new()
+            .label("Cancel")
+            .small_font(ui)
+            .left_from(ids.quit_discard, 5.0)
+            .align_middle_y_of(ids.quit_save)
+            .w_h(60.0, 25.0)
+            .set(ids.quit_cancel, ui)
+            {
+                app.pending = None;
+            }
+    }
+
+    if saved {
+        app.pending = None;
+    }
+
+    let diff_label = if app.diff.is_some() { "Close Diff" } else { "Diff ROM" };
+    for _press in widget::Button::new()
+        .label(diff_label)
+        .small_font(ui)
+        .right_from(ids.spritesheet_write, 235.0)
+        .align_middle_y_of(ids.spritesheet_write)
+        .w_h(80.0, 25.0)
+        .set(ids.diff_toggle, ui)
+        {
+            if app.diff.is_some() {
+                app.diff = None;
+                app.diff_rom = None;
+            } else if let Some(ref engine) = app.engine {
+                match nfd::dialog().filter("gba").open() {
+                    Ok(Response::Okay(file_name)) => {
+                        let compared = Engine::open_read_only(&file_name)
+                            .and_then(|other| RomDiff::compare(engine, &other).map(|diff| (other, diff)).map_err(SbrxError::from));
+                        match compared {
+                            Ok((other, diff)) => {
+                                for line in diff.report() {
+                                    println!("{}", line);
+                                }
+                                app.diff_rom = Some(other);
+                                app.diff = Some(diff);
+                                app.browser = None;
+                                app.script_open = false;
+                            }
+                            Err(error) => app.report(format!("Couldn't compare with {}: {}", file_name, error)),
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(error) => app.report(format!("Couldn't open the file dialog: {}", error)),
+                }
+            }
+            app.diff_frame = None;
+        }
+
+    // palette remapping, "swap 1 2", "merge 3 4" or a full mapping
+    let mut remapped = false;
+    for event in widget::TextBox::new(&app.remap_text)
+        .font_size(12)
+        .right_from(ids.spritesheet_write, 325.0)
+        .align_middle_y_of(ids.spritesheet_write)
+        .w_h(110.0, 25.0)
+        .set(ids.remap_text, ui)
+        {
+            match event {
+                widget::text_box::Event::Update(text) => app.remap_text = text,
+                widget::text_box::Event::Enter => {
+                    if let (Some(character), &mut Some(ref mut engine)) = (app.get_character(), &mut app.engine) {
+                        let history = &mut app.remap_history;
+                        let result = PaletteRemap::parse(&app.remap_text)
+                            .and_then(|remap| history.apply(engine, &character, &remap));
+                        match result {
+                            Ok(_) => remapped = true,
+                            Err(error) => app.report(format!("Couldn't remap: {}", error)),
+                        }
+                    }
+                }
+            }
+        }
+
+    if app.remap_history.can_undo() {
+        for _press in widget::Button::new()
+            .label("Undo Remap")
+            .small_font(ui)
+            .right_from(ids.remap_text, 5.0)
+            .align_middle_y_of(ids.remap_text)
+            .w_h(75.0, 25.0)
+            .set(ids.remap_undo, ui)
+            {
+                if let Some(ref mut engine) = app.engine {
+                    if let Some(character) = app.remap_history.undo(engine) {
+                        println!("Undid the last remap of {}", character.name);
+                        remapped = true;
+                    }
+                }
+            }
+    }
+
+    if remapped {
+        // the pixel editor's undo steps use the old indices
+        if let Some(ref mut editor) = app.editor {
+            editor.clear_history();
+        }
+        app.refresh_spritesheet(display, image_map);
+        app.update_editor(display, image_map);
+        app.update_compare(display, image_map);
+    }
+
+    let script_label = if app.script_open { "Close Scripts" } else { "Scripts" };
+    for _press in widget::Button::new()
+        .label(script_label)
+        .small_font(ui)
+        .right_from(ids.spritesheet_write, 520.0)
+        .align_middle_y_of(ids.spritesheet_write)
+        .w_h(90.0, 25.0)
+        .set(ids.script_toggle, ui)
+        {
+            app.script_open = !app.script_open;
+            if app.script_open {
+                app.browser = None;
+                app.diff = None;
+                app.diff_rom = None;
+            }
+        }
+
+    let browser_label = if app.browser.is_some() { "Close Tile Browser" } else { "Tile Browser" };
+    for _press in widget::Button::new()
+        .label(browser_label)
+        .small_font(ui)
+        .right_from(ids.spritesheet_write, 10.0)
+        .align_middle_y_of(ids.spritesheet_write)
+        .w_h(115.0, 25.0)
+        .set(ids.browser_toggle, ui)
+        {
+            if app.browser.is_some() {
+                app.browser = None;
+            } else if let Some(ref engine) = app.engine {
+                app.diff = None;
+                app.script_open = false;
+                let rom = read_rom(&mut engine.file.lock().unwrap());
+                match rom {
+                    Ok(rom) => app.browser = Some(TileBrowser::new(rom)),
+                    Err(error) => app.report(format!("Couldn't read the ROM: {}", error)),
+                }
+            }
+            app.update_browser(display, image_map);
+        }
+
+    //
+    // Animation preview
+    //
+
+    let mut preview_changed = false;
+    let mut compare_changed = false;
+    if let (Some(character), Some(script)) = (app.get_character(), app.preview_script()) {
+        let player = app.player.as_mut().unwrap();
+        preview_changed = player.update(&script);
+
+        for _press in widget::Button::new()
+            .label(if player.playing { "Pause" } else { "Play" })
+            .small_font(ui)
+            .right_from(ids.spritesheet_upload, 80.0)
+            .align_middle_y_of(ids.spritesheet_upload)
+            .w_h(50.0, 25.0)
+            .set(ids.preview_play, ui)
+            {
+                player.toggle_playing(&script);
+            }
+
+        for _press in widget::Button::new()
+            .label("Step")
+            .small_font(ui)
+            .right_from(ids.preview_play, 10.0)
+            .align_middle_y_of(ids.preview_play)
+            .w_h(50.0, 25.0)
+            .set(ids.preview_step, ui)
+            {
+                player.playing = false;
+                if !player.step(&script) {
+                    player.step_index = 0;
+                }
+                preview_changed = true;
+            }
+
+        for looping in widget::Toggle::new(player.looping)
+            .label("Loop")
+            .small_font(ui)
+            .right_from(ids.preview_step, 10.0)
+            .align_middle_y_of(ids.preview_step)
+            .w_h(50.0, 25.0)
+            .set(ids.preview_loop, ui)
+            {
+                player.looping = looping;
+            }
+
+        if let Some(speed) = widget::Slider::new(player.speed, 0.25, 4.0)
+            .label(&format!("speed {:.2}x", player.speed))
+            .small_font(ui)
+            .down_from(ids.preview_play, 10.0)
+            .align_left_of(ids.preview_play)
+            .w_h(170.0, 25.0)
+            .set(ids.preview_speed, ui)
+            {
+                player.speed = speed;
+            }
+
+        let step_text = match player.current(&script) {
+            Some(step) => format!("step {}/{}: frame {}, {} of {} ticks, offset ({}, {})", player.step_index + 1,
+                                  script.frames.len(), step.frame, step.duration, script.total_ticks(), step.x, step.y),
+            None => "empty animation".to_string(),
+        };
+        widget::Text::new(&step_text)
+            .font_size(12)
+            .down_from(ids.preview_speed, 10.0)
+            .align_left_of(ids.preview_speed)
+            .set(ids.preview_frame, ui);
+
+        // editing the script of the current step
+        let mut edit = None;
+        for _press in widget::Button::new()
+            .label("Longer")
+            .small_font(ui)
+            .down_from(ids.preview_frame, 10.0)
+            .align_left_of(ids.preview_frame)
+            .w_h(50.0, 25.0)
+            .set(ids.script_longer, ui)
+            {
+                edit = Some(ScriptEdit::Longer);
+            }
+
+        for _press in widget::Button::new()
+            .label("Shorter")
+            .small_font(ui)
+            .right_from(ids.script_longer, 10.0)
+            .align_middle_y_of(ids.script_longer)
+            .w_h(50.0, 25.0)
+            .set(ids.script_shorter, ui)
+            {
+                edit = Some(ScriptEdit::Shorter);
+            }
+
+        for _press in widget::Button::new()
+            .label("Earlier")
+            .small_font(ui)
+            .right_from(ids.script_shorter, 10.0)
+            .align_middle_y_of(ids.script_shorter)
+            .w_h(50.0, 25.0)
+            .set(ids.script_earlier, ui)
+            {
+                edit = Some(ScriptEdit::Earlier);
+            }
+
+        for _press in widget::Button::new()
+            .label("Later")
+            .small_font(ui)
+            .right_from(ids.script_earlier, 10.0)
+            .align_middle_y_of(ids.script_earlier)
+            .w_h(50.0, 25.0)
+            .set(ids.script_later, ui)
+            {
+                edit = Some(ScriptEdit::Later);
+            }
+
+        for _press in widget::Button::new()
+            .label("Write Animation to ROM")
+            .small_font(ui)
+            .down_from(ids.script_longer, 10.0)
+            .align_left_of(ids.script_longer)
+            .w_h(140.0, 25.0)
+            .set(ids.script_write, ui)
+            {
+                println!("Write Animation to ROM");
+                if let Some(ref mut engine) = app.engine {
+                    let written = engine.before_write()
+                        .and_then(|_| engine.registry.get_mut::<AnimationManager>())
+                        .and_then(|mut animations| animations.write_scripts(&character, &engine.space_manager.assets));
+                    match written {
+                        Ok(_) => println!("Wrote {} animation scripts", character.name),
+                        Err(error) => {
+                            // the player is still borrowed from app, so this can't go through report
+                            app.status = format!("Couldn't write animation scripts: {}", error);
+                            println!("{}", app.status);
+                        }
+                    }
+                }
+            }
+
+        for editing in widget::Toggle::new(app.editor.is_some())
+            .label("Edit Frame")
+            .small_font(ui)
+            .right_from(ids.script_write, 10.0)
+            .align_middle_y_of(ids.script_write)
+            .w_h(80.0, 25.0)
+            .set(ids.editor_toggle, ui)
+            {
+                app.editor = if editing { Some(FrameEditor::new()) } else { None };
+                player.playing = false;
+                preview_changed = true;
+            }
+
+        // onion skinning and comparing frames
+        if let Some(frames) = widget::Slider::new(app.onion_frames as f32, 0.0, 4.0)
+            .label(&format!("onion skin {} frames", app.onion_frames))
+            .small_font(ui)
+            .down_from(ids.script_write, 10.0)
+            .align_left_of(ids.script_write)
+            .w_h(170.0, 25.0)
+            .set(ids.onion_frames, ui)
+            {
+                app.onion_frames = frames.round() as usize;
+                preview_changed = true;
+            }
+
+        if let Some(opacity) = widget::Slider::new(app.onion_opacity, 0.1, 1.0)
+            .label(&format!("opacity {:.0}%", app.onion_opacity * 100.0))
+            .small_font(ui)
+            .right_from(ids.onion_frames, 10.0)
+            .align_middle_y_of(ids.onion_frames)
+            .w_h(120.0, 25.0)
+            .set(ids.onion_opacity, ui)
+            {
+                app.onion_opacity = opacity;
+                preview_changed = true;
+            }
+
+        let shown = player.current(&script).map(|step| (player.animation_index, step.frame as usize));
+        for _press in widget::Button::new()
+            .label("Compare A")
+            .small_font(ui)
+            .down_from(ids.onion_frames, 10.0)
+            .align_left_of(ids.onion_frames)
+            .w_h(70.0, 25.0)
+            .set(ids.compare_set_a, ui)
+            {
+                app.compare_a = shown;
+                compare_changed = true;
+            }
+
+        for _press in widget::Button::new()
+            .label("Compare B")
+            .small_font(ui)
+            .right_from(ids.compare_set_a, 10.0)
+            .align_middle_y_of(ids.compare_set_a)
+            .w_h(70.0, 25.0)
+            .set(ids.compare_set_b, ui)
+            {
+                app.compare_b = shown;
+                compare_changed = true;
+            }
+
+        for difference in widget::Toggle::new(app.compare_mode == CompareMode::Difference)
+            .label("Difference")
+            .small_font(ui)
+            .right_from(ids.compare_set_b, 10.0)
+            .align_middle_y_of(ids.compare_set_b)
+            .w_h(70.0, 25.0)
+            .set(ids.compare_mode, ui)
+            {
+                app.compare_mode = if difference { CompareMode::Difference } else { CompareMode::SideBySide };
+                compare_changed = true;
+            }
+
+        let describe = |frame: Option<(usize, usize)>| frame.map_or("-".to_string(), |(animation, frame)| format!("{}/{}", animation, frame));
+        widget::Text::new(&format!("A {}  B {}", describe(app.compare_a), describe(app.compare_b)))
+            .font_size(12)
+            .right_from(ids.compare_mode, 10.0)
+            .align_middle_y_of(ids.compare_mode)
+            .set(ids.compare_text, ui);
+
+        if let (Some(edit), &mut Some(ref mut engine)) = (edit, &mut app.engine) {
+            if let Ok(mut animations) = engine.registry.get_mut::<AnimationManager>() {
+                if let Ok(scripts) = animations.load_scripts_mut(&character) {
+                    let script = &mut scripts[player.animation_index];
+                    let step = player.step_index;
+                    player.playing = false;
+                    player.step_index = match edit {
+                        ScriptEdit::Longer => { script.change_duration(step, 1); step }
+                        ScriptEdit::Shorter => { script.change_duration(step, -1); step }
+                        ScriptEdit::Earlier => script.move_step(step, true),
+                        ScriptEdit::Later => script.move_step(step, false),
+                    };
+                    preview_changed = true;
+                }
+                animations.mark_dirty(&character, player.animation_index);
+            }
+        }
+
+        if let Some(preview) = app.preview {
+            let (frame_width, frame_height) = app.frame_size();
+            let width = (frame_width + PREVIEW_MARGIN * 2) as f64 * PREVIEW_SCALE as f64;
+            let height = (frame_height + PREVIEW_MARGIN * 2) as f64 * PREVIEW_SCALE as f64;
+            widget::Image::new(preview)
+                .w_h(width, height)
+                .right_from(ids.preview_loop, 20.0)
+                .align_top_of(ids.preview_play)
+                .set(ids.preview_image, ui);
+
+            if let (Some(compare), Some(_), Some(_)) = (app.compare, app.compare_a, app.compare_b) {
+                let (width, height) = match app.compare_mode {
+                    CompareMode::SideBySide => (frame_width * 2 * PREVIEW_SCALE, frame_height * PREVIEW_SCALE),
+                    CompareMode::Difference => (frame_width * PREVIEW_SCALE, frame_height * PREVIEW_SCALE),
+                };
+                widget::Image::new(compare)
+                    .w_h(width as f64, height as f64)
+                    .down_from(ids.preview_image, 10.0)
+                    .align_left_of(ids.preview_image)
+                    .set(ids.compare_image, ui);
+            }
+        }
+    }
+    if preview_changed {
+        app.update_preview(display, image_map);
+        app.update_editor(display, image_map);
+    }
+    if compare_changed {
+        app.update_compare(display, image_map);
+    }
+
+    // the editor and spritesheet view go below the preview controls
+    let panel_bottom = if app.player.is_some() { ids.compare_set_a } else { ids.spritesheet_write };
+
+    //
+    // Pixel editor
+    //
+
+    let editing = app.editing_frame();
+    if let (Some(character), Some((animation_index, frame_index)), true) = (app.get_character(), editing, app.editor.is_some() && app.browser.is_none() && app.diff.is_none() && !app.script_open) {
+        let (frame_width, frame_height) = app.frame_size();
+        let (width, height) = ((frame_width * EDITOR_SCALE) as f64, (frame_height * EDITOR_SCALE) as f64);
+        if let Some(editor_image) = app.editor_image {
+            widget::Image::new(editor_image)
+                .w_h(width, height)
+                .down_from(panel_bottom, 40.0)
+                .align_left_of(ids.spritesheet_write)
+                .set(ids.editor_image, ui);
+        }
+
+        // mouse positions are relative to the middle of the image
+        let to_pixel = |xy: conrod::Point| {
+            let x = ((xy[0] + width / 2.0) / EDITOR_SCALE as f64).floor();
+            let y = ((height / 2.0 - xy[1]) / EDITOR_SCALE as f64).floor();
+            if x < 0.0 || y < 0.0 || x >= frame_width as f64 || y >= frame_height as f64 {
+                None
+            } else {
+                Some((x as usize, y as usize))
+            }
+        };
+
+        let mut frame_changed = false;
+        let mut stroke_ended = false;
+        let mut palette = Vec::new();
+        if let (&mut Some(ref mut editor), &mut Some(ref mut engine)) = (&mut app.editor, &mut app.engine) {
+            palette = palette_colors(engine, &character, app.palette_variant).unwrap_or_default();
+            if let Ok(mut sprites) = engine.registry.get_mut::<SpriteManager>() {
+                if let Ok(spritesheet) = sprites.load_spritesheet_mut(&character) {
+                    let input = ui.widget_input(ids.editor_image);
+                    for (xy, _) in input.presses().mouse().left() {
+                        if let Some((x, y)) = to_pixel(xy) {
+                            frame_changed |= editor.press(spritesheet, animation_index, frame_index, x, y);
+                        }
+                    }
+                    for drag in input.drags().left() {
+                        if let Some((x, y)) = to_pixel(drag.to) {
+                            frame_changed |= editor.drag(spritesheet, animation_index, frame_index, x, y);
+                        }
+                    }
+                    for _release in input.releases().mouse().left() {
+                        editor.release();
+                        stroke_ended = true;
+                    }
+                }
+                if frame_changed {
+                    sprites.mark_dirty(&character, animation_index, frame_index);
                 }
             }
         }
 
-    if let Some(ref image) = app.spritesheet {
-        widget::Image::new(*image)
-//            .w_h(400.0,60.0)
-            .middle()
-            .w_h(800.0, 400.0)
-            .down(40.0)
+        if let Some(ref mut editor) = app.editor {
+            for (i, &(tool, name)) in TOOLS.iter().enumerate() {
+                let button = widget::Button::new()
+                    .label(name)
+                    .small_font(ui)
+                    .w_h(60.0, 25.0)
+                    .color(if editor.tool == tool { conrod::color::LIGHT_BLUE } else { conrod::color::LIGHT_RED });
+                let button = if i == 0 {
+                    button.right_from(ids.editor_image, 20.0).align_top_of(ids.editor_image)
+                } else {
+                    button.down_from(ids.editor_tools[i - 1], 5.0).align_left_of(ids.editor_tools[i - 1])
+                };
+                for _press in button.set(ids.editor_tools[i], ui) {
+                    editor.tool = tool;
+                }
+            }
+
+            // palette indices, 0 is transparent
+            for (i, color) in palette.iter().enumerate().take(16) {
+                let button = widget::Button::new()
+                    .w_h(20.0, 20.0)
+                    .color(conrod::color::rgb_bytes(color.r as u8, color.g as u8, color.b as u8))
+                    .border(if editor.color_index as usize == i { 3.0 } else { 0.0 })
+                    .border_color(conrod::color::WHITE);
+                let button = if i == 0 {
+                    button.down_from(ids.editor_tools[TOOLS.len() - 1], 20.0).align_left_of(ids.editor_tools[0])
+                } else if i % 4 == 0 {
+                    button.down_from(ids.editor_swatches[i - 4], 5.0).align_left_of(ids.editor_swatches[i - 4])
+                } else {
+                    button.right_from(ids.editor_swatches[i - 1], 5.0).align_middle_y_of(ids.editor_swatches[i - 1])
+                };
+                for _press in button.set(ids.editor_swatches[i], ui) {
+                    editor.color_index = i as u8;
+                }
+            }
+
+            for _press in widget::Button::new()
+                .label("Undo")
+                .small_font(ui)
+                .down_from(ids.editor_swatches[12], 20.0)
+                .align_left_of(ids.editor_swatches[12])
+                .w_h(60.0, 25.0)
+                .set(ids.editor_undo, ui)
+                {
+                    if let Some(ref mut engine) = app.engine {
+                        if let Ok(mut sprites) = engine.registry.get_mut::<SpriteManager>() {
+                            let undone = sprites.load_spritesheet_mut(&character).ok().and_then(|spritesheet| editor.undo(spritesheet));
+                            if let Some((animation, frame)) = undone {
+                                frame_changed = true;
+                                stroke_ended = true;
+                                sprites.mark_dirty(&character, animation, frame);
+                            }
+                        }
+                    }
+                }
+        }
+
+        if frame_changed {
+            app.update_editor(display, image_map);
+            app.update_preview(display, image_map);
+        }
+        if stroke_ended {
+            app.refresh_spritesheet(display, image_map);
+        }
+    }
+
+    //
+    // Spritesheet view
+    //
+
+    if let (Some(image), None, None, None, false) = (app.spritesheet, &app.editor, &app.browser, &app.diff, app.script_open) {
+        let mut view_changed = false;
+        let (sheet_width, sheet_height) = app.sheet.as_ref().map_or((0, 0), |sheet| sheet.dimensions());
+
+        for _press in widget::Button::new()
+            .label("-")
+            .small_font(ui)
+            .down_from(panel_bottom, 20.0)
+            .align_left_of(ids.spritesheet_write)
+            .w_h(25.0, 25.0)
+            .set(ids.view_zoom_out, ui)
+            {
+                let zoom = app.view.zoom - 1;
+                app.view.zoom_around(zoom, VIEW_WIDTH as f64 / 2.0, VIEW_HEIGHT as f64 / 2.0);
+                view_changed = true;
+            }
+
+        for _press in widget::Button::new()
+            .label("+")
+            .small_font(ui)
+            .right_from(ids.view_zoom_out, 5.0)
+            .align_middle_y_of(ids.view_zoom_out)
+            .w_h(25.0, 25.0)
+            .set(ids.view_zoom_in, ui)
+            {
+                let zoom = app.view.zoom + 1;
+                app.view.zoom_around(zoom, VIEW_WIDTH as f64 / 2.0, VIEW_HEIGHT as f64 / 2.0);
+                view_changed = true;
+            }
+
+        for grid in widget::Toggle::new(app.view.grid)
+            .label("Grid")
+            .small_font(ui)
+            .right_from(ids.view_zoom_in, 10.0)
+            .align_middle_y_of(ids.view_zoom_in)
+            .w_h(50.0, 25.0)
+            .set(ids.view_grid, ui)
+            {
+                app.view.grid = grid;
+                view_changed = true;
+            }
+
+        widget::Image::new(image)
+            .w_h(VIEW_WIDTH as f64, VIEW_HEIGHT as f64)
+            .down_from(ids.view_zoom_out, 10.0)
+            .align_left_of(ids.view_zoom_out)
             .set(ids.spritesheet, ui);
+
+        // conrod's y axis points up and is relative to the middle of the image
+        let to_view = |xy: conrod::Point| (xy[0] + VIEW_WIDTH as f64 / 2.0, VIEW_HEIGHT as f64 / 2.0 - xy[1]);
+
+        let input = ui.widget_input(ids.spritesheet);
+        for drag in input.drags().left() {
+            app.view.drag(drag.delta_xy[0], -drag.delta_xy[1], sheet_width, sheet_height);
+            view_changed = true;
+        }
+
+        let hover = input.mouse().map(|mouse| to_view(mouse.rel_xy()));
+        for scroll in input.scrolls() {
+            if let Some((x, y)) = hover {
+                let zoom = if scroll.y > 0.0 { app.view.zoom + 1 } else { app.view.zoom.saturating_sub(1) };
+                app.view.zoom_around(zoom, x, y);
+                app.view.clamp(sheet_width, sheet_height);
+                view_changed = true;
+            }
+        }
+
+        let hover_text = match (hover.and_then(|(x, y)| app.view.pixel_at(x, y, sheet_width, sheet_height)), app.get_character(), &app.engine) {
+            (Some((x, y)), Some(character), &Some(ref engine)) => {
+                let sprites = engine.registry.get::<SpriteManager>().ok();
+                match sprites.as_ref().and_then(|sprites| sprites.load_spritesheet(&character).ok()) {
+                    Some(spritesheet) => {
+                        let info = hover_info(spritesheet, x, y);
+                        format!("{}  frame {}  section {}  pixel ({}, {})  palette index {}",
+                                character.animation_label(info.animation), info.frame, info.section, info.x, info.y,
+                                info.palette_index.map_or("-".to_string(), |index| index.to_string()))
+                    }
+                    None => String::new(),
+                }
+            }
+            _ => String::new(),
+        };
+        widget::Text::new(&format!("{}x  {}", app.view.zoom, hover_text))
+            .font_size(12)
+            .right_from(ids.view_grid, 10.0)
+            .align_middle_y_of(ids.view_grid)
+            .set(ids.view_hover, ui);
+
+        if view_changed {
+            app.update_view(display, image_map);
+        }
+    }
+
+    //
+    // Tile browser
+    //
+
+    if let (Some(image), true) = (app.browser_image, app.browser.is_some()) {
+        let mut browser_changed = false;
+
+        for event in widget::TextBox::new(&app.browser_offset)
+            .font_size(12)
+            .down_from(panel_bottom, 20.0)
+            .align_left_of(ids.spritesheet_write)
+            .w_h(90.0, 25.0)
+            .set(ids.browser_offset, ui)
+            {
+                match event {
+                    widget::text_box::Event::Update(text) => app.browser_offset = text,
+                    widget::text_box::Event::Enter => {
+                        let text = app.browser_offset.trim_start_matches("0x").to_string();
+                        if let (Ok(offset), Some(ref mut browser)) = (usize::from_str_radix(&text, 16), app.browser.as_mut()) {
+                            browser.offset = offset.min(browser.rom_length().saturating_sub(1));
+                        }
+                        browser_changed = true;
+                    }
+                }
+            }
+
+        let browser = app.browser.as_mut().unwrap();
+        let (row, page) = (browser.row_bytes() as i64, browser.page_bytes() as i64);
+        let scrolls = [
+            (ids.browser_page_back, "<<", -page),
+            (ids.browser_row_back, "<", -row),
+            (ids.browser_row_forward, ">", row),
+            (ids.browser_page_forward, ">>", page),
+        ];
+        let mut previous = ids.browser_offset;
+        for &(id, label, bytes) in scrolls.iter() {
+            for _press in widget::Button::new()
+                .label(label)
+                .small_font(ui)
+                .right_from(previous, 5.0)
+                .align_middle_y_of(previous)
+                .w_h(30.0, 25.0)
+                .set(id, ui)
+                {
+                    browser.scroll(bytes);
+                    browser_changed = true;
+                }
+            previous = id;
+        }
+
+        for eight in widget::Toggle::new(browser.bpp == 8)
+            .label("8bpp")
+            .small_font(ui)
+            .right_from(ids.browser_page_forward, 10.0)
+            .align_middle_y_of(ids.browser_page_forward)
+            .w_h(50.0, 25.0)
+            .set(ids.browser_bpp, ui)
+            {
+                browser.bpp = if eight { 8 } else { 4 };
+                browser_changed = true;
+            }
+
+        for _press in widget::Button::new()
+            .label("-")
+            .small_font(ui)
+            .right_from(ids.browser_bpp, 10.0)
+            .align_middle_y_of(ids.browser_bpp)
+            .w_h(25.0, 25.0)
+            .set(ids.browser_narrower, ui)
+            {
+                browser.width = (browser.width - 1).max(1);
+                browser_changed = true;
+            }
+
+        for _press in widget::Button::new()
+            .label("+")
+            .small_font(ui)
+            .right_from(ids.browser_narrower, 5.0)
+            .align_middle_y_of(ids.browser_narrower)
+            .w_h(25.0, 25.0)
+            .set(ids.browser_wider, ui)
+            {
+                browser.width = (browser.width + 1).min(64);
+                browser_changed = true;
+            }
+
+        for sprite in widget::Toggle::new(browser.arrangement == Arrangement::Sprite)
+            .label("Sprite layout")
+            .small_font(ui)
+            .right_from(ids.browser_wider, 10.0)
+            .align_middle_y_of(ids.browser_wider)
+            .w_h(85.0, 25.0)
+            .set(ids.browser_arrangement, ui)
+            {
+                browser.arrangement = if sprite { Arrangement::Sprite } else { Arrangement::Linear };
+                browser_changed = true;
+            }
+
+        let palette_names: Vec<&str> = app.browser_palettes.iter().map(|palette| palette.name.as_str()).collect();
+        for selected_index in widget::DropDownList::new(palette_names.as_slice(), Some(browser.palette_index))
+            .small_font(ui)
+            .right_from(ids.browser_arrangement, 10.0)
+            .align_middle_y_of(ids.browser_arrangement)
+            .w_h(100.0, 25.0)
+            .scrollbar_on_top()
+            .max_visible_items(12)
+            .set(ids.browser_palette, ui)
+            {
+                browser.palette_index = selected_index;
+                browser_changed = true;
+            }
+
+        let (width, height) = browser.pixel_size();
+        let (width, height) = ((width * BROWSER_SCALE) as f64, (height * BROWSER_SCALE) as f64);
+        widget::Image::new(image)
+            .w_h(width, height)
+            .down_from(ids.browser_offset, 10.0)
+            .align_left_of(ids.browser_offset)
+            .set(ids.browser_image, ui);
+
+        let hover = ui.widget_input(ids.browser_image).mouse().map(|mouse| mouse.rel_xy())
+            .map(|xy| ((xy[0] + width / 2.0) / BROWSER_SCALE as f64, (height / 2.0 - xy[1]) / BROWSER_SCALE as f64))
+            .filter(|&(x, y)| x >= 0.0 && y >= 0.0)
+            .and_then(|(x, y)| browser.offset_at(x as u32, y as u32));
+        widget::Text::new(&hover.map_or(String::new(), |offset| format!("tile at {:#X}", offset)))
+            .font_size(12)
+            .right_from(ids.browser_image, 10.0)
+            .align_top_of(ids.browser_image)
+            .set(ids.browser_hover, ui);
+
+        if browser_changed {
+            app.update_browser(display, image_map);
+        }
+    }
+
+    //
+    // ROM diff
+    //
+
+    if let Some(ref diff) = app.diff {
+        let mut report = diff.report();
+        if report.len() > DIFF_LINES {
+            let more = report.len() - DIFF_LINES;
+            report.truncate(DIFF_LINES);
+            report.push(format!("{} more lines, the full list is in the console", more));
+        }
+        widget::Text::new(&report.join("\n"))
+            .font_size(11)
+            .w(400.0)
+            .down_from(panel_bottom, 20.0)
+            .align_left_of(ids.spritesheet_write)
+            .set(ids.diff_text, ui);
+    }
+
+    let mut diff_changed = false;
+    if let (Some(character), &Some(ref diff)) = (app.get_character(), &app.diff) {
+        let labels: Vec<String> = diff.frames(&character).iter()
+            .map(|&(animation, frame)| format!("{} {}", character.animation_label(animation), frame))
+            .collect();
+        if !labels.is_empty() {
+            for selected_index in widget::DropDownList::new(labels.as_slice(), app.diff_frame)
+                .small_font(ui)
+                .right_from(ids.diff_text, 10.0)
+                .align_top_of(ids.diff_text)
+                .w_h(130.0, 25.0)
+                .scrollbar_on_top()
+                .max_visible_items(12)
+                .set(ids.diff_frames, ui)
+                {
+                    app.diff_frame = Some(selected_index);
+                    diff_changed = true;
+                }
+        }
+    }
+    if let (Some(image), Some(_), true) = (app.diff_image, app.diff_frame, app.diff.is_some()) {
+        let (frame_width, frame_height) = app.frame_size();
+        widget::Image::new(image)
+            .w_h(((frame_width * 2 + 2) * PREVIEW_SCALE) as f64, (frame_height * PREVIEW_SCALE) as f64)
+            .down_from(ids.diff_frames, 10.0)
+            .align_left_of(ids.diff_frames)
+            .set(ids.diff_image, ui);
+    }
+    if diff_changed {
+        app.update_diff(display, image_map);
+    }
+
+    //
+    // Script console
+    //
+
+    let mut script_ran = false;
+    if app.script_open {
+        for text in widget::TextEdit::new(&app.script_text)
+            .font_size(12)
+            .w_h(400.0, 200.0)
+            .down_from(panel_bottom, 20.0)
+            .align_left_of(ids.spritesheet_write)
+            .set(ids.script_text, ui)
+            {
+                app.script_text = text;
+            }
+
+        for _press in widget::Button::new()
+            .label("Run Script")
+            .small_font(ui)
+            .right_from(ids.script_text, 10.0)
+            .align_top_of(ids.script_text)
+            .w_h(90.0, 25.0)
+            .set(ids.script_run, ui)
+            {
+                app.script_output.borrow_mut().clear();
+                if let Some(engine) = app.engine.take() {
+                    let output = app.script_output.clone();
+                    let (engine, result) = script::run(engine, "console", &app.script_text, script::CONSOLE_MAX_OPERATIONS, move |text| output.borrow_mut().push(text.to_string()));
+                    if let Err(error) = result {
+                        app.script_output.borrow_mut().push(format!("Error: {}", error));
+                    }
+                    app.engine = Some(engine);
+                    script_ran = true;
+                } else {
+                    app.script_output.borrow_mut().push("Open a ROM before running a script".to_string());
+                }
+            }
+
+        for _press in widget::Button::new()
+            .label("Load Script")
+            .small_font(ui)
+            .down_from(ids.script_run, 5.0)
+            .align_left_of(ids.script_run)
+            .w_h(90.0, 25.0)
+            .set(ids.script_load, ui)
+            {
+                match nfd::dialog().filter("rhai").open() {
+                    Ok(Response::Okay(file_name)) => match std::fs::read_to_string(&file_name) {
+                        Ok(source) => app.script_text = source,
+                        Err(error) => app.report(format!("Couldn't read {}: {}", file_name, error)),
+                    },
+                    Ok(_) => (),
+                    Err(error) => app.report(format!("Couldn't open the file dialog: {}", error)),
+                }
+            }
+
+        let output = app.script_output.borrow();
+        let shown = &output[output.len().saturating_sub(SCRIPT_LINES)..];
+        widget::Text::new(&shown.join("\n"))
+            .font_size(11)
+            .w(400.0)
+            .down_from(ids.script_text, 10.0)
+            .align_left_of(ids.script_text)
+            .set(ids.script_output, ui);
+    }
+
+    if script_ran {
+        // the script could have changed anything the editor's undo steps were made from
+        if let Some(ref mut editor) = app.editor {
+            editor.clear_history();
+        }
+        app.refresh_spritesheet(display, image_map);
+        app.update_editor(display, image_map);
+        app.update_compare(display, image_map);
     }
 
     widget::Scrollbar::y_axis(ids.canvas).auto_hide(true).set(ids.canvas_scrollbar, ui);
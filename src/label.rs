@@ -0,0 +1,40 @@
+use conrod::text::{Font, Scale};
+use conrod::text::rt::point;
+use image::{ImageBuffer, Rgb};
+use std::sync::OnceLock;
+
+use ::color::*;
+
+/// The font labels are drawn with, the GUI uses it too. It's loaded the first time it's needed.
+pub fn font() -> &'static Font {
+    static FONT: OnceLock<Font> = OnceLock::new();
+    FONT.get_or_init(|| Font::from_bytes(include_bytes!("assets/NotoSans-Regular.ttf").to_vec()).unwrap())
+}
+
+/// Draw text onto an image, anything past `max_width` pixels is cut off
+pub fn draw_text(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, text: &str, x: u32, y: u32, size: f32, max_width: u32, color: Color) {
+    let scale = Scale::uniform(size);
+    let ascent = font().v_metrics(scale).ascent;
+
+    for glyph in font().layout(text, scale, point(x as f32, y as f32 + ascent)) {
+        if let Some(bounds) = glyph.pixel_bounding_box() {
+            glyph.draw(|gx, gy, v| {
+                let px = bounds.min.x + gx as i32;
+                let py = bounds.min.y + gy as i32;
+                if px < 0 || py < 0 || px as u32 >= x + max_width
+                    || px as u32 >= image.width() || py as u32 >= image.height() {
+                    return;
+                }
+
+                // blend the glyph coverage with the background
+                let pixel = image.get_pixel_mut(px as u32, py as u32);
+                let blend = |old: u8, new: i32| (old as f32 * (1.0 - v) + new as f32 * v) as u8;
+                pixel.data = [
+                    blend(pixel.data[0], color.r),
+                    blend(pixel.data[1], color.g),
+                    blend(pixel.data[2], color.b),
+                ];
+            });
+        }
+    }
+}
@@ -5,31 +5,55 @@ extern crate lazy_static;
 #[macro_use]
 extern crate conrod;
 extern crate image;
+extern crate rhai;
 
 use conrod::backend::glium::glium;
 use conrod::backend::glium::glium::Surface;
-use conrod::text::Font;
 
-use std::fs::{File, OpenOptions, create_dir_all};
+use std::fs::{File, create_dir_all};
 use std::io::Read;
 use std::error::Error;
 use std::env;
-use std::sync::{Arc, Mutex};
 use std::rc::Rc;
 use std::time::Instant;
 use self::image::{open, ImageBuffer, Rgb, DynamicImage, ImageRgb8, ImageRgba8, ConvertBuffer};
 
 mod gui;
+mod browser;
+mod cli;
 mod data;
+mod diff;
 mod color;
+mod compression;
+mod editor;
 mod engine;
+mod error;
+mod export;
+mod label;
 mod manager;
+mod mods;
+mod preview;
+mod project;
+mod remap;
+mod rom;
+mod script;
+mod settings;
+mod view;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const WINDOW_WIDTH: u32 = gui::WINDOW_WIDTH;
 const WINDOW_HEIGHT: u32 = gui::WINDOW_HEIGHT;
 
 pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args) {
+        if let Err(error) = result {
+            println!("Error: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut events_loop = glium::glutin::EventsLoop::new();
 
     let window = glium::glutin::WindowBuilder::new()
@@ -47,37 +71,28 @@ pub fn main() {
 
     let mut ui = conrod::UiBuilder::new([WINDOW_WIDTH as f64, WINDOW_HEIGHT as f64]).theme(gui::theme()).build();
 
-    let font = Font::from_bytes(include_bytes!("assets/NotoSans-Regular.ttf").to_vec()).unwrap();
-    ui.fonts.insert(font);
+    ui.fonts.insert(label::font().clone());
 
-    let ids = gui::Ids::new(ui.widget_id_generator());
+    let mut ids = gui::Ids::new(ui.widget_id_generator());
+    ids.editor_tools.resize(editor::TOOLS.len(), &mut ui.widget_id_generator());
+    ids.editor_swatches.resize(16, &mut ui.widget_id_generator());
 
-    let engine: Option<engine::Engine> = if env::args().len() > 1 {
+    // a ROM that can't be opened is reported in the window, not only on the console
+    let (engine, error) = if env::args().len() > 1 {
         let file_name = env::args().nth(1).unwrap();
-        let file_result = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(file_name);
-        match file_result {
-            Ok(file) => {
-                let mut engine = engine::Engine::new(Arc::new(Mutex::new(file)));
-                if let Ok(_) = engine.start() {
-                    Some(engine)
-                } else {
-                    None
-                }
-            }
-            Err(error) => {
-                println!("Error occurred while opening file: {}", error);
-                None
-            }
+        match engine::Engine::open(&file_name) {
+            Ok(engine) => (Some(engine), None),
+            Err(error) => (None, Some(format!("Error occurred while opening {}: {}", file_name, error))),
         }
     } else {
         println!("No file specified!");
-        None
+        (None, None)
     };
 
     let mut app = gui::GuiState::new(engine);
+    if let Some(error) = error {
+        app.report(error);
+    }
     let mut renderer = conrod::backend::glium::Renderer::new(&display).unwrap();
 
     let mut event_loop = gui::EventLoop::new();
@@ -99,7 +114,7 @@ pub fn main() {
                             ..
                         },
                         ..
-                    } => break 'main,
+                    } => app.request_quit(),
                     _ => (),
                 },
                 _ => (),
@@ -107,6 +122,15 @@ pub fn main() {
         }
 
         gui::gui(&display, &mut image_map, &mut ui.set_widgets(), &ids, &mut app);
+        if app.should_quit() {
+            break 'main;
+        }
+
+        // keep drawing while the animation preview is playing
+        if app.is_animating() {
+            ui.needs_redraw();
+            event_loop.needs_update();
+        }
 
         if let Some(primitives) = ui.draw_if_changed() {
             renderer.fill(&display, primitives, &image_map);
@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::time::Instant;
+use std::sync::{Arc, Mutex};
+
+use ::data::*;
+use ::rom::*;
+use ::engine::*;
+use ::error::*;
+use ::preview::DEFAULT_FRAME_TICKS;
+use ::manager::asset::{AssetManager, full_name};
+use ::manager::space::SpaceManager;
+use ::manager::sprite::{SpriteManager, character_asset};
+
+/*
+ * Animation scripts
+ *
+ * The format below was worked out by looking at the ROM, there's no documentation of it to go by.
+ * Every character is assumed to have a table of ROM pointers (0x08xxxxxx), one per animation.
+ * Each pointer leads to a script of 4 byte commands:
+ *
+ *     frame: u8, duration: u8, x: i8, y: i8
+ *
+ * `frame` is relative to the first frame of the animation, `duration` is in ticks (1/60s) and
+ * (x, y) is added to the sprite position while the frame is shown. A duration of 0 ends the script:
+ *
+ *     frame 0xFF: jump back to command `x`, the animation loops
+ *     frame 0xFE: hold the last frame, the animation plays once
+ *
+ * Tables are found by searching the ROM for pointers to data that decodes as scripts fitting the
+ * character's frame counts, once for every character that doesn't have a table yet. That can be a
+ * false match, so scripts are only shown until the table has been checked (like by changing a
+ * duration in an emulator) and confirmed with `sbrx script-table <rom> <character> --confirm
+ * <offset>`. Confirmed tables are kept in the record next to the ROM and are the only ones written.
+ */
+
+/// size of a single script command
+pub const COMMAND_SIZE: usize = 4;
+
+/// scripts longer than this are assumed to be garbage while searching for tables
+const MAX_COMMANDS: usize = 64;
+
+const LOOP_COMMAND: u8 = 0xFF;
+const END_COMMAND: u8 = 0xFE;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScriptFrame {
+    pub frame: u8,
+    pub duration: u8,
+    pub x: i8,
+    pub y: i8,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationScript {
+    pub frames: Vec<ScriptFrame>,
+    /// command to jump back to at the end, None if the animation plays once
+    pub loop_start: Option<usize>,
+    /// where the script is stored, None if it wasn't found in the ROM
+    pub offset: Option<u64>,
+    /// number of bytes the script takes up in the ROM
+    pub length: usize,
+}
+
+impl AnimationScript {
+    /// A script that plays every frame in order, used when the ROM's scripts couldn't be found
+    pub fn contiguous(frame_count: usize, looping: bool) -> AnimationScript {
+        AnimationScript {
+            frames: (0..frame_count).map(|frame| ScriptFrame {
+                frame: frame as u8,
+                duration: DEFAULT_FRAME_TICKS as u8,
+                x: 0,
+                y: 0,
+            }).collect(),
+            loop_start: if looping { Some(0) } else { None },
+            offset: None,
+            length: 0,
+        }
+    }
+
+    /// Decode a script, returns None if the bytes don't look like a valid script for an animation
+    /// with `frame_count` frames
+    pub fn decode(bytes: &[u8], frame_count: usize) -> Option<AnimationScript> {
+        let mut frames = Vec::new();
+        for command in bytes.chunks(COMMAND_SIZE).take(MAX_COMMANDS + 1) {
+            if command.len() < COMMAND_SIZE {
+                return None;
+            }
+            let (frame, duration, x, y) = (command[0], command[1], command[2] as i8, command[3] as i8);
+
+            if duration != 0 {
+                if frame as usize >= frame_count {
+                    return None;
+                }
+                frames.push(ScriptFrame { frame, duration, x, y });
+                continue;
+            }
+
+            if frames.is_empty() {
+                return None;
+            }
+            let loop_start = match frame {
+                LOOP_COMMAND if (x as u8 as usize) < frames.len() => Some(x as u8 as usize),
+                END_COMMAND => None,
+                _ => return None,
+            };
+            let length = (frames.len() + 1) * COMMAND_SIZE;
+            return Some(AnimationScript { frames, loop_start, offset: None, length });
+        }
+        None
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((self.frames.len() + 1) * COMMAND_SIZE);
+        for frame in self.frames.iter() {
+            bytes.extend_from_slice(&[frame.frame, frame.duration, frame.x as u8, frame.y as u8]);
+        }
+        match self.loop_start {
+            Some(start) => bytes.extend_from_slice(&[LOOP_COMMAND, 0, start as u8, 0]),
+            None => bytes.extend_from_slice(&[END_COMMAND, 0, 0, 0]),
+        }
+        bytes
+    }
+
+    /// Change how long a step is shown, steps are shown for at least one tick
+    pub fn change_duration(&mut self, step: usize, delta: i32) {
+        if let Some(frame) = self.frames.get_mut(step) {
+            frame.duration = (frame.duration as i32 + delta).max(1).min(0xFF) as u8;
+        }
+    }
+
+    /// Swap a step with one of its neighbours, returns the new position of the step
+    pub fn move_step(&mut self, step: usize, earlier: bool) -> usize {
+        if earlier && step > 0 && step < self.frames.len() {
+            self.frames.swap(step, step - 1);
+            step - 1
+        } else if !earlier && step + 1 < self.frames.len() {
+            self.frames.swap(step, step + 1);
+            step + 1
+        } else {
+            step
+        }
+    }
+
+    /// Length of one playthrough in ticks
+    pub fn total_ticks(&self) -> u32 {
+        self.frames.iter().map(|frame| frame.duration as u32).sum()
+    }
+}
+
+pub struct AnimationManager {
+    file: Arc<Mutex<File>>,
+    pub scripts: HashMap<String, Vec<AnimationScript>>,
+    /// location of each character's pointer table, found or confirmed. Found tables are kept so
+    /// reading the scripts again doesn't search the ROM again.
+    pub tables: HashMap<String, u64>,
+    /// characters whose table was confirmed, only their scripts are written
+    pub confirmed: HashSet<String>,
+    /// animations whose scripts changed since they were read or written, by character
+    pub dirty: HashMap<String, HashSet<usize>>,
+}
+
+impl AnimationManager {
+    pub fn new(file: Arc<Mutex<File>>) -> AnimationManager {
+        AnimationManager {
+            file: file.clone(),
+            scripts: HashMap::new(),
+            tables: HashMap::new(),
+            confirmed: HashSet::new(),
+            dirty: HashMap::new(),
+        }
+    }
+
+    /// Find and decode the animation scripts of every character, the sprites have to be read first
+    /// so the scripts can be checked against the animations' frame counts. Confirmed tables come
+    /// from the space manager's record.
+    pub fn read_scripts(&mut self, sprite_manager: &SpriteManager, space_manager: &SpaceManager) -> Result<(), SbrxError> {
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        self.confirmed.clear();
+        for (name, &table) in space_manager.script_tables.iter() {
+            self.tables.insert(name.clone(), table);
+            self.confirmed.insert(name.clone());
+        }
+
+        let frame_counts: Vec<(Character, Vec<usize>)> = CHARACTERS.iter()
+            .map(|character| (*character, sprite_manager.frame_counts(character)))
+            .collect();
+        let missing: Vec<(Character, Vec<usize>)> = frame_counts.iter()
+            .filter(|&&(ref character, ref counts)| self.known_table(&rom, character, &counts[..]).is_none())
+            .cloned()
+            .collect();
+        for &(ref character, _) in missing.iter() {
+            if self.confirmed.remove(character.name) {
+                println!("Warning: {}'s confirmed animation table doesn't fit its animations any more", character.name);
+            }
+        }
+        if !missing.is_empty() {
+            let start = Instant::now();
+            let found = find_script_tables(&rom, &missing[..]);
+            println!(" * searched for {} animation tables ({:?})", missing.len(), start.elapsed());
+            for (name, table) in found {
+                self.tables.insert(name.to_string(), table as u64);
+            }
+        }
+
+        for &(ref character, ref counts) in frame_counts.iter() {
+            self.read_character_scripts(&rom, character, &counts[..]);
+        }
+        Ok(())
+    }
+
+    /// The character's table if one was found or confirmed before and it still fits
+    fn known_table(&self, rom: &[u8], character: &Character, frame_counts: &[usize]) -> Option<usize> {
+        let table = *self.tables.get(character.name)? as usize;
+        read_script_table(rom, table, frame_counts).map(|_| table)
+    }
+
+    fn read_character_scripts(&mut self, rom: &[u8], character: &Character, frame_counts: &[usize]) {
+        let scripts = match self.known_table(rom, character, frame_counts) {
+            Some(table) => {
+                let state = if self.confirmed.contains(character.name) { "confirmed" } else { "not confirmed, it won't be written" };
+                println!(" * {} animation table at {:#X}, {}", character.name, table, state);
+                read_script_table(rom, table, frame_counts).unwrap()
+            }
+            None => {
+                println!(" * {} animation table not found, using frame order", character.name);
+                self.tables.remove(character.name);
+                self.confirmed.remove(character.name);
+                frame_counts.iter().enumerate()
+                    .map(|(i, &frames)| AnimationScript::contiguous(frames, character.animation_info(i).looping))
+                    .collect()
+            }
+        };
+        self.scripts.insert(character.name.to_string(), scripts);
+        self.dirty.remove(character.name);
+    }
+
+    /// Offset of the character's table if it was found, and if it was confirmed
+    pub fn table(&self, character: &Character) -> Option<(u64, bool)> {
+        self.tables.get(character.name).map(|&table| (table, self.confirmed.contains(character.name)))
+    }
+
+    /// Remember that an animation's script was edited
+    pub fn mark_dirty(&mut self, character: &Character, animation: usize) {
+        self.dirty.entry(character.name.to_string()).or_insert_with(HashSet::new).insert(animation);
+    }
+
+    pub fn load_scripts(&self, character: &Character) -> Result<&Vec<AnimationScript>, SbrxError> {
+        match self.scripts.get(&character.name.to_string()) {
+            Some(scripts) => Ok(scripts),
+            None => Err(SbrxError::UnknownCharacter(character.name.to_string())),
+        }
+    }
+
+    pub fn load_scripts_mut(&mut self, character: &Character) -> Result<&mut Vec<AnimationScript>, SbrxError> {
+        match self.scripts.get_mut(&character.name.to_string()) {
+            Some(scripts) => Ok(scripts),
+            None => Err(SbrxError::UnknownCharacter(character.name.to_string())),
+        }
+    }
+
+    /// Write a character's edited scripts back in place, returns how many bytes were written. The
+    /// table has to be confirmed. Writes are checked against `assets`.
+    pub fn write_scripts(&mut self, character: &Character, assets: &[AssetRegion]) -> Result<usize, SbrxError> {
+        match self.table(character) {
+            Some((_, true)) => (),
+            Some((table, false)) => return Err(SbrxError::Unsupported(format!(
+                "{}'s animation table at {:#X} was found by searching and isn't confirmed, check it and confirm it with sbrx script-table <rom> {} --confirm {:#X}",
+                character.name, table, character.name, table))),
+            None => return Err(SbrxError::Unsupported(format!("{}'s animation table wasn't found", character.name))),
+        }
+        let mut written = 0;
+        let scripts = self.load_scripts(character)?;
+        for (animation_index, script) in scripts.iter().enumerate() {
+            let offset = match script.offset {
+                Some(offset) => offset,
+                None => continue,
+            };
+
+            let bytes = script.encode();
+            if bytes.len() > script.length {
+                return Err(SbrxError::BoundsViolation(format!(
+                    "{} animation {} grew from {} to {} bytes and doesn't fit in the ROM",
+                    character.name, animation_index, script.length, bytes.len())));
+            }
+
+            let mut file = self.file.lock().unwrap();
+            let rom_length = rom_length(&file)?;
+            check_write(&format!("{} {}", full_name(character.name, "animations"), animation_index), offset, bytes.len() as u64,
+                        (offset, script.length as u64), rom_length, assets)?;
+            written += write_changes(&mut file, offset, &bytes[..])?;
+        }
+        self.dirty.remove(character.name);
+        Ok(written)
+    }
+}
+
+/// Character animation scripts as assets, named after the character
+impl AssetManager for AnimationManager {
+    fn kind(&self) -> &'static str {
+        "animations"
+    }
+
+    fn assets(&self, _engine: &Engine) -> Vec<String> {
+        CHARACTERS.iter().map(|character| character.name.to_string()).collect()
+    }
+
+    fn read(&mut self, engine: &Engine) -> Result<(), SbrxError> {
+        self.read_scripts(&*engine.registry.get::<SpriteManager>()?, &engine.space_manager)
+    }
+
+    fn load(&mut self, engine: &mut Engine, asset: &str) -> Result<(), SbrxError> {
+        let character = character_asset(asset)?;
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        let frame_counts = engine.registry.get::<SpriteManager>()?.frame_counts(&character);
+        if self.known_table(&rom, &character, &frame_counts[..]).is_none() {
+            if let Some(&(_, table)) = find_script_tables(&rom, &[(character, frame_counts.clone())]).first() {
+                self.tables.insert(character.name.to_string(), table as u64);
+            }
+        }
+        self.read_character_scripts(&rom, &character, &frame_counts[..]);
+        Ok(())
+    }
+
+    fn is_dirty(&self, _engine: &Engine, asset: &str) -> bool {
+        self.dirty.contains_key(asset)
+    }
+
+    fn write(&mut self, engine: &mut Engine, asset: &str) -> Result<usize, SbrxError> {
+        self.write_scripts(&character_asset(asset)?, &engine.space_manager.assets)
+    }
+
+    fn describe_changes(&self, _engine: &Engine, asset: &str) -> String {
+        format!("{} ({})", full_name(asset, self.kind()), self.dirty.get(asset).map_or(0, |animations| animations.len()))
+    }
+}
+
+/// Follow a pointer and decode the script it points to
+fn read_script(rom: &[u8], pointer_offset: usize, frame_count: usize) -> Option<AnimationScript> {
+    let offset = pointer_to_offset(read_u32(rom, pointer_offset)?, rom.len())?;
+    let mut script = AnimationScript::decode(&rom[offset..], frame_count)?;
+    script.offset = Some(offset as u64);
+    Some(script)
+}
+
+/// Decode every script in a pointer table
+fn read_script_table(rom: &[u8], table: usize, frame_counts: &[usize]) -> Option<Vec<AnimationScript>> {
+    frame_counts.iter().enumerate()
+        .map(|(i, &frames)| read_script(rom, table + i * 4, frames))
+        .collect()
+}
+
+/// Search the ROM for pointer tables whose scripts fit the characters' animations, the first
+/// match for each character. The ROM is gone through once for all of them.
+fn find_script_tables(rom: &[u8], characters: &[(Character, Vec<usize>)]) -> Vec<(&'static str, usize)> {
+    let mut found: Vec<(&'static str, usize)> = Vec::new();
+    for offset in (0..rom.len() / 4).map(|i| i * 4) {
+        if found.len() == characters.len() {
+            break;
+        }
+        if read_u32(rom, offset).and_then(|pointer| pointer_to_offset(pointer, rom.len())).is_none() {
+            continue;
+        }
+        for &(ref character, ref frame_counts) in characters.iter() {
+            if frame_counts.is_empty() || found.iter().any(|&(name, _)| name == character.name) {
+                continue;
+            }
+            if read_script(rom, offset, frame_counts[0]).is_some() && read_script_table(rom, offset, frame_counts).is_some() {
+                found.push((character.name, offset));
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn scripts_round_trip() {
+        let bytes = [0, 4, 1, 0xFF, 1, 6, 0, 0, LOOP_COMMAND, 0, 1, 0];
+        let script = AnimationScript::decode(&bytes, 2).unwrap();
+        assert_eq!(script.frames[0], ScriptFrame { frame: 0, duration: 4, x: 1, y: -1 });
+        assert_eq!(script.loop_start, Some(1));
+        assert_eq!(script.length, bytes.len());
+        assert_eq!(script.encode(), bytes.to_vec());
+        // frame 1 doesn't exist in a single frame animation
+        assert!(AnimationScript::decode(&bytes, 1).is_none());
+    }
+
+    #[test]
+    fn tables_are_found_in_one_pass_and_only_written_when_confirmed() {
+        let mut rom = vec![0; 0x10000];
+        rom[0x1000..0x100C].copy_from_slice(&[0, 4, 0, 0, 1, 4, 0, 0, LOOP_COMMAND, 0, 0, 0]);
+        rom[0x1010..0x1018].copy_from_slice(&[0, 8, 0, 0, END_COMMAND, 0, 0, 0]);
+        rom[0x2000..0x2008].copy_from_slice(&[0x00, 0x10, 0x00, 0x08, 0x10, 0x10, 0x00, 0x08]);
+        let character = CHARACTERS[0];
+        assert_eq!(find_script_tables(&rom, &[(character, vec![2, 1])]), vec![(character.name, 0x2000)]);
+
+        let path = ::std::env::temp_dir().join(format!("sbrx-animation-{}.gba", ::std::process::id()));
+        fs::write(&path, &rom[..]).unwrap();
+        let mut manager = AnimationManager::new(Arc::new(Mutex::new(File::open(&path).unwrap())));
+        manager.tables.insert(character.name.to_string(), 0x2000);
+        manager.read_character_scripts(&rom, &character, &[2, 1]);
+        assert_eq!(manager.load_scripts(&character).unwrap()[1].offset, Some(0x1010));
+        assert!(manager.write_scripts(&character, &asset_map()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
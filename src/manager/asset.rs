@@ -0,0 +1,240 @@
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::fs::File;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use ::data::Character;
+use ::engine::*;
+use ::error::*;
+use ::manager::*;
+
+/*
+ * Asset kinds
+ *
+ * Every kind of asset (palettes, sprites, text, ...) has an AssetManager that knows how to list,
+ * read, write, export and import it. A kind keeps its assets itself and is registered with the
+ * engine. The engine goes through the registry for everything that works the same for all kinds,
+ * anything particular to a kind gets its manager from the registry with `get` or `get_mut`.
+ *
+ * A manager is borrowed from the registry while it's used. It can get other kinds from the
+ * registry while it's reading or writing, but not itself, that's an error instead of a panic.
+ *
+ * Kinds list their assets by character or background, "Sonic" or "Sonic/1". Anywhere else the
+ * kind follows, "Sonic sprites" or "Sonic/1 palette", see `full_name`.
+ */
+
+/// Lets the registry hand out the manager behind a kind
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Name of an asset for messages, like "Sonic/1 palette"
+pub fn full_name(asset: &str, kind: &str) -> String {
+    format!("{} {}", asset, kind)
+}
+
+pub trait AssetManager: AsAny + 'static {
+    /// Name of the kind, like "sprites"
+    fn kind(&self) -> &'static str;
+
+    /// Every asset of this kind that's in the ROM
+    fn assets(&self, engine: &Engine) -> Vec<String>;
+
+    /// Read every asset of this kind when the ROM is opened, after palettes, sprites and free space
+    fn read(&mut self, _engine: &Engine) -> Result<(), SbrxError> {
+        Ok(())
+    }
+
+    /// Read an asset from the ROM again, throwing away its changes
+    fn load(&mut self, engine: &mut Engine, asset: &str) -> Result<(), SbrxError>;
+
+    fn is_dirty(&self, engine: &Engine, asset: &str) -> bool;
+
+    /// Write an asset's changes to the ROM, returns how many bytes were written
+    fn write(&mut self, engine: &mut Engine, asset: &str) -> Result<usize, SbrxError>;
+
+    /// If an asset is one of a character's, they're named after the character
+    fn belongs_to(&self, asset: &str, character: &Character) -> bool {
+        asset.split('/').next() == Some(character.name)
+    }
+
+    /// Extension of exported files, None if the kind can't be exported
+    fn extension(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn export(&self, _engine: &mut Engine, _asset: &str, _path: &Path) -> Result<(), SbrxError> {
+        Err(SbrxError::Unsupported(format!("{} can't be exported", self.kind())))
+    }
+
+    /// Replace an asset with a file, it still has to be written
+    fn import(&mut self, _engine: &mut Engine, _asset: &str, _path: &Path) -> Result<(), SbrxError> {
+        Err(SbrxError::Unsupported(format!("{} can't be imported", self.kind())))
+    }
+
+    /// What changed in an asset, for lists of unsaved changes
+    fn describe_changes(&self, _engine: &Engine, asset: &str) -> String {
+        full_name(asset, self.kind())
+    }
+}
+
+/// A registered kind, it's borrowed while it's used
+pub type SharedAssetManager = Rc<RefCell<dyn AssetManager>>;
+
+/// A registered kind, found by its name or its type without borrowing it
+struct Registered {
+    kind: &'static str,
+    type_id: TypeId,
+    manager: SharedAssetManager,
+}
+
+/// The kinds of assets an engine knows about, in the order they're read and saved
+pub struct AssetRegistry {
+    managers: Vec<Registered>,
+}
+
+impl AssetRegistry {
+    pub fn new() -> AssetRegistry {
+        AssetRegistry { managers: Vec::new() }
+    }
+
+    /// Every kind sbrx can edit
+    pub fn with_defaults(file: Arc<Mutex<File>>) -> AssetRegistry {
+        let mut registry = AssetRegistry::new();
+        registry.register(palette::PaletteManager::new(file.clone()));
+        registry.register(sprite::SpriteManager::new(file.clone()));
+        registry.register(animation::AnimationManager::new(file.clone()));
+        registry.register(background::BackgroundManager::new(file.clone()));
+        registry.register(text::TextAssets);
+        registry
+    }
+
+    /// Add a kind, replacing the one with the same name
+    pub fn register<T: AssetManager>(&mut self, manager: T) {
+        let registered = Registered { kind: manager.kind(), type_id: TypeId::of::<T>(), manager: Rc::new(RefCell::new(manager)) };
+        match self.managers.iter().position(|other| other.kind == registered.kind) {
+            Some(index) => self.managers[index] = registered,
+            None => self.managers.push(registered),
+        }
+    }
+
+    /// Every kind, they're handed out separately so they can be used with the engine they're in
+    pub fn managers(&self) -> Vec<SharedAssetManager> {
+        self.managers.iter().map(|registered| registered.manager.clone()).collect()
+    }
+
+    pub fn find(&self, kind: &str) -> Result<SharedAssetManager, SbrxError> {
+        self.managers.iter().find(|registered| registered.kind == kind).map(|registered| registered.manager.clone())
+            .ok_or_else(|| SbrxError::UnknownAsset(format!("unknown asset kind {}", kind)))
+    }
+
+    fn find_type<T: AssetManager>(&self) -> Result<&Registered, SbrxError> {
+        self.managers.iter().find(|registered| registered.type_id == TypeId::of::<T>())
+            .ok_or_else(|| SbrxError::UnknownAsset("that asset kind isn't registered".to_string()))
+    }
+
+    /// The manager of a kind, to use what's particular to it. It's an error if the manager is
+    /// being changed, like while it's writing.
+    pub fn get<'a, T: AssetManager>(&'a self) -> Result<Ref<'a, T>, SbrxError> {
+        let registered = self.find_type::<T>()?;
+        let manager = registered.manager.try_borrow().map_err(|_| in_use(registered.kind))?;
+        Ok(Ref::map(manager, |manager| manager.as_any().downcast_ref::<T>().unwrap()))
+    }
+
+    /// Like `get`, it's an error if the manager is being used at all
+    pub fn get_mut<'a, T: AssetManager>(&'a self) -> Result<RefMut<'a, T>, SbrxError> {
+        let registered = self.find_type::<T>()?;
+        let manager = registered.manager.try_borrow_mut().map_err(|_| in_use(registered.kind))?;
+        Ok(RefMut::map(manager, |manager| manager.as_any_mut().downcast_mut::<T>().unwrap()))
+    }
+}
+
+fn in_use(kind: &str) -> SbrxError {
+    SbrxError::Unsupported(format!("the {} manager is already in use", kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(usize);
+
+    impl AssetManager for Counter {
+        fn kind(&self) -> &'static str {
+            "animations"
+        }
+
+        fn assets(&self, _engine: &Engine) -> Vec<String> {
+            vec!["Sonic/1".to_string(), "Tails".to_string()]
+        }
+
+        fn load(&mut self, _engine: &mut Engine, _asset: &str) -> Result<(), SbrxError> {
+            Ok(())
+        }
+
+        fn is_dirty(&self, _engine: &Engine, _asset: &str) -> bool {
+            false
+        }
+
+        fn write(&mut self, _engine: &mut Engine, _asset: &str) -> Result<usize, SbrxError> {
+            self.0 += 1;
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn kinds_keep_their_state_in_the_registry() {
+        let path = ::std::env::temp_dir().join(format!("sbrx-registry-{}.gba", ::std::process::id()));
+        let mut registry = AssetRegistry::with_defaults(Arc::new(Mutex::new(File::create(&path).unwrap())));
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(registry.get::<animation::AnimationManager>().is_ok());
+
+        // a kind with the same name takes the place of the default one
+        registry.register(Counter(0));
+        assert!(registry.get::<animation::AnimationManager>().is_err());
+        registry.get_mut::<Counter>().unwrap().0 += 2;
+        assert_eq!(registry.get::<Counter>().unwrap().0, 2);
+        assert_eq!(registry.managers().len(), 5);
+
+        let counter = registry.find("animations").unwrap();
+        let sonic = ::data::find_character("Sonic").unwrap();
+        assert!(counter.borrow().belongs_to("Sonic/1", &sonic));
+        assert!(!counter.borrow().belongs_to("Tails", &sonic));
+        assert_eq!(full_name("Sonic/1", "palette"), "Sonic/1 palette");
+    }
+
+    #[test]
+    fn managers_in_use_are_an_error_instead_of_a_panic() {
+        let path = ::std::env::temp_dir().join(format!("sbrx-registry-borrowed-{}.gba", ::std::process::id()));
+        let registry = AssetRegistry::with_defaults(Arc::new(Mutex::new(File::create(&path).unwrap())));
+        ::std::fs::remove_file(&path).unwrap();
+
+        // like a manager that's writing and looks itself up, other kinds can still be found
+        let sprites = registry.find("sprites").unwrap();
+        let writing = sprites.borrow_mut();
+        assert!(registry.get::<sprite::SpriteManager>().is_err());
+        assert!(registry.get_mut::<sprite::SpriteManager>().is_err());
+        assert!(registry.get_mut::<palette::PaletteManager>().is_ok());
+        assert!(registry.find("sprites").is_ok());
+        drop(writing);
+
+        let reading = registry.get::<palette::PaletteManager>().unwrap();
+        assert!(registry.get::<palette::PaletteManager>().is_ok());
+        assert!(registry.get_mut::<palette::PaletteManager>().is_err());
+        drop(reading);
+        assert!(registry.get_mut::<palette::PaletteManager>().is_ok());
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use image::{ImageBuffer, Rgb, open};
+
+use ::data::*;
+use ::color::*;
+use ::compression;
+use ::rom::*;
+use ::engine::*;
+use ::error::*;
+use ::manager::asset::{AssetManager, full_name};
+use ::manager::sprite::{Section, SECTION_SIZE, SECTION_BYTE_COUNT};
+
+/*
+ * Text mode backgrounds
+ *
+ * The map is a grid of 16 bit entries:
+ *
+ *     bits 0-9   tile index
+ *     bit 10     horizontal flip
+ *     bit 11     vertical flip
+ *     bits 12-15 palette bank
+ *
+ * Maps wider or taller than 32 tiles are split into 32x32 screen blocks stored one after another.
+ *
+ * Only the format is done so far: decoding, encoding and writing back tiles and maps, compressed
+ * or not. No background has been located in the ROM (`BACKGROUNDS` in data.rs is empty), so they
+ * can only be opened from the command line with their offsets, and the GUI has no background
+ * panel yet. The title screen and the stages still have to be found and checked in the game.
+ */
+
+const SCREEN_BLOCK_SIZE: usize = 32;
+const MAX_TILES: usize = 0x400;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MapEntry {
+    pub tile: usize,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub palette: usize,
+}
+
+impl MapEntry {
+    fn decode(value: u16) -> MapEntry {
+        MapEntry {
+            tile: (value & 0x3FF) as usize,
+            hflip: value & 0x400 != 0,
+            vflip: value & 0x800 != 0,
+            palette: (value >> 12) as usize,
+        }
+    }
+
+    fn encode(&self) -> u16 {
+        self.tile as u16 | (self.hflip as u16) << 10 | (self.vflip as u16) << 11 | (self.palette as u16) << 12
+    }
+}
+
+pub struct TiledBackground {
+    pub tiles: Vec<Section>,
+    /// map entries row by row
+    pub map: Vec<MapEntry>,
+    pub width: usize,
+    pub height: usize,
+    /// 16 colors per palette bank
+    pub palettes: Vec<Vec<Color>>,
+    /// bank number of the first palette, map entries use bank numbers from there on
+    pub first_bank: usize,
+    /// compression type of the tiles and map, None if they're stored as is
+    tiles_format: Option<u8>,
+    map_format: Option<u8>,
+    /// bytes available in the ROM for the tiles and map
+    tiles_length: usize,
+    map_length: usize,
+}
+
+impl TiledBackground {
+    /// Render the whole map
+    pub fn to_img(&self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new((self.width * SECTION_SIZE) as u32, (self.height * SECTION_SIZE) as u32);
+        for (index, entry) in self.map.iter().enumerate() {
+            let tile = match self.tiles.get(entry.tile) {
+                Some(tile) => tile,
+                None => continue,
+            };
+            let palette = &self.palettes[entry.palette.saturating_sub(self.first_bank).min(self.palettes.len() - 1)];
+            let (tx, ty) = ((index % self.width) * SECTION_SIZE, (index / self.width) * SECTION_SIZE);
+            for y in 0..SECTION_SIZE {
+                for x in 0..SECTION_SIZE {
+                    let sx = if entry.hflip { SECTION_SIZE - 1 - x } else { x };
+                    let sy = if entry.vflip { SECTION_SIZE - 1 - y } else { y };
+                    let c = palette[tile.bytes[sy][sx] as usize];
+                    image.get_pixel_mut((tx + x) as u32, (ty + y) as u32).data = [c.r as u8, c.g as u8, c.b as u8];
+                }
+            }
+        }
+        image
+    }
+
+    /// Replace the tiles and map with an image of the same size. Every 8x8 block has to use the
+    /// colors of a single palette bank, the one the map used there before is picked if it still
+    /// fits. Identical and flipped tiles are only stored once.
+    pub fn import_img(&mut self, image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Result<(), SbrxError> {
+        if image.width() as usize != self.width * SECTION_SIZE || image.height() as usize != self.height * SECTION_SIZE {
+            return Err(SbrxError::ImageFormat(format!(
+                "the image has to be {}x{} but is {}x{}",
+                self.width * SECTION_SIZE, self.height * SECTION_SIZE, image.width(), image.height())));
+        }
+
+        let mut tiles: Vec<Section> = Vec::new();
+        let mut known: HashMap<[[u8; SECTION_SIZE]; SECTION_SIZE], MapEntry> = HashMap::new();
+        let mut map = Vec::with_capacity(self.width * self.height);
+
+        for ty in 0..self.height {
+            for tx in 0..self.width {
+                let colors: Vec<Color> = (0..SECTION_SIZE * SECTION_SIZE).map(|i| {
+                    let rgb = image.get_pixel((tx * SECTION_SIZE + i % SECTION_SIZE) as u32, (ty * SECTION_SIZE + i / SECTION_SIZE) as u32).data;
+                    Color { r: rgb[0] as i32, g: rgb[1] as i32, b: rgb[2] as i32 }
+                }).collect();
+
+                let fits = |bank: usize| self.palettes.get(bank).map_or(false, |bank| colors.iter().all(|c| bank.contains(c)));
+                let previous = self.map.get(ty * self.width + tx).map(|entry| entry.palette.wrapping_sub(self.first_bank));
+                let bank = previous.filter(|&bank| fits(bank))
+                    .or_else(|| (0..self.palettes.len()).find(|&bank| fits(bank)))
+                    .ok_or_else(|| SbrxError::ImageFormat(format!(
+                        "tile ({}, {}) uses colors that aren't in a single palette bank", tx, ty)))?;
+                let palette = self.first_bank + bank;
+
+                let mut tile = Section::new();
+                for (i, color) in colors.iter().enumerate() {
+                    tile.bytes[i / SECTION_SIZE][i % SECTION_SIZE] = self.palettes[bank].iter().position(|c| c == color).unwrap() as u8;
+                }
+
+                let entry = match known.get(&tile.bytes) {
+                    Some(entry) => MapEntry { palette, ..*entry },
+                    None => {
+                        let index = tiles.len();
+                        for &(hflip, vflip) in [(false, false), (true, false), (false, true), (true, true)].iter() {
+                            known.entry(flip(&tile, hflip, vflip).bytes)
+                                .or_insert(MapEntry { tile: index, hflip, vflip, palette });
+                        }
+                        tiles.push(tile);
+                        MapEntry { tile: index, hflip: false, vflip: false, palette }
+                    }
+                };
+                map.push(entry);
+            }
+        }
+
+        if tiles.len() > MAX_TILES {
+            return Err(SbrxError::ImageFormat(format!("the image needs {} tiles, a map can only use {}", tiles.len(), MAX_TILES)));
+        }
+        println!("Imported {} unique tiles for {} map entries", tiles.len(), map.len());
+        self.tiles = tiles;
+        self.map = map;
+        Ok(())
+    }
+
+    fn encode_tiles(&self) -> Vec<u8> {
+        self.tiles.iter().flat_map(|tile| tile.bytes.iter().flat_map(|row| {
+            row.chunks(2).map(|pair| pair[0] | pair[1] << 4).collect::<Vec<_>>()
+        })).collect()
+    }
+
+    fn encode_map(&self) -> Vec<u8> {
+        let mut entries = vec![0u16; self.width * self.height];
+        for (index, entry) in self.map.iter().enumerate() {
+            entries[map_index(index % self.width, index / self.width, self.width, self.height)] = entry.encode();
+        }
+        entries.iter().flat_map(|&value| vec![value as u8, (value >> 8) as u8]).collect()
+    }
+}
+
+/// A copy of a tile flipped horizontally and/or vertically
+fn flip(tile: &Section, hflip: bool, vflip: bool) -> Section {
+    let mut flipped = Section::new();
+    for y in 0..SECTION_SIZE {
+        for x in 0..SECTION_SIZE {
+            let sx = if hflip { SECTION_SIZE - 1 - x } else { x };
+            let sy = if vflip { SECTION_SIZE - 1 - y } else { y };
+            flipped.bytes[y][x] = tile.bytes[sy][sx];
+        }
+    }
+    flipped
+}
+
+/// Position of a map entry in the ROM, taking screen blocks into account
+fn map_index(x: usize, y: usize, width: usize, height: usize) -> usize {
+    if width <= SCREEN_BLOCK_SIZE && height <= SCREEN_BLOCK_SIZE {
+        return y * width + x;
+    }
+    let blocks_per_row = (width + SCREEN_BLOCK_SIZE - 1) / SCREEN_BLOCK_SIZE;
+    let block = (y / SCREEN_BLOCK_SIZE) * blocks_per_row + x / SCREEN_BLOCK_SIZE;
+    block * SCREEN_BLOCK_SIZE * SCREEN_BLOCK_SIZE + (y % SCREEN_BLOCK_SIZE) * SCREEN_BLOCK_SIZE + x % SCREEN_BLOCK_SIZE
+}
+
+fn decode_tiles(bytes: &[u8]) -> Vec<Section> {
+    bytes.chunks(SECTION_BYTE_COUNT).filter(|chunk| chunk.len() == SECTION_BYTE_COUNT).map(|chunk| {
+        let mut tile = Section::new();
+        for (i, byte) in chunk.iter().enumerate() {
+            let (x, y) = ((i * 2) % SECTION_SIZE, (i * 2) / SECTION_SIZE);
+            tile.bytes[y][x] = byte & 0x0F;
+            tile.bytes[y][x + 1] = byte >> 4;
+        }
+        tile
+    }).collect()
+}
+
+/// Read `length` bytes, or the compressed data at the offset, returns (data, format, bytes used)
+fn read_block(rom: &[u8], offset: usize, length: usize, compressed: bool) -> Result<(Vec<u8>, Option<u8>, usize), SbrxError> {
+    if compressed {
+        let (data, used) = compression::decompress_at(rom, offset)?;
+        return Ok((data, Some(rom[offset]), used));
+    }
+    match rom.get(offset..offset + length) {
+        Some(bytes) => Ok((bytes.to_vec(), None, length)),
+        None => Err(SbrxError::InvalidRom(format!("{:#X} is outside of the ROM", offset))),
+    }
+}
+
+// --
+
+pub struct BackgroundManager {
+    file: Arc<Mutex<File>>,
+    color_cache: GBAColorCache,
+    pub backgrounds: HashMap<String, TiledBackground>,
+    /// backgrounds that changed since they were read or written, by name
+    pub dirty: HashMap<String, Background>,
+}
+
+impl BackgroundManager {
+    pub fn new(file: Arc<Mutex<File>>) -> BackgroundManager {
+        BackgroundManager {
+            file: file.clone(),
+            color_cache: GBAColorCache::new(),
+            backgrounds: HashMap::new(),
+            dirty: HashMap::new(),
+        }
+    }
+
+    pub fn read_backgrounds(&mut self) -> Result<(), SbrxError> {
+        if BACKGROUNDS.is_empty() {
+            return Ok(());
+        }
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        for background in BACKGROUNDS.iter() {
+            let tiled = self.read_background(&rom, background)?;
+            self.backgrounds.insert(background.name.to_string(), tiled);
+            self.dirty.remove(background.name);
+        }
+        Ok(())
+    }
+
+    /// Read a single background, also works for ones that aren't in BACKGROUNDS
+    pub fn open_background(&mut self, background: &Background) -> Result<(), SbrxError> {
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        let tiled = self.read_background(&rom, background)?;
+        self.backgrounds.insert(background.name.to_string(), tiled);
+        self.dirty.remove(background.name);
+        Ok(())
+    }
+
+    fn read_background(&mut self, rom: &[u8], background: &Background) -> Result<TiledBackground, SbrxError> {
+        let (tile_bytes, tiles_format, tiles_length) = read_block(
+            rom, background.tiles_offset as usize, background.tile_count * SECTION_BYTE_COUNT, background.compressed)?;
+        let (map_bytes, map_format, map_length) = read_block(
+            rom, background.map_offset as usize, background.width * background.height * 2, background.compressed)?;
+        if map_bytes.len() < background.width * background.height * 2 {
+            return Err(SbrxError::InvalidRom(format!("{} map is smaller than {}x{}", background.name, background.width, background.height)));
+        }
+
+        let mut map = Vec::with_capacity(background.width * background.height);
+        for y in 0..background.height {
+            for x in 0..background.width {
+                let index = map_index(x, y, background.width, background.height) * 2;
+                map.push(MapEntry::decode(map_bytes[index] as u16 | (map_bytes[index + 1] as u16) << 8));
+            }
+        }
+
+        let mut palettes = Vec::new();
+        for bank in 0..background.palette_banks.max(1) {
+            let offset = background.palette_offset as usize + bank * 32;
+            let colors = (0..16).map(|i| {
+                let value = match rom.get(offset + i * 2..offset + i * 2 + 2) {
+                    Some(bytes) => (bytes[1] as i32) << 8 | bytes[0] as i32,
+                    None => 0,
+                };
+                self.color_cache.gba_to_rgb(value)
+            }).collect();
+            palettes.push(colors);
+        }
+
+        Ok(TiledBackground {
+            tiles: decode_tiles(&tile_bytes[..]),
+            map,
+            width: background.width,
+            height: background.height,
+            palettes,
+            first_bank: background.first_bank,
+            tiles_format,
+            map_format,
+            tiles_length,
+            map_length,
+        })
+    }
+
+    pub fn load_background(&self, background: &Background) -> Result<&TiledBackground, SbrxError> {
+        match self.backgrounds.get(background.name) {
+            Some(tiled) => Ok(tiled),
+            None => Err(SbrxError::UnknownAsset(format!("unknown background {}", background.name)))
+        }
+    }
+
+    /// The background is counted as changed once it's been borrowed mutably
+    pub fn load_background_mut(&mut self, background: &Background) -> Result<&mut TiledBackground, SbrxError> {
+        match self.backgrounds.get_mut(background.name) {
+            Some(tiled) => {
+                self.dirty.insert(background.name.to_string(), *background);
+                Ok(tiled)
+            }
+            None => Err(SbrxError::UnknownAsset(format!("unknown background {}", background.name)))
+        }
+    }
+
+    /// Write a background's tiles and map back in place, in the format they were read in. Returns
+    /// how many bytes were written. Writes are checked against `assets`.
+    pub fn write_background(&mut self, background: &Background, assets: &[AssetRegion]) -> Result<usize, SbrxError> {
+        let mut written = 0;
+        let tiled = self.load_background(background)?;
+        let blocks = [
+            ("tiles", background.tiles_offset, tiled.encode_tiles(), tiled.tiles_format, tiled.tiles_length),
+            ("map", background.map_offset, tiled.encode_map(), tiled.map_format, tiled.map_length),
+        ];
+
+        // check everything fits before writing anything
+        let mut encoded = Vec::new();
+        for &(name, offset, ref data, format, length) in blocks.iter() {
+            let bytes = match format {
+                Some(kind) => compression::compress(&data[..], kind)?,
+                None => data.clone(),
+            };
+            if bytes.len() > length {
+                return Err(SbrxError::BoundsViolation(format!(
+                    "{} {} grew from {} to {} bytes and doesn't fit in the ROM", full_name(background.name, "background"), name, length, bytes.len())));
+            }
+            encoded.push((offset, bytes));
+        }
+
+        let mut file = self.file.lock().unwrap();
+        let rom_length = rom_length(&file)?;
+        for (&(name, _, _, _, length), &(offset, ref bytes)) in blocks.iter().zip(encoded.iter()) {
+            check_write(&format!("{} {}", full_name(background.name, "background"), name), offset, bytes.len() as u64, (offset, length as u64),
+                        rom_length, assets)?;
+        }
+        for (offset, bytes) in encoded {
+            written += write_changes(&mut file, offset, &bytes[..])?;
+        }
+        self.dirty.remove(background.name);
+        Ok(written)
+    }
+}
+
+impl BackgroundManager {
+    /// Backgrounds opened by hand aren't in BACKGROUNDS, they're found in the dirty list once changed
+    fn background(&self, asset: &str) -> Result<Background, SbrxError> {
+        self.dirty.get(asset).cloned()
+            .or_else(|| find_background(asset))
+            .ok_or_else(|| SbrxError::UnknownAsset(format!("unknown background {}", asset)))
+    }
+}
+
+/// Backgrounds that were read as assets, named like in data.rs
+impl AssetManager for BackgroundManager {
+    fn kind(&self) -> &'static str {
+        "background"
+    }
+
+    fn assets(&self, _engine: &Engine) -> Vec<String> {
+        let mut names: Vec<String> = self.backgrounds.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn read(&mut self, _engine: &Engine) -> Result<(), SbrxError> {
+        self.read_backgrounds()
+    }
+
+    fn load(&mut self, _engine: &mut Engine, asset: &str) -> Result<(), SbrxError> {
+        let background = self.background(asset)?;
+        self.open_background(&background)
+    }
+
+    fn is_dirty(&self, _engine: &Engine, asset: &str) -> bool {
+        self.dirty.contains_key(asset)
+    }
+
+    fn write(&mut self, engine: &mut Engine, asset: &str) -> Result<usize, SbrxError> {
+        let background = self.background(asset)?;
+        self.write_background(&background, &engine.space_manager.assets)
+    }
+
+    /// Backgrounds aren't any character's, even when they share a name
+    fn belongs_to(&self, _asset: &str, _character: &Character) -> bool {
+        false
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("png")
+    }
+
+    fn export(&self, _engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let background = self.background(asset)?;
+        Ok(self.load_background(&background)?.to_img().save(path)?)
+    }
+
+    fn import(&mut self, _engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let background = self.background(asset)?;
+        let image = open(path).map_err(|e| SbrxError::ImageFormat(format!("{}: {}", path.display(), e)))?.to_rgb();
+        self.load_background_mut(&background)?.import_img(&image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(offset: i32) -> Vec<Color> {
+        (0..16).map(|i| Color { r: (offset + i) * 8, g: 0, b: 0 }).collect()
+    }
+
+    #[test]
+    fn imported_maps_keep_the_bank_numbers() {
+        let mut tile = Section::new();
+        for y in 0..SECTION_SIZE {
+            for x in 0..SECTION_SIZE {
+                tile.bytes[y][x] = (x + y) as u8 % 16;
+            }
+        }
+        let mut background = TiledBackground {
+            tiles: vec![tile.clone(), flip(&tile, true, false)],
+            map: vec![
+                MapEntry { tile: 0, hflip: false, vflip: false, palette: 8 },
+                MapEntry { tile: 1, hflip: false, vflip: false, palette: 9 },
+                MapEntry { tile: 0, hflip: false, vflip: true, palette: 9 },
+            ],
+            width: 3,
+            height: 1,
+            // the two banks have the same colors, the map decides which one a tile uses
+            palettes: vec![bank(0), bank(0)],
+            first_bank: 8,
+            tiles_format: None,
+            map_format: None,
+            tiles_length: 0,
+            map_length: 0,
+        };
+
+        let image = background.to_img();
+        background.import_img(&image).unwrap();
+        assert_eq!(background.tiles.len(), 1);
+        assert_eq!(background.map[0], MapEntry { tile: 0, hflip: false, vflip: false, palette: 8 });
+        assert_eq!(background.map[1], MapEntry { tile: 0, hflip: true, vflip: false, palette: 9 });
+        assert_eq!(background.map[2], MapEntry { tile: 0, hflip: false, vflip: true, palette: 9 });
+        assert!(background.to_img().into_raw() == image.into_raw());
+    }
+
+    #[test]
+    fn map_entries_round_trip() {
+        let entry = MapEntry { tile: 0x2AB, hflip: true, vflip: false, palette: 13 };
+        assert_eq!(MapEntry::decode(entry.encode()), entry);
+        assert_eq!(entry.encode(), 0xD6AB);
+    }
+}
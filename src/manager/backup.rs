@@ -0,0 +1,279 @@
+use std::fs::{self, File};
+use std::io::{SeekFrom, Seek, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ::error::*;
+use ::rom::*;
+use ::settings::Settings;
+
+/*
+ * Backups
+ *
+ * The ROM is copied into the backup directory before the first write of a session, or before every
+ * write if `backup_every_write` is set. Backups are named after the ROM and the time they were made
+ * so they sort oldest first:
+ *
+ *     <rom name>.<YYYYMMDD-HHMMSS>.gba
+ *     <rom name>.<YYYYMMDD-HHMMSS>-<n>.gba    the nth backup made in the same second
+ *
+ * Only the last `backup_keep` backups of each ROM are kept. The files sbrx keeps next to the ROM
+ * (moved assets and installed mods) describe what's in it, so they're backed up and restored with
+ * it, as `<backup>.sbrx`, `<backup>.mods` and `<backup>.mods.d/`.
+ */
+
+/// files and directories next to the ROM that belong to it, named `<rom><suffix>`
+const SIDE_FILES: [&'static str; 3] = [".sbrx", ".mods", ".mods.d"];
+
+pub struct BackupManager {
+    file: Arc<Mutex<File>>,
+    /// path of the ROM, None if the engine was made from an open file
+    rom_path: Option<String>,
+    /// file name of the ROM
+    rom_name: Option<String>,
+    settings: Settings,
+    /// if a backup was made since the ROM was opened
+    backed_up: bool,
+}
+
+impl BackupManager {
+    pub fn new(file: Arc<Mutex<File>>, rom_path: Option<&str>, settings: Settings) -> BackupManager {
+        BackupManager {
+            file: file.clone(),
+            rom_path: rom_path.map(|path| path.to_string()),
+            rom_name: rom_path.and_then(|path| Path::new(path).file_stem()).map(|name| name.to_string_lossy().into_owned()),
+            settings,
+            backed_up: false,
+        }
+    }
+
+    /// Back up the ROM if the settings say it's time, call before writing anything. ROMs without a
+    /// path aren't backed up.
+    pub fn before_write(&mut self) -> Result<(), SbrxError> {
+        if self.rom_name.is_none() || self.settings.backup_keep == 0 || (self.backed_up && !self.settings.backup_every_write) {
+            return Ok(());
+        }
+        self.backup()?;
+        Ok(())
+    }
+
+    /// Copy the ROM into the backup directory and remove the oldest backups, returns the new backup
+    pub fn backup(&mut self) -> Result<PathBuf, SbrxError> {
+        self.backup_keeping(None)
+    }
+
+    /// Back up the ROM without removing `keep`, even if it's one of the oldest
+    fn backup_keeping(&mut self, keep: Option<&Path>) -> Result<PathBuf, SbrxError> {
+        let name = self.rom_name.clone()
+            .ok_or_else(|| SbrxError::Unsupported("the ROM has no path to name backups after".to_string()))?;
+        fs::create_dir_all(&self.settings.backup_dir)?;
+
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        let mut path = Path::new(&self.settings.backup_dir).join(format!("{}.{}.gba", name, timestamp()));
+        // two backups in the same second
+        let mut count = 1;
+        while path.exists() {
+            count += 1;
+            path = Path::new(&self.settings.backup_dir).join(format!("{}.{}-{}.gba", name, timestamp(), count));
+        }
+        fs::write(&path, &rom[..])?;
+        if let Some(ref rom_path) = self.rom_path {
+            for suffix in SIDE_FILES.iter() {
+                copy_side_file(Path::new(&format!("{}{}", rom_path, suffix)), &side_file(&path, suffix))?;
+            }
+        }
+        self.backed_up = true;
+        println!("Backed up the ROM to {}", path.display());
+
+        let backups = self.backups()?;
+        if backups.len() > self.settings.backup_keep {
+            for old in backups[..backups.len() - self.settings.backup_keep].iter().filter(|&old| Some(old.as_path()) != keep) {
+                fs::remove_file(old)?;
+                for suffix in SIDE_FILES.iter() {
+                    remove_side_file(&side_file(old, suffix))?;
+                }
+                println!("Removed old backup {}", old.display());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Every backup of this ROM, oldest first
+    pub fn backups(&self) -> Result<Vec<PathBuf>, SbrxError> {
+        let name = match self.rom_name {
+            Some(ref name) => format!("{}.", name),
+            None => return Ok(Vec::new()),
+        };
+        let entries = match fs::read_dir(&self.settings.backup_dir) {
+            Ok(entries) => entries,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let order = path.file_name().and_then(|file_name| backup_order(&name, &file_name.to_string_lossy()));
+            if let Some(order) = order {
+                backups.push((order, path));
+            }
+        }
+        backups.sort();
+        Ok(backups.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Overwrite the ROM and the files next to it with a backup, everything has to be read again
+    /// afterwards. Files the backup doesn't have didn't exist when it was made, so they're removed.
+    /// The ROM is backed up first, so restoring the wrong backup can be undone.
+    pub fn restore(&mut self, backup: &Path) -> Result<(), SbrxError> {
+        let contents = fs::read(backup)?;
+        self.backup_keeping(Some(backup))?;
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&contents[..])?;
+            file.set_len(contents.len() as u64)?;
+        }
+        if let Some(ref rom_path) = self.rom_path {
+            for suffix in SIDE_FILES.iter() {
+                let side = PathBuf::from(format!("{}{}", rom_path, suffix));
+                remove_side_file(&side)?;
+                copy_side_file(&side_file(backup, suffix), &side)?;
+            }
+        }
+        println!("Restored the ROM from {}", backup.display());
+        Ok(())
+    }
+
+    pub fn backup_dir(&self) -> &str {
+        &self.settings.backup_dir
+    }
+}
+
+/// When a backup was made as (timestamp, count in that second), None if the file isn't a backup of
+/// the ROM named `<prefix>`
+fn backup_order(prefix: &str, file_name: &str) -> Option<(String, u32)> {
+    if !file_name.starts_with(prefix) || !file_name.ends_with(".gba") {
+        return None;
+    }
+    let stamp = &file_name[prefix.len()..file_name.len() - ".gba".len()];
+    let mut parts = stamp.splitn(3, '-');
+    let (date, time) = (parts.next()?, parts.next()?);
+    if date.len() != 8 || time.len() != 6 || !date.chars().chain(time.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let count = match parts.next() {
+        Some(count) => count.parse().ok()?,
+        None => 1,
+    };
+    Some((format!("{}-{}", date, time), count))
+}
+
+/// `<path><suffix>`, like the record next to a backup
+fn side_file(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Copy a file or a directory with everything in it, nothing happens if it doesn't exist
+fn copy_side_file(from: &Path, to: &Path) -> Result<(), SbrxError> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_side_file(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if from.exists() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn remove_side_file(path: &Path) -> Result<(), SbrxError> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// The current time in UTC as YYYYMMDD-HHMMSS
+fn timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // days since 1970-01-01 to a date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn restore_brings_back_the_files_next_to_the_rom() {
+        let directory = ::std::env::temp_dir().join(format!("sbrx-backup-{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let rom_path = directory.join("test.gba").to_string_lossy().into_owned();
+        fs::write(&rom_path, b"before").unwrap();
+        fs::write(format!("{}.sbrx", rom_path), "alloc Sonic/1 0x100 0x40\n").unwrap();
+
+        let file = Arc::new(Mutex::new(OpenOptions::new().read(true).write(true).open(&rom_path).unwrap()));
+        let settings = Settings { backup_dir: directory.join("backups").to_string_lossy().into_owned(), ..Settings::default() };
+        let mut manager = BackupManager::new(file.clone(), Some(&rom_path), settings);
+        let backup = manager.backup().unwrap();
+        assert!(side_file(&backup, ".sbrx").exists());
+        assert!(!side_file(&backup, ".mods").exists());
+
+        fs::write(&rom_path, b"after, and longer").unwrap();
+        fs::write(format!("{}.sbrx", rom_path), "alloc Sonic/1 0x200 0x80\n").unwrap();
+        fs::write(format!("{}.mods", rom_path), "mod test\n").unwrap();
+        fs::create_dir_all(format!("{}.mods.d/test", rom_path)).unwrap();
+
+        manager.restore(&backup).unwrap();
+        assert_eq!(fs::read(&rom_path).unwrap(), b"before");
+        // what was there before the restore was backed up
+        let backups = manager.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(fs::read(&backups[1]).unwrap(), b"after, and longer");
+        assert!(side_file(&backups[1], ".mods").exists());
+        assert_eq!(fs::read_to_string(format!("{}.sbrx", rom_path)).unwrap(), "alloc Sonic/1 0x100 0x40\n");
+        assert!(!Path::new(&format!("{}.mods", rom_path)).exists());
+        assert!(!Path::new(&format!("{}.mods.d", rom_path)).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn backups_in_the_same_second_sort_after_the_first() {
+        let mut names = vec!["test.20240102-030405-2.gba", "test.20240102-030405-10.gba", "test.20240102-030405.gba", "test.20231231-235959.gba"];
+        names.sort_by_key(|name| backup_order("test.", name));
+        assert_eq!(names, ["test.20231231-235959.gba", "test.20240102-030405.gba", "test.20240102-030405-2.gba", "test.20240102-030405-10.gba"]);
+        assert_eq!(backup_order("test.", "test.sbrx.gba"), None);
+        assert_eq!(backup_order("test.", "other.20240102-030405.gba"), None);
+    }
+
+    #[test]
+    fn roms_without_a_path_are_not_backed_up() {
+        let path = ::std::env::temp_dir().join(format!("sbrx-backup-pathless-{}.gba", ::std::process::id()));
+        fs::write(&path, b"rom").unwrap();
+        let file = Arc::new(Mutex::new(File::open(&path).unwrap()));
+        let mut manager = BackupManager::new(file, None, Settings::default());
+        assert!(manager.before_write().is_ok());
+        assert!(manager.backup().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,5 +2,12 @@
  * Import all the managers
  */
 
+pub mod animation;
+pub mod asset;
+pub mod backup;
+pub mod background;
 pub mod palette;
+pub mod pointer;
+pub mod space;
 pub mod sprite;
+pub mod text;
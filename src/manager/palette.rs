@@ -1,17 +1,36 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{SeekFrom, Seek, Read, Error, Write};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{SeekFrom, Seek, Read};
+use std::path::Path;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
 use ::data::*;
 use ::color::*;
+use ::rom::*;
 use ::engine::*;
+use ::error::*;
+use ::manager::asset::{AssetManager, full_name};
+use ::project::{format_palette, parse_palette};
+
+/// most characters only have a few colorways, if more than this look like one the match is too
+/// loose and only the ones the game points to are kept
+const MAX_VARIANTS: usize = 8;
+
+/// a variant keeps at least this many colors of the main palette, like the eyes and outline
+const MIN_SHARED_COLORS: usize = 4;
 
 pub struct PaletteManager {
     file: Arc<Mutex<File>>,
     color_cache: GBAColorCache,
     palettes: HashMap<String, Vec<i32>>,
+    /// indices of the colors that changed since the palette was read or written, by name
+    pub dirty: HashMap<String, Vec<usize>>,
+    /// offsets of every palette of a character, the one in data.rs first. Only variants the game
+    /// has a pointer to are here, they're edited and written like the main palette.
+    pub variants: HashMap<String, Vec<u64>>,
+    /// offsets of palettes that look like variants but nothing points to, they're never written
+    pub unconfirmed: HashMap<String, Vec<u64>>,
 }
 
 impl PaletteManager {
@@ -20,11 +39,24 @@ impl PaletteManager {
             file: file.clone(),
             color_cache: GBAColorCache::new(),
             palettes: HashMap::new(),
+            dirty: HashMap::new(),
+            variants: HashMap::new(),
+            unconfirmed: HashMap::new(),
         }
     }
 
     /// Store the palette of GBA encoded numbers
     pub fn store_palette_i32(&mut self, name: String, colors: Vec<i32>) {
+        let changed: Vec<usize> = {
+            let previous = self.palettes.get(&name).map(|previous| &previous[..]).unwrap_or(&[]);
+            (0..colors.len()).filter(|&i| previous.get(i) != Some(&colors[i])).collect()
+        };
+        if !changed.is_empty() {
+            let dirty = self.dirty.entry(name.clone()).or_insert_with(Vec::new);
+            dirty.extend(changed);
+            dirty.sort();
+            dirty.dedup();
+        }
         self.palettes.insert(name, colors);
     }
 
@@ -45,51 +77,264 @@ impl PaletteManager {
         values.iter().map(|&i| self.color_cache.gba_to_rgb(i)).collect()
     }
 
-    /// Read all the palettes in the ROM and store them
-    pub fn read_palettes(&mut self) -> Result<(), Error> {
+    /// Read all the palettes in the ROM and store them, with every variant the game points to
+    pub fn read_palettes(&mut self) -> Result<(), SbrxError> {
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        let mut found = find_variants(&rom);
         for character in CHARACTERS.iter() {
-            self.read_palette(character)?
+            self.read_palette(character)?;
+            let (confirmed, unconfirmed) = found.remove(character.name).unwrap_or_default();
+            if !confirmed.is_empty() || !unconfirmed.is_empty() {
+                println!(" * {} has {} palette variants, {} more that nothing points to aren't used",
+                         character.name, confirmed.len(), unconfirmed.len());
+            }
+            let mut variants = vec![character.palette_offset];
+            variants.extend(confirmed);
+            for (variant, &offset) in variants.iter().enumerate().skip(1) {
+                self.read_palette_at(variant_name(character, variant), offset)?;
+            }
+            self.variants.insert(character.name.to_string(), variants);
+            self.unconfirmed.insert(character.name.to_string(), unconfirmed);
         }
         Ok(())
     }
 
     /// Read a palette for a specific character and store it
-    pub fn read_palette(&mut self, character: &Character) -> Result<(), Error> {
-        self.file.lock().unwrap().seek(SeekFrom::Start(character.palette_offset))?;
+    pub fn read_palette(&mut self, character: &Character) -> Result<(), SbrxError> {
+        self.read_palette_at(String::from(character.name), character.palette_offset)
+    }
+
+    fn read_palette_at(&mut self, name: String, offset: u64) -> Result<(), SbrxError> {
+        self.file.lock().unwrap().seek(SeekFrom::Start(offset))?;
 
         let mut color_buffer: [u8; 32] = [0; 32];
-        self.file.lock().unwrap().read(&mut color_buffer[..])?;
+        self.file.lock().unwrap().read_exact(&mut color_buffer[..])?;
 
         let mut colors = [0; 16];
         for i in 0..16 {
             let a = color_buffer[i * 2] as i32;
             let b = color_buffer[i * 2 + 1] as i32;
 
-            // swap the bytes
+            // swap the bytes, the top bit isn't part of the color
             let color: i32 = (b << 8) | a;
-            colors[i] = color;
+            colors[i] = color & 0x7FFF;
         }
-        self.store_palette_i32(String::from(character.name), colors.to_vec());
+        self.store_palette_i32(name.clone(), colors.to_vec());
+        self.dirty.remove(&name);
         Ok(())
     }
 
-    /// Write the palette stored for a character into the ROM
-    pub fn write_palette(&mut self, character: &Character) -> Result<(), Error> {
-        self.file.lock().unwrap().seek(SeekFrom::Start(character.palette_offset))?;
-        for i in self.load_palette_i32(character.name.to_string()).iter() {
-            let b = (i & 0xFF00) >> 8;
-            let a = i & 0x00FF;
-            self.file.lock().unwrap().write(&[a as u8, b as u8])?;
+    /// Palettes a character has, the one in data.rs first
+    pub fn variant_count(&self, character: &Character) -> usize {
+        self.variants.get(character.name).map_or(1, |variants| variants.len())
+    }
+
+    pub fn variant_offset(&self, character: &Character, variant: usize) -> Option<u64> {
+        match self.variants.get(character.name) {
+            Some(variants) => variants.get(variant).cloned(),
+            None if variant == 0 => Some(character.palette_offset),
+            None => None,
         }
+    }
+
+    /// Write one of a character's palettes into the ROM, checked against `assets`. Only the colors
+    /// that are different are written, returns how many bytes were written.
+    pub fn write_variant(&mut self, character: &Character, variant: usize, assets: &[AssetRegion]) -> Result<usize, SbrxError> {
+        let offset = self.variant_offset(character, variant)
+            .ok_or_else(|| SbrxError::UnknownAsset(format!("{} has no palette variant {}", character.name, variant)))?;
+        let name = variant_name(character, variant);
+        let colors = self.load_palette_i32(name.clone());
+        let rom_length = rom_length(&self.file.lock().unwrap())?;
+        check_write(&full_name(&name, "palette"), offset, colors.len() as u64 * 2, (offset, 32), rom_length, assets)?;
+
+        // the top bit is kept as it is in the ROM, so unchanged colors stay the same bytes
+        let mut file = self.file.lock().unwrap();
+        let mut current = [0; 32];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut current)?;
+        let bytes: Vec<u8> = colors.iter().enumerate()
+            .map(|(i, &color)| color | ((current[i * 2 + 1] as i32 & 0x80) << 8))
+            .flat_map(|i| vec![(i & 0x00FF) as u8, ((i & 0xFF00) >> 8) as u8])
+            .collect();
+        let written = write_changes(&mut file, offset, &bytes[..])?;
+        self.dirty.remove(&name);
+        Ok(written)
+    }
+}
+
+/// Name a palette variant is stored under, the main palette is stored under the character's name
+pub fn variant_name(character: &Character, variant: usize) -> String {
+    if variant == 0 {
+        character.name.to_string()
+    } else {
+        format!("{}/{}", character.name, variant)
+    }
+}
+
+/// The character and variant of a name from `variant_name`
+pub fn parse_variant_name(name: &str) -> Option<(Character, usize)> {
+    let mut split = name.splitn(2, '/');
+    let character = find_character(split.next()?)?;
+    let variant = match split.next() {
+        Some(variant) => variant.parse().ok()?,
+        None => 0,
+    };
+    Some((character, variant))
+}
+
+impl PaletteManager {
+    fn variant(&self, asset: &str) -> Result<(Character, usize), SbrxError> {
+        parse_variant_name(asset)
+            .filter(|&(character, variant)| variant < self.variant_count(&character))
+            .ok_or_else(|| SbrxError::UnknownAsset(format!("unknown palette {}", asset)))
+    }
+}
+
+/// Character palettes as assets, named like `variant_name`
+impl AssetManager for PaletteManager {
+    fn kind(&self) -> &'static str {
+        "palette"
+    }
+
+    fn assets(&self, _engine: &Engine) -> Vec<String> {
+        CHARACTERS.iter()
+            .flat_map(|character| (0..self.variant_count(character)).map(move |variant| variant_name(character, variant)))
+            .collect()
+    }
+
+    fn read(&mut self, _engine: &Engine) -> Result<(), SbrxError> {
+        self.read_palettes()
+    }
+
+    fn load(&mut self, _engine: &mut Engine, asset: &str) -> Result<(), SbrxError> {
+        let (character, variant) = self.variant(asset)?;
+        let offset = self.variant_offset(&character, variant).unwrap_or(character.palette_offset);
+        self.read_palette_at(asset.to_string(), offset)
+    }
+
+    fn is_dirty(&self, _engine: &Engine, asset: &str) -> bool {
+        self.dirty.contains_key(asset)
+    }
+
+    fn write(&mut self, engine: &mut Engine, asset: &str) -> Result<usize, SbrxError> {
+        let (character, variant) = self.variant(asset)?;
+        self.write_variant(&character, variant, &engine.space_manager.assets)
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("pal")
+    }
+
+    fn export(&self, _engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        self.variant(asset)?;
+        Ok(fs::write(path, format_palette(&self.load_palette_i32(asset.to_string())[..]))?)
+    }
+
+    fn import(&mut self, _engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        self.variant(asset)?;
+        let colors = parse_palette(&fs::read_to_string(path)?)
+            .ok_or_else(|| SbrxError::ImageFormat(format!("{} isn't 16 hex colors", path.display())))?;
+        self.store_palette_i32(asset.to_string(), colors);
         Ok(())
     }
 
-    pub fn print_palette(&mut self, character: &Character) {
-        let converted_colors = self.load_palette_colors(character.name.to_string());
-        println!("v== {} ==v", character.name);
-        for convcol in converted_colors.iter() {
-            println!("{:?}", convcol)
+    fn describe_changes(&self, _engine: &Engine, asset: &str) -> String {
+        let colors = self.dirty.get(asset).map_or(0, |colors| colors.len());
+        format!("{} ({} colors)", full_name(asset, self.kind()), colors)
+    }
+}
+
+/// Look for other colorways of every character's palette, as (confirmed, unconfirmed) offsets by
+/// character. A variant has the same transparent color and keeps some colors in the same slots,
+/// every color has to be a valid GBA color and it can't be part of another known asset. It's only
+/// confirmed if there's a pointer to it somewhere in the ROM. The ROM is gone through twice, once
+/// for the palettes and once for the pointers, however many characters there are.
+fn find_variants(rom: &[u8]) -> HashMap<&'static str, (Vec<u64>, Vec<u64>)> {
+    let color = |offset: usize| (rom[offset] as u16) | (rom[offset + 1] as u16) << 8;
+    let palette = |offset: usize| -> Vec<u16> { (0..16).map(|i| color(offset + i * 2)).collect() };
+    let mains: Vec<(&Character, Vec<u16>)> = CHARACTERS.iter()
+        .filter(|character| character.palette_offset as usize + 32 <= rom.len())
+        .map(|character| (character, palette(character.palette_offset as usize)))
+        .collect();
+    let assets = asset_map();
+
+    let mut candidates: HashMap<&'static str, Vec<u64>> = HashMap::new();
+    for offset in (0..rom.len().saturating_sub(32) / 4).map(|i| i * 4) {
+        let first = color(offset);
+        for &(character, ref main_colors) in mains.iter().filter(|&&(character, ref main_colors)| main_colors[0] == first && character.palette_offset as usize != offset) {
+            let colors = palette(offset);
+            let shared = colors.iter().zip(main_colors.iter()).skip(1).filter(|&(a, b)| a == b).count();
+            if shared < MIN_SHARED_COLORS || colors.iter().any(|&c| c > 0x7FFF) {
+                continue;
+            }
+            let is_asset = assets.iter().any(|asset| (offset as u64) < asset.offset + asset.length && asset.offset < offset as u64 + 32);
+            if !is_asset {
+                candidates.entry(character.name).or_insert_with(Vec::new).push(offset as u64);
+            }
+        }
+    }
+
+    let wanted: HashMap<u32, u64> = candidates.values().flat_map(|offsets| offsets.iter())
+        .filter_map(|&offset| offset_to_pointer(offset as usize).map(|pointer| (pointer, offset)))
+        .collect();
+    let mut referenced = HashSet::new();
+    if !wanted.is_empty() {
+        for word in rom.chunks(4).filter(|word| word.len() == 4) {
+            let value = word[0] as u32 | (word[1] as u32) << 8 | (word[2] as u32) << 16 | (word[3] as u32) << 24;
+            if let Some(&offset) = wanted.get(&value) {
+                referenced.insert(offset);
+            }
+        }
+    }
+
+    candidates.into_iter().map(|(name, offsets)| {
+        let (confirmed, mut unconfirmed): (Vec<u64>, Vec<u64>) = offsets.into_iter().partition(|offset| referenced.contains(offset));
+        if confirmed.len() + unconfirmed.len() > MAX_VARIANTS {
+            println!("Warning: {} palettes look like {} variants, too many to be real, only {} the game points to are kept",
+                     confirmed.len() + unconfirmed.len(), name, confirmed.len());
+            unconfirmed.clear();
+        }
+        (name, (confirmed, unconfirmed))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rom::tests::noise_rom;
+
+    fn put_palette(rom: &mut [u8], offset: usize, colors: &[u16]) {
+        for (i, &color) in colors.iter().enumerate() {
+            rom[offset + i * 2] = color as u8;
+            rom[offset + i * 2 + 1] = (color >> 8) as u8;
+        }
+    }
+
+    fn found_for(rom: &[u8], character: Character) -> (Vec<u64>, Vec<u64>) {
+        find_variants(rom).remove(character.name).unwrap_or_default()
+    }
+
+    #[test]
+    fn only_variants_the_game_points_to_are_confirmed() {
+        let mut rom = noise_rom();
+        let character = CHARACTERS[0];
+        let main: Vec<u16> = (0..16).map(|i| i * 0x421).collect();
+        let mut variant = main.clone();
+        for color in variant[8..].iter_mut() {
+            *color = 0x7C00;
+        }
+        put_palette(&mut rom, character.palette_offset as usize, &main);
+        put_palette(&mut rom, 0xF00000, &variant);
+        put_palette(&mut rom, 0xF00100, &variant);
+        // a pointer to the first one
+        rom[0xF00200..0xF00204].copy_from_slice(&[0x00, 0x00, 0xF0, 0x08]);
+
+        assert_eq!(found_for(&rom, character), (vec![0xF00000], vec![0xF00100]));
+
+        // too many look-alikes are dropped, unless something points to them
+        for i in 0..MAX_VARIANTS {
+            put_palette(&mut rom, 0xF01000 + i * 0x100, &variant);
         }
-        println!("^== {} ==^", character.name);
+        assert_eq!(found_for(&rom, character), (vec![0xF00000], vec![]));
     }
 }
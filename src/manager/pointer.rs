@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use ::data::*;
+use ::error::*;
+use ::rom::*;
+use ::manager::sprite::SpriteManager;
+
+/// pointer tables shorter than this are probably just pointers that happen to be next to each other
+const MIN_TABLE_LENGTH: usize = 4;
+
+/// A named offset that other parts of the ROM point to
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    pub offset: u64,
+}
+
+/// A pointer to a target
+#[derive(Copy, Clone, Debug)]
+pub struct Reference {
+    /// where the pointer is stored
+    pub from: u64,
+    /// (start, index) of the pointer table the pointer is in, if it's in one
+    pub table: Option<(u64, usize)>,
+}
+
+/// Index of every pointer to a known asset
+pub struct PointerManager {
+    file: Arc<Mutex<File>>,
+    pub targets: Vec<Target>,
+    /// pointers to each target offset
+    pub references: HashMap<u64, Vec<Reference>>,
+    /// (start, length) of every run of pointers in the ROM
+    pub tables: Vec<(u64, usize)>,
+}
+
+impl PointerManager {
+    pub fn new(file: Arc<Mutex<File>>) -> PointerManager {
+        PointerManager {
+            file: file.clone(),
+            targets: Vec::new(),
+            references: HashMap::new(),
+            tables: Vec::new(),
+        }
+    }
+
+    /// Scan the ROM for pointers to every asset in `assets` and to the start of every animation,
+    /// the sprites have to be read first
+    pub fn build_index(&mut self, assets: &[AssetRegion], sprite_manager: &SpriteManager) -> Result<(), SbrxError> {
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+
+        self.targets = assets.iter().map(|asset| Target { name: asset.name.clone(), offset: asset.offset }).collect();
+        for character in CHARACTERS.iter() {
+            for (index, &(offset, _)) in sprite_manager.layout(character).iter().enumerate().skip(1) {
+                self.targets.push(Target { name: format!("{} {}", character.name, character.animation_label(index)), offset: offset as u64 });
+            }
+        }
+
+        self.tables = find_pointer_tables(&rom);
+        // targets that can't be pointed to can't have references either
+        let pointers: HashMap<u32, u64> = self.targets.iter()
+            .filter_map(|target| offset_to_pointer(target.offset as usize).map(|pointer| (pointer, target.offset)))
+            .collect();
+        self.references.clear();
+        for from in (0..rom.len() / 4).map(|i| i * 4) {
+            let target = match read_u32(&rom, from).and_then(|pointer| pointers.get(&pointer)) {
+                Some(&target) => target,
+                None => continue,
+            };
+            let table = self.tables.iter()
+                .find(|&&(start, length)| from as u64 >= start && (from as u64) < start + length as u64 * 4)
+                .map(|&(start, _)| (start, (from as u64 - start) as usize / 4));
+            self.references.entry(target).or_insert_with(Vec::new).push(Reference { from: from as u64, table });
+        }
+
+        let found = self.targets.iter().filter(|target| self.references.contains_key(&target.offset)).count();
+        println!(" * pointers found to {} of {} assets, {} pointer tables", found, self.targets.len(), self.tables.len());
+        Ok(())
+    }
+
+    /// Every pointer to an offset
+    pub fn references_to(&self, offset: u64) -> &[Reference] {
+        match self.references.get(&offset) {
+            Some(references) => &references[..],
+            None => &[],
+        }
+    }
+}
+
+/// Runs of consecutive words that are all pointers into the ROM
+pub fn find_pointer_tables(rom: &[u8]) -> Vec<(u64, usize)> {
+    let mut tables = Vec::new();
+    let mut start = None;
+    for offset in (0..rom.len() / 4 + 1).map(|i| i * 4) {
+        let is_pointer = read_u32(rom, offset).and_then(|pointer| pointer_to_offset(pointer, rom.len())).is_some();
+        match (is_pointer, start) {
+            (true, None) => start = Some(offset),
+            (false, Some(table)) => {
+                if (offset - table) / 4 >= MIN_TABLE_LENGTH {
+                    tables.push((table as u64, (offset - table) / 4));
+                }
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    tables
+}
+
+/// Describe a reference for lists like "0x1234 (table 0x1200[3])"
+pub fn describe_reference(reference: &Reference) -> String {
+    match reference.table {
+        Some((start, index)) => format!("{:#X} (table {:#X}[{}])", reference.from, start, index),
+        None => format!("{:#X}", reference.from),
+    }
+}
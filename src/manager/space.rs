@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{SeekFrom, Seek, ErrorKind, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use ::data::*;
+use ::error::*;
+use ::rom::*;
+
+/*
+ * Free space
+ *
+ * Assets that outgrow their slot are moved into free space and every pointer to them is updated.
+ * Runs of 0xFF or 0x00 aren't always free, the game can read padding, tables or compressed data
+ * that happen to look empty, so only confirmed regions are used: regions added to the record by
+ * hand (or with `sbrx free-space --confirm`) after checking them, and allocations sbrx gave back.
+ * Empty looking regions are only suggested.
+ *
+ * A move finds its region and the pointers to the old slot first, then writes the data, then
+ * the pointers, and only then fills the old slot with 0xFF. Nothing is changed if any of it fails.
+ *
+ * Allocations are recorded next to the ROM (`<rom>.sbrx`) so editing the same asset again reuses
+ * its space instead of taking a new region every time:
+ *
+ *     free <offset> <length>
+ *     alloc <name> <offset> <length>
+ *     layout <character> <offset>:<frames> <offset>:<frames> ...
+ *
+ * The record also keeps the animation script tables that were checked and confirmed by hand, see
+ * animation.rs:
+ *
+ *     scripts <character> <offset>
+ */
+
+/// 0xFF runs shorter than this are left alone
+const MIN_FREE_FF: u64 = 0x100;
+
+/// sprites are full of 0x00 runs, so empty space filled with 0x00 has to be a lot bigger
+const MIN_FREE_00: u64 = 0x1000;
+
+const FREE_BYTE: u8 = 0xFF;
+
+pub struct SpaceManager {
+    file: Arc<Mutex<File>>,
+    /// where allocations are recorded, None if they aren't kept between runs
+    record_path: Option<String>,
+    /// (offset, length) of every confirmed free region, sorted by offset
+    pub free: Vec<(u64, u64)>,
+    /// (offset, length) of regions that look empty but weren't confirmed, never used for moves
+    pub candidates: Vec<(u64, u64)>,
+    /// (offset, length) of every asset that was moved, by name
+    pub allocations: HashMap<String, (u64, u64)>,
+    /// animation layouts of characters whose sprites were moved
+    pub layouts: HashMap<String, Vec<(i32, i32)>>,
+    /// confirmed animation script tables by character
+    pub script_tables: HashMap<String, u64>,
+    /// where the assets are, writes are checked against it and free space is kept out of it. It's
+    /// the map in data.rs until the engine works it out from what it read.
+    pub assets: Vec<AssetRegion>,
+}
+
+/// An asset's slot in the ROM, what `plan_move` is asked to fit new data into
+pub struct Slot<'a> {
+    /// name of the allocation, like "Sonic/3"
+    pub name: &'a str,
+    /// the asset it's part of, like "Sonic sprites", writes are checked against it
+    pub asset: &'a str,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A move worked out by `plan_move`, nothing is changed until it's applied
+#[derive(Clone, Debug)]
+pub struct Move {
+    /// name of the allocation, like "Sonic/3"
+    pub name: String,
+    pub from: u64,
+    pub from_length: u64,
+    /// the old slot was an allocation, it goes back to the free regions
+    pub from_allocation: bool,
+    pub to: u64,
+    pub length: u64,
+    /// where the pointers to the old slot are
+    pub pointers: Vec<u64>,
+}
+
+impl SpaceManager {
+    pub fn new(file: Arc<Mutex<File>>, record_path: Option<String>) -> SpaceManager {
+        SpaceManager {
+            file: file.clone(),
+            record_path,
+            free: Vec::new(),
+            candidates: Vec::new(),
+            allocations: HashMap::new(),
+            layouts: HashMap::new(),
+            script_tables: HashMap::new(),
+            assets: asset_map(),
+        }
+    }
+
+    /// Load the allocation record, check its free regions and look for more that might be free
+    pub fn read_free_space(&mut self) -> Result<(), SbrxError> {
+        self.read_record()?;
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+
+        // known assets are never free, even if they look empty
+        let mut reserved: Vec<(u64, u64)> = asset_map().iter().map(|asset| (asset.offset, asset.length)).collect();
+        reserved.extend(self.allocations.values().cloned());
+
+        // the ROM can have changed since the region was recorded, like when a backup was restored
+        let recorded = mem::replace(&mut self.free, Vec::new());
+        for (offset, length) in recorded {
+            match rom.get(offset as usize..(offset + length) as usize) {
+                Some(bytes) if is_empty(bytes) => self.free.extend(subtract((offset, offset + length), &reserved[..])),
+                _ => println!("Warning: {:#X}-{:#X} is recorded as free but isn't empty, it won't be used", offset, offset + length),
+            }
+        }
+        self.free.retain(|&(_, length)| length >= 4);
+        self.free.sort();
+        reserved.extend(self.free.iter().cloned());
+
+        self.candidates.clear();
+        let mut start = 0;
+        while start < rom.len() {
+            let byte = rom[start];
+            let end = start + rom[start..].iter().take_while(|&&b| b == byte).count();
+            let minimum = match byte {
+                0xFF => MIN_FREE_FF,
+                0x00 => MIN_FREE_00,
+                _ => u64::max_value(),
+            };
+            if (end - start) as u64 >= minimum {
+                for region in subtract(((start as u64 + 3) & !3, end as u64), &reserved[..]) {
+                    self.candidates.push(region);
+                }
+            }
+            start = end;
+        }
+        self.candidates.retain(|&(_, length)| length >= 4);
+        println!(" * {} confirmed free regions, {:#X} bytes, {} more regions look empty",
+                 self.free.len(), self.total_free(), self.candidates.len());
+        Ok(())
+    }
+
+    /// Use the asset map worked out from the ROM, free regions that overlap it shrink
+    pub fn set_assets(&mut self, assets: Vec<AssetRegion>) {
+        let reserved: Vec<(u64, u64)> = assets.iter().map(|asset| (asset.offset, asset.length)).collect();
+        let outside = |regions: &[(u64, u64)]| -> Vec<(u64, u64)> {
+            regions.iter()
+                .flat_map(|&(offset, length)| subtract((offset, offset + length), &reserved[..]))
+                .filter(|&(_, length)| length >= 4)
+                .collect()
+        };
+        self.free = outside(&self.free);
+        self.candidates = outside(&self.candidates);
+        self.assets = assets;
+    }
+
+    pub fn total_free(&self) -> u64 {
+        self.free.iter().map(|&(_, length)| length).sum()
+    }
+
+    /// Mark a region that looks empty as free after it was checked, it has to be one of the
+    /// candidates. The record is saved straight away.
+    pub fn confirm(&mut self, offset: u64) -> Result<(u64, u64), SbrxError> {
+        let index = self.candidates.iter().position(|&(start, _)| start == offset)
+            .ok_or_else(|| SbrxError::UnknownAsset(format!("{:#X} isn't the start of a region that looks empty", offset)))?;
+        let region = self.candidates.remove(index);
+        self.free.push(region);
+        self.free.sort();
+        self.save_record()?;
+        Ok(region)
+    }
+
+    /// Record that a character's animation script table was checked, the record is saved straight
+    /// away
+    pub fn confirm_script_table(&mut self, character: &Character, offset: u64) -> Result<(), SbrxError> {
+        if self.record_path.is_none() {
+            return Err(SbrxError::Unsupported("the ROM has no path to record the table next to".to_string()));
+        }
+        self.script_tables.insert(character.name.to_string(), offset);
+        self.save_record()
+    }
+
+    /// Room an asset can use at `offset`, its allocation if it has one there or else its slot
+    pub fn extent(&self, name: &str, offset: u64, slot_length: u64) -> (u64, u64) {
+        match self.allocations.get(name) {
+            Some(&(allocated, length)) if allocated == offset => (offset, length),
+            _ => (offset, slot_length),
+        }
+    }
+
+    /// Work out where an asset goes if it doesn't fit its slot any more, None if it still fits.
+    /// The region is taken out of `free`, a copy of the free regions, so several moves can be
+    /// planned before any of them is applied. Every write the move makes is checked.
+    pub fn plan_move(&self, free: &mut Vec<(u64, u64)>, rom: &[u8], slot: &Slot, length: u64)
+                     -> Result<Option<Move>, SbrxError> {
+        let (name, asset, offset) = (slot.name, slot.asset, slot.offset);
+        let (_, capacity) = self.extent(name, offset, slot.length);
+        if length <= capacity {
+            return Ok(None);
+        }
+
+        let pointers: Vec<u64> = find_pointers(rom, offset as usize).into_iter().map(|at| at as u64).collect();
+        if pointers.is_empty() {
+            return Err(SbrxError::Unsupported(format!("nothing points to {} at {:#X}, it can't be moved", name, offset)));
+        }
+
+        let aligned = (length + 3) & !3;
+        let index = free.iter().position(|&(_, free)| free >= aligned)
+            .ok_or_else(|| self.no_room(name, aligned))?;
+        let (to, free_length) = free[index];
+        if free_length == aligned {
+            free.remove(index);
+        } else {
+            free[index] = (to + aligned, free_length - aligned);
+        }
+
+        let rom_length = rom.len() as u64;
+        check_write(asset, to, length, (to, aligned), rom_length, &self.assets)?;
+        for &at in pointers.iter() {
+            check_write(&format!("pointer to {}", name), at, 4, (at, 4), rom_length, &self.assets)?;
+        }
+        check_write(asset, offset, capacity, (offset, capacity), rom_length, &self.assets)?;
+        if offset_to_pointer(to as usize).is_none() {
+            return Err(SbrxError::BoundsViolation(format!("{:#X} can't be pointed to", to)));
+        }
+
+        Ok(Some(Move {
+            name: name.to_string(),
+            from: offset,
+            from_length: capacity,
+            from_allocation: self.allocations.get(name).map_or(false, |&(allocated, _)| allocated == offset),
+            to,
+            length: aligned,
+            pointers,
+        }))
+    }
+
+    /// Move an asset: write its data to the new region, point every pointer at it and fill the
+    /// old slot with 0xFF, in that order. Returns how many bytes were written.
+    pub fn apply_move(&mut self, planned: &Move, bytes: &[u8]) -> Result<usize, SbrxError> {
+        let mut written;
+        {
+            let mut file = self.file.lock().unwrap();
+            written = write_changes(&mut file, planned.to, bytes)?;
+
+            let pointer = offset_to_pointer(planned.to as usize)
+                .ok_or_else(|| SbrxError::BoundsViolation(format!("{:#X} can't be pointed to", planned.to)))?;
+            for &at in planned.pointers.iter() {
+                file.seek(SeekFrom::Start(at))?;
+                file.write_all(&[pointer as u8, (pointer >> 8) as u8, (pointer >> 16) as u8, (pointer >> 24) as u8])?;
+                written += 4;
+            }
+
+            file.seek(SeekFrom::Start(planned.from))?;
+            file.write_all(&vec![FREE_BYTE; planned.from_length as usize][..])?;
+        }
+        println!("Moved {} from {:#X} to {:#X}, updated {} pointers", planned.name, planned.from, planned.to, planned.pointers.len());
+
+        self.free = self.free.iter()
+            .flat_map(|&(offset, length)| subtract((offset, offset + length), &[(planned.to, planned.length)]))
+            .collect();
+        self.allocations.insert(planned.name.clone(), (planned.to, planned.length));
+        // an original slot stays part of its asset in the asset map, only allocations are reused
+        if planned.from_allocation {
+            self.give_back(planned.from, planned.from_length);
+        }
+        Ok(written)
+    }
+
+    /// Set an asset's allocation back to what it was, like when a mod is uninstalled. The current
+    /// one is given back, None leaves the asset without one.
+    pub fn set_allocation(&mut self, name: &str, allocation: Option<(u64, u64)>) {
+        if let Some((offset, length)) = self.allocations.remove(name) {
+            self.give_back(offset, length);
+        }
+        if let Some((offset, length)) = allocation {
+            self.free = self.free.iter()
+                .flat_map(|&(start, free)| subtract((start, start + free), &[(offset, length)]))
+                .collect();
+            self.allocations.insert(name.to_string(), (offset, length));
+        }
+    }
+
+    /// Put a region back with the free regions, merged with the ones around it
+    fn give_back(&mut self, offset: u64, length: u64) {
+        self.free.push((offset, length));
+        self.free.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for &(offset, length) in self.free.iter() {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 >= offset => last.1 = last.1.max(offset + length - last.0),
+                _ => merged.push((offset, length)),
+            }
+        }
+        self.free = merged;
+    }
+
+    /// Error for a move that doesn't fit, with a region that could be confirmed if there is one
+    fn no_room(&self, name: &str, length: u64) -> SbrxError {
+        let hint = match (self.candidates.iter().find(|&&(_, free)| free >= length), &self.record_path) {
+            (Some(&(offset, free)), &Some(_)) => format!(
+                ", {:#X} bytes at {:#X} look empty: check they aren't used and confirm them with sbrx free-space <rom> --confirm {:#X}",
+                free, offset, offset),
+            _ => String::new(),
+        };
+        SbrxError::BoundsViolation(format!("no confirmed free region of {:#X} bytes left for {}{}", length, name, hint))
+    }
+
+    fn read_record(&mut self) -> Result<(), SbrxError> {
+        self.free.clear();
+        self.allocations.clear();
+        self.layouts.clear();
+        self.script_tables.clear();
+        let path = match self.record_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let invalid = |line: &str| SbrxError::InvalidRom(format!("invalid line in {}: {}", path, line));
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "free" if parts.len() == 3 => {
+                    let offset = parse_number(parts[1]).ok_or_else(|| invalid(line))?;
+                    let length = parse_number(parts[2]).ok_or_else(|| invalid(line))?;
+                    self.free.push((offset, length));
+                }
+                "alloc" if parts.len() == 4 => {
+                    let offset = parse_number(parts[2]).ok_or_else(|| invalid(line))?;
+                    let length = parse_number(parts[3]).ok_or_else(|| invalid(line))?;
+                    self.allocations.insert(parts[1].to_string(), (offset, length));
+                }
+                "layout" if parts.len() >= 2 => {
+                    let mut layout = Vec::new();
+                    for entry in parts[2..].iter() {
+                        let mut split = entry.split(':');
+                        match (split.next().and_then(parse_number), split.next().and_then(parse_number)) {
+                            (Some(offset), Some(frames)) => layout.push((offset as i32, frames as i32)),
+                            _ => return Err(invalid(line)),
+                        }
+                    }
+                    self.layouts.insert(parts[1].to_string(), layout);
+                }
+                "scripts" if parts.len() == 3 => {
+                    let offset = parse_number(parts[2]).ok_or_else(|| invalid(line))?;
+                    self.script_tables.insert(parts[1].to_string(), offset);
+                }
+                _ => return Err(invalid(line)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the allocation record next to the ROM
+    pub fn save_record(&self) -> Result<(), SbrxError> {
+        let path = match self.record_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::from("# sbrx free space record, keep this next to the ROM\n");
+        for &(offset, length) in self.free.iter() {
+            contents.push_str(&format!("free {:#X} {:#X}\n", offset, length));
+        }
+        let mut names: Vec<&String> = self.allocations.keys().collect();
+        names.sort();
+        for name in names {
+            let (offset, length) = self.allocations[name];
+            contents.push_str(&format!("alloc {} {:#X} {:#X}\n", name, offset, length));
+        }
+        let mut characters: Vec<&String> = self.layouts.keys().collect();
+        characters.sort();
+        for character in characters {
+            let entries: Vec<String> = self.layouts[character].iter().map(|&(offset, frames)| format!("{:#X}:{}", offset, frames)).collect();
+            contents.push_str(&format!("layout {} {}\n", character, entries.join(" ")));
+        }
+        let mut characters: Vec<&String> = self.script_tables.keys().collect();
+        characters.sort();
+        for character in characters {
+            contents.push_str(&format!("scripts {} {:#X}\n", character, self.script_tables[character]));
+        }
+        Ok(fs::write(path, contents)?)
+    }
+}
+
+/// A region filled with a single byte that empty space is filled with
+fn is_empty(bytes: &[u8]) -> bool {
+    match bytes.first() {
+        Some(&first) => (first == FREE_BYTE || first == 0x00) && bytes.iter().all(|&byte| byte == first),
+        None => false,
+    }
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Parts of the region from `start` to `end` that don't overlap any of the reserved regions
+fn subtract(region: (u64, u64), reserved: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut parts = vec![region];
+    for &(offset, length) in reserved.iter() {
+        let (reserved_start, reserved_end) = (offset, offset + length);
+        parts = parts.into_iter().flat_map(|(start, end)| {
+            let mut pieces = Vec::new();
+            if reserved_end <= start || reserved_start >= end {
+                pieces.push((start, end));
+            } else {
+                if reserved_start > start {
+                    pieces.push((start, reserved_start));
+                }
+                if reserved_end < end {
+                    pieces.push(((reserved_end + 3) & !3, end));
+                }
+            }
+            pieces
+        }).collect();
+    }
+    parts.into_iter().filter(|&(start, end)| end > start).map(|(start, end)| (start, end - start)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+
+    const SLOT: u64 = 0x100;
+    const POINTER_AT: u64 = 0x10;
+    const FREE_START: u64 = 0x1000;
+
+    /// A small ROM with an asset at 0x100 that's pointed to from 0x10, and an empty region
+    fn rom_file(name: &str) -> (Arc<Mutex<File>>, String) {
+        let path = ::std::env::temp_dir().join(format!("sbrx-space-{}-{}.gba", name, ::std::process::id()));
+        let mut rom = vec![0x55u8; 0x2000];
+        for byte in rom[FREE_START as usize..FREE_START as usize + 0x200].iter_mut() {
+            *byte = FREE_BYTE;
+        }
+        rom[POINTER_AT as usize..POINTER_AT as usize + 4].copy_from_slice(&[0x00, 0x01, 0x00, 0x08]);
+        fs::write(&path, &rom).unwrap();
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        (Arc::new(Mutex::new(file)), path.to_string_lossy().into_owned())
+    }
+
+    fn contents(file: &Arc<Mutex<File>>) -> Vec<u8> {
+        let mut file = file.lock().unwrap();
+        let mut rom = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut rom).unwrap();
+        rom
+    }
+
+    fn cleanup(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.sbrx", path));
+    }
+
+    #[test]
+    fn empty_regions_are_only_candidates() {
+        let (file, path) = rom_file("candidates");
+        let mut space = SpaceManager::new(file.clone(), Some(format!("{}.sbrx", path)));
+        space.read_free_space().unwrap();
+        assert!(space.free.is_empty());
+        assert_eq!(space.candidates, vec![(FREE_START, 0x200)]);
+
+        let rom = contents(&file);
+        let error = space.plan_move(&mut space.free.clone(), &rom, &slot("test/0", SLOT, 0x40), 0x80).unwrap_err();
+        assert!(error.to_string().contains("--confirm 0x1000"), "{}", error);
+
+        space.confirm(FREE_START).unwrap();
+        let mut reread = SpaceManager::new(file.clone(), Some(format!("{}.sbrx", path)));
+        reread.read_free_space().unwrap();
+        assert_eq!(reread.free, vec![(FREE_START, 0x200)]);
+        cleanup(&path);
+    }
+
+    fn slot(name: &'static str, offset: u64, length: u64) -> Slot<'static> {
+        Slot { name, asset: "test", offset, length }
+    }
+
+    #[test]
+    fn moves_write_data_then_pointers_then_clear_the_slot() {
+        let (file, path) = rom_file("move");
+        let mut space = SpaceManager::new(file.clone(), None);
+        space.free = vec![(FREE_START, 0x200)];
+        let rom = contents(&file);
+
+        assert!(space.plan_move(&mut space.free.clone(), &rom, &slot("test/0", SLOT, 0x40), 0x40).unwrap().is_none());
+        let mut free = space.free.clone();
+        let planned = space.plan_move(&mut free, &rom, &slot("test/0", SLOT, 0x40), 0x7E).unwrap().unwrap();
+        assert_eq!((planned.to, planned.length), (FREE_START, 0x80));
+        assert_eq!(free, vec![(FREE_START + 0x80, 0x180)]);
+        // planning doesn't change anything
+        assert_eq!(contents(&file), rom);
+        assert_eq!(space.free, vec![(FREE_START, 0x200)]);
+
+        space.apply_move(&planned, &vec![0xAB; 0x7E][..]).unwrap();
+        let moved = contents(&file);
+        assert!(moved[FREE_START as usize..FREE_START as usize + 0x7E].iter().all(|&byte| byte == 0xAB));
+        assert_eq!(&moved[POINTER_AT as usize..POINTER_AT as usize + 4], &[0x00, 0x10, 0x00, 0x08]);
+        assert!(moved[SLOT as usize..SLOT as usize + 0x40].iter().all(|&byte| byte == FREE_BYTE));
+        assert_eq!(space.allocations.get("test/0"), Some(&(FREE_START, 0x80)));
+        assert_eq!(space.free, vec![(FREE_START + 0x80, 0x180)]);
+
+        // growing again gives the old allocation back
+        let moved_rom = contents(&file);
+        let planned = space.plan_move(&mut space.free.clone(), &moved_rom, &slot("test/0", FREE_START, 0x7E), 0x100).unwrap().unwrap();
+        assert!(planned.from_allocation);
+        space.apply_move(&planned, &vec![0xCD; 0x100][..]).unwrap();
+        assert_eq!(space.allocations.get("test/0"), Some(&(FREE_START + 0x80, 0x100)));
+        assert_eq!(space.free, vec![(FREE_START, 0x80), (FREE_START + 0x180, 0x80)]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn failed_moves_change_nothing() {
+        let (file, path) = rom_file("failed");
+        let mut space = SpaceManager::new(file.clone(), None);
+        space.free = vec![(FREE_START, 0x200)];
+        let rom = contents(&file);
+
+        // nothing points to 0x200
+        assert!(space.plan_move(&mut space.free.clone(), &rom, &slot("test/1", 0x200, 0x40), 0x80).is_err());
+        // too big for any free region
+        assert!(space.plan_move(&mut space.free.clone(), &rom, &slot("test/0", SLOT, 0x40), 0x400).is_err());
+        assert_eq!(contents(&file), rom);
+        assert!(space.allocations.is_empty());
+        assert_eq!(space.free, vec![(FREE_START, 0x200)]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn free_space_shrinks_around_assets_found_later() {
+        let (file, path) = rom_file("assets");
+        let mut space = SpaceManager::new(file.clone(), None);
+        space.free = vec![(FREE_START, 0x200)];
+        space.set_assets(vec![AssetRegion { name: "test sprites".to_string(), offset: FREE_START + 0x100, length: 0x40 }]);
+        assert_eq!(space.free, vec![(FREE_START, 0x100), (FREE_START + 0x140, 0xC0)]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn subtract_keeps_the_parts_outside() {
+        assert_eq!(subtract((0, 0x100), &[(0x40, 0x40)]), vec![(0, 0x40), (0x80, 0x80)]);
+        assert_eq!(subtract((0, 0x100), &[(0, 0x100)]), vec![]);
+        assert_eq!(subtract((0x10, 0x20), &[(0x40, 0x40)]), vec![(0x10, 0x10)]);
+    }
+}
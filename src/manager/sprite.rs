@@ -1,73 +1,178 @@
 extern crate image;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::fs::File;
-use std::io::{SeekFrom, Seek, Read, Error, ErrorKind, Write};
+use std::fs::{File, create_dir_all};
+use std::io::{SeekFrom, Seek, Read};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::sync::{Arc, Mutex};
-use self::image::{ImageBuffer, GenericImage, Rgb};
+use self::image::{ImageBuffer, GenericImage, Rgb, open};
 use self::image::gif::*;
 
 use ::data::*;
 use ::color::*;
 use ::engine::*;
+use ::error::*;
 use ::manager::*;
+use ::manager::asset::{AssetManager, full_name};
+use ::label;
+use ::rom::*;
 
 // colors used for the background in spritesheets
 const PURPLE_1: Color = Color { r: 255, g: 0, b: 250 };
 const PURPLE_2: Color = Color { r: 185, g: 0, b: 255 };
 const PURPLE_3: Color = Color { r: 185, g: 0, b: 185 }; // no frame
 
-/// normal character sprites are 6x6 sections
-pub const FRAME_SIZE: usize = 6;
-
 /// each section is 8x8 pixels
 pub const SECTION_SIZE: usize = 8;
 
+/// 4bpp sections take 32 bytes
+pub const SECTION_BYTE_COUNT: usize = SECTION_SIZE * SECTION_SIZE / 2;
+
+/// stop following an animation pointer table after this many entries
+const MAX_ANIMATIONS: usize = 128;
+
+/// the last animation of a table is longer than this only if its end was guessed wrong
+const MAX_FRAMES: usize = 64;
+
+/// height of the animation name strip below exported spritesheets
+pub const LABEL_HEIGHT: usize = 12;
+
+/// The shapes an OAM object can have
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ObjShape {
+    Square,
+    Wide,
+    Tall,
+}
+
+/// A single OAM object, sprites are drawn out of one or more of these
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Obj {
+    pub shape: ObjShape,
+    /// OAM size 0-3, together with the shape it picks one of the 12 object sizes
+    pub size: usize,
+    /// position in the frame in sections
+    pub x: usize,
+    pub y: usize,
+}
+
+impl Obj {
+    /// (width, height) in sections
+    pub fn dimensions(&self) -> (usize, usize) {
+        const SQUARE: [(usize, usize); 4] = [(1, 1), (2, 2), (4, 4), (8, 8)];
+        const WIDE: [(usize, usize); 4] = [(2, 1), (4, 1), (4, 2), (8, 4)];
+        const TALL: [(usize, usize); 4] = [(1, 2), (1, 4), (2, 4), (4, 8)];
+        match self.shape {
+            ObjShape::Square => SQUARE[self.size & 3],
+            ObjShape::Wide => WIDE[self.size & 3],
+            ObjShape::Tall => TALL[self.size & 3],
+        }
+    }
+}
+
+/// How a frame is put together from OAM objects. The objects' sections are stored one after
+/// another in the ROM, each object row by row.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameShape {
+    pub objs: &'static [Obj],
+}
+
+impl FrameShape {
+    /// width in sections
+    pub fn width(&self) -> usize {
+        self.objs.iter().map(|obj| obj.x + obj.dimensions().0).max().unwrap_or(0)
+    }
+
+    /// height in sections
+    pub fn height(&self) -> usize {
+        self.objs.iter().map(|obj| obj.y + obj.dimensions().1).max().unwrap_or(0)
+    }
+
+    pub fn pixel_width(&self) -> usize {
+        self.width() * SECTION_SIZE
+    }
+
+    pub fn pixel_height(&self) -> usize {
+        self.height() * SECTION_SIZE
+    }
+
+    /// number of bytes a frame takes up in the ROM
+    pub fn byte_count(&self) -> usize {
+        self.section_order().len() * SECTION_BYTE_COUNT
+    }
+
+    /// index in the frame (row by row) of every section, in the order they're stored in the ROM
+    pub fn section_order(&self) -> Vec<usize> {
+        let width = self.width();
+        let mut order = Vec::new();
+        for obj in self.objs.iter() {
+            let (obj_width, obj_height) = obj.dimensions();
+            for y in 0..obj_height {
+                for x in 0..obj_width {
+                    order.push((obj.y + y) * width + obj.x + x);
+                }
+            }
+        }
+        order
+    }
+}
+
+#[derive(Clone)]
 pub struct Spritesheet {
+    pub shape: FrameShape,
     pub animations: Vec<Animation>
 }
 
 impl Spritesheet {
-    pub fn new() -> Spritesheet {
-        Spritesheet { animations: Vec::new() }
+    pub fn new(shape: FrameShape) -> Spritesheet {
+        Spritesheet { shape, animations: Vec::new() }
+    }
+
+    /// change every pixel's palette index, `mapping[old] = new`
+    pub fn remap_indices(&mut self, mapping: &[u8]) {
+        for frame in self.animations.iter_mut().flat_map(|animation| animation.frames.iter_mut()) {
+            for section in frame.sections.iter_mut() {
+                for pixel in section.bytes.iter_mut().flat_map(|row| row.iter_mut()) {
+                    *pixel = mapping.get(*pixel as usize).cloned().unwrap_or(*pixel);
+                }
+            }
+        }
     }
 
     /// convert a spritesheet to an image
     pub fn to_img(&self, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
         let max_frames = self.animations.iter().map(|animation| animation.frames.len()).max().unwrap();
         let animation_length = self.animations.len();
+        let (frame_width, frame_height) = (self.shape.pixel_width(), self.shape.pixel_height());
 
-        let image_width = SECTION_SIZE * FRAME_SIZE * animation_length as usize;
-        let image_height = SECTION_SIZE * FRAME_SIZE * max_frames as usize;
+        let image_width = frame_width * animation_length as usize;
+        let image_height = frame_height * max_frames as usize;
 
         let purple_rgb = Rgb { data: [PURPLE_3.r as u8, PURPLE_3.g as u8, PURPLE_3.b as u8] };
         let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel(image_width as u32, image_height as u32, purple_rgb);
 
         for (animation_index, animation) in self.animations.iter().enumerate() {
             for (frame_index, frame) in animation.frames.iter().enumerate() {
-                for (section_index, section) in frame.sections.iter().enumerate() {
-                    for (y, row) in section.bytes.iter().enumerate() {
-                        for (x, v) in row.iter().enumerate() {
-                            let c: Color;
-                            let b = *v as usize;
-
-                            let c = if b == 0 {
-                                if (animation_index % 2 == 0) == (frame_index % 2 == 0) {
-                                    PURPLE_1
-                                } else {
-                                    PURPLE_2
-                                }
+                for y in 0..frame.height() {
+                    for x in 0..frame.width() {
+                        let b = frame.get_pixel(x, y) as usize;
+
+                        let c = if b == 0 {
+                            if (animation_index % 2 == 0) == (frame_index % 2 == 0) {
+                                PURPLE_1
                             } else {
-                                palette[b]
-                            };
+                                PURPLE_2
+                            }
+                        } else {
+                            palette[b]
+                        };
 
-                            let ix = x + (section_index % FRAME_SIZE) * SECTION_SIZE + (SECTION_SIZE * FRAME_SIZE * animation_index);
-                            let iy = y + (section_index / FRAME_SIZE) * SECTION_SIZE + FRAME_SIZE * SECTION_SIZE * frame_index;
+                        let ix = x + frame_width * animation_index;
+                        let iy = y + frame_height * frame_index;
 
-                            image.get_pixel_mut(ix as u32, iy as u32).data = [c.r as u8, c.g as u8, c.b as u8];
-                        }
+                        image.get_pixel_mut(ix as u32, iy as u32).data = [c.r as u8, c.g as u8, c.b as u8];
                     }
                 }
             }
@@ -75,64 +180,136 @@ impl Spritesheet {
         image
     }
 
-    /// convert an image to a spritesheet
-    pub fn from_img(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, character: &Character) -> Result<(Spritesheet, Vec<Color>), Error> {
-        let mut spritesheet = Spritesheet::new();
-        let mut palette = vec![Color { r: 0, g: 248, b: 248 }];
+    /// convert a spritesheet to an image with the animation names below each column
+    pub fn to_labelled_img(&self, palette: &[Color], character: &Character) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let sheet = self.to_img(palette);
+        let column_width = self.shape.pixel_width() as u32;
 
-        for (animation_index, frames) in character.sprite_frames.iter().enumerate() {
-            let mut animation = Animation::new();
-            spritesheet.animations.push(animation);
-            for frame_index in 0..*frames {
-                let mut frame = Frame::new();
-                spritesheet.animations[animation_index].frames.push(frame);
-                for sy in 0..FRAME_SIZE {
-                    for sx in 0..FRAME_SIZE {
-                        for y in 0..SECTION_SIZE {
-                            for x in 0..SECTION_SIZE {
-                                let ix = sx * SECTION_SIZE + x + (SECTION_SIZE * FRAME_SIZE * animation_index as usize);
-                                let iy = sy * SECTION_SIZE + y + (SECTION_SIZE * FRAME_SIZE * frame_index as usize);
-                                let section_index = sx + sy * FRAME_SIZE;
-
-                                // convert to our color struct
-                                let rgb = image.get_pixel_mut(ix as u32, iy as u32).data;
-                                let color = Color { r: rgb[0] as i32, g: rgb[1] as i32, b: rgb[2] as i32 };
-
-                                let mut color_index;
-
-                                if color == PURPLE_1 || color == PURPLE_2 || color == PURPLE_3 {
-                                    color_index = 0;
-                                } else if palette.contains(&color) {
-                                    color_index = palette.iter().position(|&c| c == color).unwrap();
-                                } else if palette.len() < 16 {
-                                    palette.push(color);
-                                    color_index = palette.len() - 1;
-                                } else {
-                                    println!("Found invaild color at ({}, {})", ix, iy);
-//                                    return Err(Error::new(ErrorKind::InvalidData, format!("Invalid color found at {}, {}", ix, iy)));
-                                    color_index = 0;
-                                }
-
-                                spritesheet
-                                    .animations[animation_index]
-                                    .frames[frame_index as usize]
-                                    .sections[section_index]
-                                    .bytes[y][x] = color_index as u8;
-                            }
-                        }
-                    }
+        // the labels go below the frames so the image can still be read with from_img
+        let purple_rgb = Rgb { data: [PURPLE_3.r as u8, PURPLE_3.g as u8, PURPLE_3.b as u8] };
+        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_pixel(sheet.width(), sheet.height() + LABEL_HEIGHT as u32, purple_rgb);
+        image.copy_from(&sheet, 0, 0);
+
+        let white = Color { r: 255, g: 255, b: 255 };
+        for animation_index in 0..self.animations.len() {
+            let x = column_width * animation_index as u32;
+            label::draw_text(&mut image, &character.animation_label(animation_index), x + 1, sheet.height(),
+                             (LABEL_HEIGHT - 1) as f32, column_width - 2, white);
+        }
+        image
+    }
+
+    /// convert an image to a spritesheet with the given number of animations. Each column holds
+    /// frames until the first cell that's completely filled with the "no frame" color, so frames
+    /// can be added by drawing them below the last one.
+    pub fn from_img(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, shape: FrameShape, animation_count: usize) -> Result<(Spritesheet, Vec<Color>), SbrxError> {
+        let mut palette = vec![Color { r: 0, g: 248, b: 248 }];
+        let mut overflow = Vec::new();
+
+        let spritesheet = Spritesheet::from_img_with(image, shape, animation_count, |_, (ix, iy), color| {
+            if palette.contains(&color) {
+                Ok(palette.iter().position(|&c| c == color).unwrap() as u8)
+            } else if palette.len() < 16 {
+                palette.push(color);
+                Ok((palette.len() - 1) as u8)
+            } else {
+                if !overflow.contains(&color) {
+                    println!("Found a 17th color at ({}, {})", ix, iy);
+                    overflow.push(color);
                 }
+                Ok(0)
             }
-        }
+        })?;
 
+        if !overflow.is_empty() {
+            return Err(SbrxError::PaletteOverflow { colors: palette.len() + overflow.len(), max: 16 });
+        }
         while palette.len() < 16 {
             palette.push(Color { r: 0, g: 0, b: 0 });
         }
 
         Ok((spritesheet, palette))
     }
+
+    /// convert an image to a spritesheet drawn with an existing palette. A pixel keeps the index
+    /// it has in `previous` when that index still has its color, so palettes that hold the same
+    /// color twice come back unchanged.
+    pub fn from_img_with_palette(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, shape: FrameShape, animation_count: usize,
+                                 palette: &[Color], previous: Option<&Spritesheet>) -> Result<Spritesheet, SbrxError> {
+        Spritesheet::from_img_with(image, shape, animation_count, |(animation, frame, x, y), (ix, iy), color| {
+            let old_index = previous
+                .and_then(|spritesheet| spritesheet.animations.get(animation))
+                .and_then(|old| old.frames.get(frame))
+                .map(|old| old.get_pixel(x, y) as usize);
+            if let Some(index) = old_index {
+                if index != 0 && palette.get(index) == Some(&color) {
+                    return Ok(index as u8);
+                }
+            }
+            match palette.iter().skip(1).position(|&c| c == color) {
+                Some(index) => Ok(index as u8 + 1),
+                None => Err(SbrxError::ImageFormat(format!(
+                    "the color at ({}, {}) isn't in the palette", ix, iy))),
+            }
+        })
+    }
+
+    /// split an image into frames, `color_index` turns the color of every pixel that isn't
+    /// background into a palette index. It's given (animation, frame, x, y) and the pixel's
+    /// position in the image.
+    fn from_img_with<F>(image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, shape: FrameShape, animation_count: usize, mut color_index: F) -> Result<Spritesheet, SbrxError>
+        where F: FnMut((usize, usize, usize, usize), (usize, usize), Color) -> Result<u8, SbrxError> {
+        let mut spritesheet = Spritesheet::new(shape);
+        let (frame_width, frame_height) = (shape.pixel_width(), shape.pixel_height());
+
+        if (image.width() as usize) < frame_width * animation_count {
+            return Err(SbrxError::ImageFormat(format!(
+                "the image has room for {} animations but {} are needed", image.width() as usize / frame_width, animation_count)));
+        }
+        let rows = image.height() as usize / frame_height;
+        let no_frame = [PURPLE_3.r as u8, PURPLE_3.g as u8, PURPLE_3.b as u8];
+
+        for animation_index in 0..animation_count {
+            let frames = (0..rows).take_while(|&frame_index| {
+                (0..frame_height).any(|y| (0..frame_width).any(|x| {
+                    image.get_pixel((x + frame_width * animation_index) as u32, (y + frame_height * frame_index) as u32).data != no_frame
+                }))
+            }).count();
+            if frames == 0 {
+                return Err(SbrxError::ImageFormat(format!("animation {} has no frames", animation_index)));
+            }
+
+            let mut animation = Animation::new();
+            for frame_index in 0..frames {
+                let mut frame = Frame::new(&shape);
+                for y in 0..frame_height {
+                    for x in 0..frame_width {
+                        let ix = x + frame_width * animation_index;
+                        let iy = y + frame_height * frame_index;
+
+                        // convert to our color struct
+                        let rgb = image.get_pixel_mut(ix as u32, iy as u32).data;
+                        let color = Color { r: rgb[0] as i32, g: rgb[1] as i32, b: rgb[2] as i32 };
+
+                        let index = if color == PURPLE_1 || color == PURPLE_2 || color == PURPLE_3 {
+                            0
+                        } else {
+                            color_index((animation_index, frame_index, x, y), (ix, iy), color)?
+                        };
+
+                        frame.set_pixel(x, y, index);
+                    }
+                }
+                animation.frames.push(frame);
+            }
+            spritesheet.animations.push(animation);
+        }
+
+        Ok(spritesheet)
+    }
 }
 
+#[derive(Clone)]
 pub struct Animation {
     pub frames: Vec<Frame>
 }
@@ -145,34 +322,108 @@ impl Animation {
     pub fn get_frames(&self, palette: &[Color]) -> Vec<ImageBuffer<Rgb<u8>, Vec<u8>>> {
         self.frames.iter().map(|frame| frame.to_image(palette)).collect()
     }
+
+    /// convert an animation to an image with the frames stacked vertically
+    pub fn to_img(&self, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let (frame_width, frame_height) = self.frames.first().map_or((0, 0), |frame| (frame.width() as u32, frame.height() as u32));
+        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(frame_width, frame_height * self.frames.len() as u32);
+        for (frame_index, frame) in self.get_frames(palette).iter().enumerate() {
+            image.copy_from(frame, 0, frame_height * frame_index as u32);
+        }
+        image
+    }
 }
 
-#[derive(Copy, Clone)]
+/// A frame of sections, stored row by row
+#[derive(Clone)]
 pub struct Frame {
-    pub sections: [Section; FRAME_SIZE * FRAME_SIZE]
+    /// size in sections
+    columns: usize,
+    rows: usize,
+    pub sections: Vec<Section>
 }
 
 impl Frame {
-    pub fn new() -> Frame {
-        Frame { sections: [Section::new(); FRAME_SIZE * FRAME_SIZE] }
+    pub fn new(shape: &FrameShape) -> Frame {
+        Frame {
+            columns: shape.width(),
+            rows: shape.height(),
+            sections: vec![Section::new(); shape.width() * shape.height()],
+        }
     }
 
-    pub fn to_image(&self, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(
-            (FRAME_SIZE * SECTION_SIZE) as u32,
-            (FRAME_SIZE * SECTION_SIZE) as u32,
-        );
+    /// width in pixels
+    pub fn width(&self) -> usize {
+        self.columns * SECTION_SIZE
+    }
 
-        for (section_index, section) in self.sections.iter().enumerate() {
-            for (y, row) in section.bytes.iter().enumerate() {
-                for (x, v) in row.iter().enumerate() {
-                    let c = palette[*v as usize];
+    /// height in pixels
+    pub fn height(&self) -> usize {
+        self.rows * SECTION_SIZE
+    }
 
-                    let ix = x + (section_index % FRAME_SIZE) * SECTION_SIZE;
-                    let iy = y + (section_index / FRAME_SIZE) * SECTION_SIZE;
+    /// palette index of a pixel
+    pub fn get_pixel(&self, x: usize, y: usize) -> u8 {
+        let section = (y / SECTION_SIZE) * self.columns + x / SECTION_SIZE;
+        self.sections[section].bytes[y % SECTION_SIZE][x % SECTION_SIZE]
+    }
 
-                    image.get_pixel_mut(ix as u32, iy as u32).data = [c.r as u8, c.g as u8, c.b as u8];
-                }
+    /// set the palette index of a pixel, pixels outside of the frame are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, index: u8) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let section = (y / SECTION_SIZE) * self.columns + x / SECTION_SIZE;
+        self.sections[section].bytes[y % SECTION_SIZE][x % SECTION_SIZE] = index;
+    }
+
+    /// move every pixel, pixels moved out of the frame are lost and the ones left behind are
+    /// transparent
+    pub fn shift(&mut self, dx: i32, dy: i32) {
+        let original = self.clone();
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let (from_x, from_y) = (x as i32 - dx, y as i32 - dy);
+                let index = if from_x >= 0 && from_y >= 0 && (from_x as usize) < self.width() && (from_y as usize) < self.height() {
+                    original.get_pixel(from_x as usize, from_y as usize)
+                } else {
+                    0
+                };
+                self.set_pixel(x, y, index);
+            }
+        }
+    }
+
+    /// read a frame from 4bpp data, `bytes` has to be `shape.byte_count()` long
+    pub fn decode(shape: &FrameShape, bytes: &[u8]) -> Frame {
+        let mut frame = Frame::new(shape);
+        for (section_bytes, &section_index) in bytes.chunks(SECTION_BYTE_COUNT).zip(shape.section_order().iter()) {
+            let section = &mut frame.sections[section_index];
+            for (i, byte) in section_bytes.iter().enumerate() {
+                // the left pixel is in the low nibble
+                let (x, y) = ((i * 2) % SECTION_SIZE, (i * 2) / SECTION_SIZE);
+                section.bytes[y][x] = byte & 0x0F;
+                section.bytes[y][x + 1] = (byte & 0xF0) >> 4;
+            }
+        }
+        frame
+    }
+
+    /// convert a frame back to 4bpp data with the sections in ROM order
+    pub fn encode(&self, shape: &FrameShape) -> Vec<u8> {
+        let pixels = shape.section_order().iter()
+            .flat_map(|&section_index| self.sections[section_index].bytes.iter().flat_map(|row| row.iter().map(|&b| b)))
+            .collect::<Vec<_>>();
+        ByteFolder::new(pixels.into_iter()).collect()
+    }
+
+    pub fn to_image(&self, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(self.width() as u32, self.height() as u32);
+
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let c = palette[self.get_pixel(x, y) as usize];
+                image.get_pixel_mut(x as u32, y as u32).data = [c.r as u8, c.g as u8, c.b as u8];
             }
         }
         image
@@ -195,108 +446,128 @@ impl Section {
 pub struct SpriteManager {
     file: Arc<Mutex<File>>,
     pub spritesheets: HashMap<String, Spritesheet>,
+    /// (offset, frame count) of every animation, found in the ROM's animation tables
+    pub layouts: HashMap<String, Vec<(i32, i32)>>,
+    /// (animation, frame) of every frame that changed since it was read or written, by character
+    pub dirty: HashMap<String, HashSet<(usize, usize)>>,
 }
 
 impl SpriteManager {
     pub fn new(file: Arc<Mutex<File>>) -> SpriteManager {
         SpriteManager {
             file: file.clone(),
-            spritesheets: HashMap::new()
+            spritesheets: HashMap::new(),
+            layouts: HashMap::new(),
+            dirty: HashMap::new(),
         }
     }
 
-    pub fn read_sprites(&mut self) -> Result<(), Error> {
+    /// Read every character's sprites, characters whose animations were moved use the layout
+    /// recorded by the space manager
+    pub fn read_sprites(&mut self, space_manager: &space::SpaceManager) -> Result<(), SbrxError> {
+        let rom = read_rom(&mut self.file.lock().unwrap())?;
+        let mut tables = find_animation_tables(&rom);
         for character in CHARACTERS.iter() {
+            match space_manager.layouts.get(character.name) {
+                Some(layout) => {
+                    println!(" * {} animations were moved, using the recorded layout", character.name);
+                    self.layouts.insert(character.name.to_string(), layout.clone());
+                }
+                None => self.use_found_layout(character, tables.remove(character.name)),
+            }
             self.read_sprite(character)?;
         }
         Ok(())
     }
 
-    pub fn read_sprite(&mut self, character: &Character) -> Result<(), Error> {
+    /// Use the layout found in the character's table of animation pointers, after checking it
+    /// against the frame counts in data.rs
+    fn use_found_layout(&mut self, character: &Character, found: Option<Vec<(i32, i32)>>) {
+        match found {
+            Some(layout) => {
+                check_layout(character, &layout[..]);
+                self.layouts.insert(character.name.to_string(), layout);
+            }
+            None => {
+                println!("Warning: {} animation pointers not found, using the frame counts in data.rs", character.name);
+                self.layouts.remove(character.name);
+            }
+        }
+    }
+
+    /// (offset, frame count) of every animation of a character
+    pub fn layout(&self, character: &Character) -> Vec<(i32, i32)> {
+        match self.layouts.get(character.name) {
+            Some(layout) => layout.clone(),
+            None => compute_sprite_offsets(character),
+        }
+    }
+
+    /// Bytes from the character's sprite offset to the end of the last animation that's still
+    /// there, animations that were moved to free space don't count
+    pub fn extent(&self, character: &Character, space_manager: &space::SpaceManager) -> u64 {
+        let frame_byte_count = character.frame_shape.byte_count() as u64;
+        self.layout(character).iter().enumerate()
+            .filter(|&(index, _)| !space_manager.allocations.contains_key(&format!("{}/{}", character.name, index)))
+            .map(|(_, &(offset, frames))| offset as u64 + frames as u64 * frame_byte_count)
+            .max()
+            .map_or(0, |end| end.saturating_sub(character.sprite_offset as u64))
+    }
+
+    /// Frames in each animation, edited spritesheets can have more frames than the ROM
+    pub fn frame_counts(&self, character: &Character) -> Vec<usize> {
+        match self.spritesheets.get(character.name) {
+            Some(spritesheet) => spritesheet.animations.iter().map(|animation| animation.frames.len()).collect(),
+            None => self.layout(character).iter().map(|&(_, frames)| frames as usize).collect(),
+        }
+    }
+
+    pub fn read_sprite(&mut self, character: &Character) -> Result<(), SbrxError> {
         let spritesheet = self.read_spritesheet_from_rom(character)?;
         self.spritesheets.insert(character.name.to_string(), spritesheet);
+        self.dirty.remove(character.name);
         Ok(())
     }
 
-    pub fn read_spritesheet_from_rom(&mut self, character: &Character) -> Result<Spritesheet, Error> {
+    /// Remember that a frame was edited
+    pub fn mark_dirty(&mut self, character: &Character, animation: usize, frame: usize) {
+        self.dirty.entry(character.name.to_string()).or_insert_with(HashSet::new).insert((animation, frame));
+    }
+
+    /// Remember that every frame of a character may have changed
+    pub fn mark_all_dirty(&mut self, character: &Character) {
+        let frames: Vec<(usize, usize)> = self.frame_counts(character).iter().enumerate()
+            .flat_map(|(animation, &count)| (0..count).map(move |frame| (animation, frame)))
+            .collect();
+        self.dirty.entry(character.name.to_string()).or_insert_with(HashSet::new).extend(frames);
+    }
+
+    pub fn read_spritesheet_from_rom(&mut self, character: &Character) -> Result<Spritesheet, SbrxError> {
         let start = Instant::now();
-        let sprite_data = compute_sprite_offsets(character);
+        let sprite_data = self.layout(character);
 
         // used for the image
         let max_frames = sprite_data.iter().max_by_key(|p| { p.1 }).map_or(0, |p| { p.1 });
 
-        let mut spritesheet = Spritesheet::new();
+        let shape = character.frame_shape;
+        let frame_byte_count = shape.byte_count();
+        let mut spritesheet = Spritesheet::new(shape);
 
         // go through every animation
-        for (animation_index, animation_data) in sprite_data.iter().enumerate() {
+        for animation_data in sprite_data.iter() {
             let offset = animation_data.0;
             let frame_count = animation_data.1;
 
             let mut animation = Animation::new();
 
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(offset as u64))?;
+            let mut buffer = vec![0; frame_byte_count * frame_count as usize];
+            file.read_exact(&mut buffer[..])?;
+
             // go through every frame in the animation
-            for current_frame in 0..frame_count {
-                // read sections from ROM
-                let mut frame = Frame::new();
-                {
-                    /*
-                    sort sections
-                    00 01 02 03 24 25
-                    04 05 06 07 26 27
-                    08 09 10 11 28 29
-                    12 13 14 15 30 31
-                    16 17 18 19 32 33
-                    20 21 22 23 34 35
-                    */
-                    const SECTION_MAPPING: [usize; 36] = [
-                        00, 01, 02, 03,
-                        06, 07, 08, 09,
-                        12, 13, 14, 15,
-                        18, 19, 20, 21,
-                        24, 25, 26, 27,
-                        30, 31, 32, 33,
-                        04, 05,
-                        10, 11,
-                        16, 17,
-                        22, 23,
-                        28, 29,
-                        34, 35,
-                    ];
-
-                    let mut current_section = 0;
-                    let mut x = 1;
-                    let mut y = 1;
-
-                    const FRAME_BYTE_COUNT: usize = FRAME_SIZE * FRAME_SIZE * 32;
-                    let frame_offset = offset + FRAME_BYTE_COUNT as i32 * current_frame;
-
-                    self.file.lock().unwrap().seek(SeekFrom::Start(frame_offset as u64))?;
-
-                    let mut buffer = [0; FRAME_BYTE_COUNT];
-                    self.file.lock().unwrap().read(&mut buffer[..])?;
-
-                    for i in 0..FRAME_BYTE_COUNT {
-                        let a = buffer[i] & 0x0F;
-                        let b = (buffer[i] & 0xF0) >> 4;
-
-                        frame.sections[SECTION_MAPPING[current_section]].bytes[y - 1][x - 1] = a;
-                        frame.sections[SECTION_MAPPING[current_section]].bytes[y - 1][x] = b;
-
-                        // check bounds
-                        x += 1;
-                        if x % 8 == 0 {
-                            x -= 8;
-                            if y != 0 && y % 8 == 0 {
-                                x = 0;
-                                y = 0;
-                                current_section += 1;
-                            }
-                            y += 1;
-                        }
-                        x += 1;
-                    }
-                }
-                animation.frames.push(frame);
+            for frame_bytes in buffer.chunks(frame_byte_count) {
+                animation.frames.push(Frame::decode(&shape, frame_bytes));
             }
             spritesheet.animations.push(animation);
         }
@@ -304,72 +575,244 @@ impl SpriteManager {
         Ok(spritesheet)
     }
 
-    pub fn store_image(&mut self, palette_manager: &mut palette::PaletteManager, image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, character: &Character) -> Result<(), Error> {
-        let (spritesheet, palette) = Spritesheet::from_img(image, character)?;
+    pub fn store_image(&mut self, palette_manager: &mut palette::PaletteManager, image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, character: &Character) -> Result<(), SbrxError> {
+        self.store_image_with_palette(palette_manager, image, character, character.name.to_string())
+    }
+
+    /// Store an image's sprites and put its colors in one of the character's palettes
+    pub fn store_image_with_palette(&mut self, palette_manager: &mut palette::PaletteManager, image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>,
+                                    character: &Character, palette_name: String) -> Result<(), SbrxError> {
+        let (spritesheet, palette) = Spritesheet::from_img(image, character.frame_shape, self.layout(character).len())?;
         self.spritesheets.insert(character.name.to_string(), spritesheet);
-        palette_manager.store_palette_colors(character.name.to_string(), palette);
+        self.mark_all_dirty(character);
+        palette_manager.store_palette_colors(palette_name, palette);
         Ok(())
     }
 
-    pub fn write_spritesheets(&mut self) -> Result<(), Error> {
-        for character in CHARACTERS.iter() {
-            self.write_spritesheet(character)?;
-        }
+    /// Store an image's sprites drawn with the given palette, the stored palette isn't changed
+    pub fn store_image_with_colors(&mut self, image: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, character: &Character, palette: &[Color]) -> Result<(), SbrxError> {
+        let spritesheet = Spritesheet::from_img_with_palette(image, character.frame_shape, self.layout(character).len(),
+                                                             palette, self.spritesheets.get(character.name))?;
+        self.spritesheets.insert(character.name.to_string(), spritesheet);
+        self.mark_all_dirty(character);
         Ok(())
     }
 
-    pub fn write_spritesheet(&mut self, character: &Character) -> Result<(), Error> {
-        let spritesheet_o = self.spritesheets.get(&character.name.to_string());
-        if let Some(spritesheet) = spritesheet_o {
-            let mut bytes: Vec<u8> = Vec::new();
-
-            for animation in spritesheet.animations.iter() {
-                for frame in animation.frames.iter() {
-                    // resort the sections back to the GBA format
-                    let mut sorted_sections = [Section::new(); FRAME_SIZE * FRAME_SIZE];
+    pub fn write_spritesheets(&mut self, space_manager: &mut space::SpaceManager) -> Result<usize, SbrxError> {
+        let mut written = 0;
+        for character in CHARACTERS.iter() {
+            written += self.write_spritesheet(character, space_manager)?;
+        }
+        Ok(written)
+    }
 
-                    const SECTION_MAPPING: [usize; 36] = [
-                        00, 01, 02, 03, 24, 25,
-                        04, 05, 06, 07, 26, 27,
-                        08, 09, 10, 11, 28, 29,
-                        12, 13, 14, 15, 30, 31,
-                        16, 17, 18, 19, 32, 33,
-                        20, 21, 22, 23, 34, 35,
-                    ];
+    /// Write a character's sprites back, only the bytes that are different are written. Animations
+    /// that gained frames and don't fit their slot any more are moved to free space. Every write is
+    /// checked before anything is changed. Returns how many bytes were written.
+    pub fn write_spritesheet(&mut self, character: &Character, space_manager: &mut space::SpaceManager) -> Result<usize, SbrxError> {
+        let mut written = 0;
+        let layout = self.layout(character);
+        let mut new_layout = layout.clone();
+        if let Some(spritesheet) = self.spritesheets.get(&character.name.to_string()) {
+            let rom = read_rom(&mut self.file.lock().unwrap())?;
+            let asset = full_name(character.name, "sprites");
+            let frame_byte_count = spritesheet.shape.byte_count() as u64;
+
+            // animations aren't always stored back to back, so each one goes to its own offset
+            let mut free = space_manager.free.clone();
+            let mut writes = Vec::new();
+            for (index, (animation, &(offset, frames))) in spritesheet.animations.iter().zip(layout.iter()).enumerate() {
+                let bytes = animation.frames.iter()
+                    .flat_map(|frame| frame.encode(&spritesheet.shape))
+                    .collect::<Vec<_>>();
+
+                let name = format!("{}/{}", character.name, index);
+                let slot_length = frames as u64 * frame_byte_count;
+                let slot = space::Slot { name: &name, asset: &asset, offset: offset as u64, length: slot_length };
+                let moved = space_manager.plan_move(&mut free, &rom, &slot, bytes.len() as u64)?;
+                if moved.is_none() {
+                    let extent = space_manager.extent(&name, offset as u64, slot_length);
+                    check_write(&asset, offset as u64, bytes.len() as u64, extent, rom.len() as u64, &space_manager.assets)?;
+                }
+                writes.push((index, offset as u64, bytes, moved));
+            }
 
-                    for (section_index, section) in frame.sections.iter().enumerate() {
-                        sorted_sections[SECTION_MAPPING[section_index]] = *section;
+            for (index, offset, bytes, moved) in writes {
+                let offset = match moved {
+                    Some(planned) => {
+                        written += space_manager.apply_move(&planned, &bytes[..])?;
+                        planned.to
                     }
-
-                    bytes.extend(sorted_sections.iter().flat_map(
-                        |section| section.bytes.iter().flat_map(
-                            |row| row.iter().map(|&b| b)
-                        )
-                    ));
-                }
+                    None => {
+                        written += write_changes(&mut self.file.lock().unwrap(), offset, &bytes[..])?;
+                        offset
+                    }
+                };
+                new_layout[index] = (offset as i32, (bytes.len() as u64 / frame_byte_count) as i32);
             }
+        }
+        self.dirty.remove(character.name);
 
-            self.file.lock().unwrap().seek(SeekFrom::Start(character.sprite_offset as u64))?;
-            let mut byte_folder = ByteFolder::new(bytes.into_iter());
-            self.file.lock().unwrap().write(byte_folder.collect::<Vec<_>>().as_slice())?;
+        if new_layout != layout {
+            self.layouts.insert(character.name.to_string(), new_layout.clone());
+            space_manager.layouts.insert(character.name.to_string(), new_layout);
+            space_manager.save_record()?;
         }
-        Ok(())
+        Ok(written)
     }
 
-    pub fn save_spritesheet(&self, palette_manager: &mut palette::PaletteManager, character: &Character) -> Result<(), Error> {
-        let spritesheet_o = self.spritesheets.get(&character.name.to_string());
-        if let Some(spritesheet) = spritesheet_o {
-            let palette = palette_manager.load_palette_colors(character.name.to_string());
-            spritesheet.to_img(&palette[..]).save(format!("roms/sprites/{}.png", character.name))?;
-        }
-        Ok(())
+    /// Save a character's labelled spritesheet as `<directory>/<character>.png`, the directory is
+    /// created if it's missing
+    pub fn save_spritesheet(&self, palette_manager: &mut palette::PaletteManager, character: &Character, directory: &Path) -> Result<PathBuf, SbrxError> {
+        let spritesheet = self.load_spritesheet(character)?;
+        let palette = palette_manager.load_palette_colors(character.name.to_string());
+        create_dir_all(directory)?;
+        let path = directory.join(format!("{}.png", character.name));
+        spritesheet.to_labelled_img(&palette[..], character).save(&path)?;
+        Ok(path)
     }
 
-    pub fn load_spritesheet(&self, character: &Character) -> Result<&Spritesheet, Error> {
+    pub fn load_spritesheet(&self, character: &Character) -> Result<&Spritesheet, SbrxError> {
         let result = self.spritesheets.get(&character.name.to_string());
         match result {
             Some(spritesheet) => return Ok(spritesheet),
-            None => return Err(Error::new(ErrorKind::InvalidData, format!("invalid character {}", &character.name.to_string())))
+            None => return Err(SbrxError::UnknownCharacter(character.name.to_string()))
+        }
+    }
+
+    pub fn load_spritesheet_mut(&mut self, character: &Character) -> Result<&mut Spritesheet, SbrxError> {
+        match self.spritesheets.get_mut(&character.name.to_string()) {
+            Some(spritesheet) => Ok(spritesheet),
+            None => Err(SbrxError::UnknownCharacter(character.name.to_string()))
+        }
+    }
+}
+
+/// Character spritesheets as assets, named after the character
+impl AssetManager for SpriteManager {
+    fn kind(&self) -> &'static str {
+        "sprites"
+    }
+
+    fn assets(&self, _engine: &Engine) -> Vec<String> {
+        CHARACTERS.iter().map(|character| character.name.to_string()).collect()
+    }
+
+    fn read(&mut self, engine: &Engine) -> Result<(), SbrxError> {
+        self.read_sprites(&engine.space_manager)
+    }
+
+    fn load(&mut self, _engine: &mut Engine, asset: &str) -> Result<(), SbrxError> {
+        self.read_sprite(&character_asset(asset)?)
+    }
+
+    fn is_dirty(&self, _engine: &Engine, asset: &str) -> bool {
+        self.dirty.contains_key(asset)
+    }
+
+    fn write(&mut self, engine: &mut Engine, asset: &str) -> Result<usize, SbrxError> {
+        self.write_spritesheet(&character_asset(asset)?, &mut engine.space_manager)
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("png")
+    }
+
+    fn export(&self, engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let character = character_asset(asset)?;
+        let palette = engine.registry.get_mut::<palette::PaletteManager>()?.load_palette_colors(character.name.to_string());
+        Ok(self.load_spritesheet(&character)?.to_labelled_img(&palette[..], &character).save(path)?)
+    }
+
+    /// The spritesheet's palette comes from the image too
+    fn import(&mut self, engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let character = character_asset(asset)?;
+        let mut image = open(path).map_err(|e| SbrxError::ImageFormat(format!("{}: {}", path.display(), e)))?.to_rgb();
+        self.store_image(&mut *engine.registry.get_mut::<palette::PaletteManager>()?, &mut image, &character)
+    }
+
+    fn describe_changes(&self, _engine: &Engine, asset: &str) -> String {
+        format!("{} ({} frames)", full_name(asset, self.kind()), self.dirty.get(asset).map_or(0, |frames| frames.len()))
+    }
+}
+
+/// The character an asset is named after
+pub fn character_asset(asset: &str) -> Result<Character, SbrxError> {
+    find_character(asset).ok_or_else(|| SbrxError::UnknownCharacter(asset.to_string()))
+}
+
+/// Follow every character's animation pointer table, a run of pointers that starts at the
+/// character's sprite base and increases by whole frames of the character's shape. The ROM is gone
+/// through once for all of them.
+fn find_animation_tables(rom: &[u8]) -> HashMap<&'static str, Vec<(i32, i32)>> {
+    let bases: HashMap<u32, &Character> = CHARACTERS.iter()
+        .filter_map(|character| offset_to_pointer(character.sprite_offset as usize).map(|base| (base, character)))
+        .collect();
+
+    let mut best: HashMap<&'static str, Vec<u32>> = HashMap::new();
+    for table in (0..rom.len() / 4).map(|i| i * 4) {
+        let (base, character) = match read_u32(rom, table).and_then(|value| bases.get(&value).map(|&character| (value, character))) {
+            Some(found) => found,
+            None => continue,
+        };
+        let frame_byte_count = character.frame_shape.byte_count();
+        let mut pointers = vec![base];
+        while pointers.len() < MAX_ANIMATIONS {
+            let previous = *pointers.last().unwrap();
+            match read_u32(rom, table + pointers.len() * 4) {
+                Some(pointer) if pointer > previous
+                    && (pointer - previous) as usize % frame_byte_count == 0
+                    && pointer_to_offset(pointer, rom.len()).is_some() => pointers.push(pointer),
+                _ => break,
+            }
+        }
+        if pointers.len() > best.get(character.name).map_or(1, |best| best.len()) {
+            best.insert(character.name, pointers);
+        }
+    }
+
+    best.into_iter().filter_map(|(name, pointers)| {
+        let character = CHARACTERS.iter().find(|character| character.name == name)?;
+        Some((name, table_layout(character, &pointers[..])))
+    }).collect()
+}
+
+/// (offset, frame count) of every animation in a pointer table. The table only says where each
+/// animation starts, the last one runs up to the next known asset. If that isn't a whole number of
+/// frames its length comes from data.rs.
+fn table_layout(character: &Character, pointers: &[u32]) -> Vec<(i32, i32)> {
+    let frame_byte_count = character.frame_shape.byte_count();
+    let mut layout: Vec<(i32, i32)> = pointers.windows(2)
+        .map(|pair| ((pair[0] - ROM_BASE) as i32, ((pair[1] - pair[0]) as usize / frame_byte_count) as i32))
+        .collect();
+
+    let last = (pointers[layout.len()] - ROM_BASE) as u64;
+    let sprites = format!("{} sprites", character.name);
+    let end = asset_map().into_iter()
+        .filter(|asset| asset.name != sprites && asset.offset > last)
+        .map(|asset| asset.offset)
+        .min();
+    let frames = match end.map(|end| (end - last) as usize) {
+        Some(length) if length % frame_byte_count == 0 && length / frame_byte_count <= MAX_FRAMES => Some(length / frame_byte_count),
+        _ => None,
+    };
+    match frames.or_else(|| character.sprite_frames.get(layout.len()).map(|&frames| frames as usize)) {
+        Some(frames) => layout.push((last as i32, frames as i32)),
+        None => println!("Warning: the length of {}'s last animation is unknown, it's left out", character.name),
+    }
+    layout
+}
+
+/// Warn about every difference between the layout found in the ROM and the hardcoded one
+fn check_layout(character: &Character, layout: &[(i32, i32)]) {
+    let expected = compute_sprite_offsets(character);
+    if layout.len() != expected.len() {
+        println!("Warning: {} has {} animations in the ROM but {} in data.rs", character.name, layout.len(), expected.len());
+    }
+    for (index, (found, expected)) in layout.iter().zip(expected.iter()).enumerate() {
+        if found != expected {
+            println!("Warning: {} animation {} is {} frames at {:#X} in the ROM but {} frames at {:#X} in data.rs",
+                     character.name, index, found.1, found.0, expected.1, expected.0);
         }
     }
 }
@@ -394,3 +837,58 @@ impl<I: Iterator<Item=u8>> Iterator for ByteFolder<I> {
         Some(first | (second << 4))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32x32 with a 16x32 strip to its right and a 32x16 block below, like a small effect
+    const OTHER_SHAPE: FrameShape = FrameShape {
+        objs: &[
+            Obj { shape: ObjShape::Square, size: 2, x: 0, y: 0 },
+            Obj { shape: ObjShape::Tall, size: 2, x: 4, y: 0 },
+            Obj { shape: ObjShape::Wide, size: 2, x: 0, y: 4 },
+        ],
+    };
+
+    #[test]
+    fn shapes_are_measured_from_their_objects() {
+        assert_eq!((CHARACTER_SHAPE.pixel_width(), CHARACTER_SHAPE.pixel_height()), (48, 48));
+        assert_eq!(CHARACTER_SHAPE.byte_count(), 0x480);
+        assert_eq!((OTHER_SHAPE.width(), OTHER_SHAPE.height()), (6, 6));
+        assert_eq!(OTHER_SHAPE.byte_count(), (16 + 8 + 8) * SECTION_BYTE_COUNT);
+        // the 16x32 strip is two sections wide and stored after the square, a row at a time
+        assert_eq!(&OTHER_SHAPE.section_order()[16..24], &[4, 5, 10, 11, 16, 17, 22, 23]);
+    }
+
+    #[test]
+    fn animation_tables_run_up_to_the_next_asset() {
+        let mut rom = vec![0; 0x800000];
+        let (sonic, knuckles) = (CHARACTERS[0], CHARACTERS[1]);
+        let frame = 0x480;
+        let base = sonic.sprite_offset as usize;
+        let last = knuckles.palette_offset as usize - 4 * frame;
+        for (i, &offset) in [base, base + 8 * frame, last].iter().enumerate() {
+            let pointer = offset_to_pointer(offset).unwrap();
+            rom[0x100000 + i * 4..0x100000 + i * 4 + 4].copy_from_slice(&[pointer as u8, (pointer >> 8) as u8, (pointer >> 16) as u8, (pointer >> 24) as u8]);
+        }
+
+        let tables = find_animation_tables(&rom);
+        assert_eq!(tables[sonic.name], vec![
+            (base as i32, 8),
+            ((base + 8 * frame) as i32, ((last - base) / frame - 8) as i32),
+            (last as i32, 4),
+        ]);
+        assert!(!tables.contains_key(knuckles.name));
+    }
+
+    #[test]
+    fn frames_of_any_shape_round_trip() {
+        for shape in [CHARACTER_SHAPE, OTHER_SHAPE].iter() {
+            let bytes: Vec<u8> = (0..shape.byte_count()).map(|i| (i * 7 + i / 13) as u8).collect();
+            let frame = Frame::decode(shape, &bytes);
+            assert_eq!((frame.width(), frame.height()), (shape.pixel_width(), shape.pixel_height()));
+            assert_eq!(frame.encode(shape), bytes);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fs;
+use std::path::Path;
+
+use ::data::*;
+use ::engine::*;
+use ::error::*;
+use ::manager::asset::AssetManager;
+use ::manager::sprite::character_asset;
+
+/*
+ * Character text
+ *
+ * There's no text encoding yet, so text is kept as the raw bytes of each character's block. It's
+ * read and written straight from the ROM, nothing is kept in between.
+ */
+
+/// Character text blocks as assets, named after the character
+pub struct TextAssets;
+
+impl AssetManager for TextAssets {
+    fn kind(&self) -> &'static str {
+        "text"
+    }
+
+    fn assets(&self, _engine: &Engine) -> Vec<String> {
+        CHARACTERS.iter().filter(|character| character.text_offsets.0 >= 0).map(|character| character.name.to_string()).collect()
+    }
+
+    fn load(&mut self, _engine: &mut Engine, _asset: &str) -> Result<(), SbrxError> {
+        Ok(())
+    }
+
+    fn is_dirty(&self, _engine: &Engine, _asset: &str) -> bool {
+        false
+    }
+
+    fn write(&mut self, _engine: &mut Engine, _asset: &str) -> Result<usize, SbrxError> {
+        Ok(0)
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        Some("bin")
+    }
+
+    fn export(&self, engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let text = engine.read_text(&character_asset(asset)?)?
+            .ok_or_else(|| SbrxError::Unsupported(format!("{} has no text", asset)))?;
+        Ok(fs::write(path, text)?)
+    }
+
+    /// Text is written to the ROM straight away
+    fn import(&mut self, engine: &mut Engine, asset: &str, path: &Path) -> Result<(), SbrxError> {
+        let character = character_asset(asset)?;
+        let bytes = fs::read(path)?;
+        engine.before_write()?;
+        engine.write_text(&character, &bytes[..])?;
+        Ok(())
+    }
+}
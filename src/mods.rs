@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use ::engine::*;
+use ::manager::space::SpaceManager;
+use ::project::{asset_files, apply_assets};
+use ::rom::*;
+
+/*
+ * Mod packages
+ *
+ * A mod is a directory with a manifest, `sbrx.mod`, and assets laid out like a project:
+ *
+ *     name = Dark Sonic
+ *     version = 1.0
+ *     author = someone
+ *
+ * Installed mods are recorded next to the ROM in `<rom>.mods`, one line per mod with the assets it
+ * changed:
+ *
+ *     mod <name> <asset>, <asset>, ...
+ *
+ * Installing a mod keeps what it replaced in `<rom>.mods.d/<name>/` so uninstalling it can put the
+ * ROM back exactly: `rom.patch` has every run of bytes it changed, as the offset and length (both
+ * u32, little endian) followed by the bytes from before and the bytes it wrote, and `space` has the
+ * allocations and layouts the free space record had before, `-` if there was none:
+ *
+ *     alloc <name> <offset> <length>
+ *     layout <character> <offset>:<frames> ...
+ *
+ * An install that fails part of the way puts the ROM and the free space record back as they were.
+ */
+
+pub const MOD_MANIFEST: &'static str = "sbrx.mod";
+
+pub struct ModPackage {
+    pub directory: PathBuf,
+    pub name: String,
+    pub version: String,
+    pub author: String,
+}
+
+impl ModPackage {
+    pub fn load(directory: &Path) -> Result<ModPackage, Error> {
+        let manifest = directory.join(MOD_MANIFEST);
+        let contents = fs::read_to_string(&manifest)?;
+
+        let invalid = |line: &str| Error::new(ErrorKind::InvalidData, format!("invalid line in {}: {}", manifest.display(), line));
+        let mut package = ModPackage {
+            directory: directory.to_path_buf(),
+            name: String::new(),
+            version: String::new(),
+            author: String::new(),
+        };
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim().to_string()),
+                _ => return Err(invalid(line)),
+            };
+            match key {
+                "name" => package.name = value,
+                "version" => package.version = value,
+                "author" => package.author = value,
+                _ => return Err(invalid(line)),
+            }
+        }
+        if package.name.is_empty() || package.name.contains(',') {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} needs a name without commas", manifest.display())));
+        }
+        Ok(package)
+    }
+
+    /// Assets the mod changes, like "Sonic sprites"
+    pub fn assets(&self) -> Vec<String> {
+        asset_files(&self.directory)
+    }
+}
+
+/// A mod that's installed and what it changed
+#[derive(Clone, Debug)]
+pub struct InstalledMod {
+    pub name: String,
+    pub assets: Vec<String>,
+}
+
+/// The mods installed in a ROM, oldest first
+pub struct ModRecord {
+    path: String,
+    pub mods: Vec<InstalledMod>,
+}
+
+impl ModRecord {
+    pub fn load(rom_path: &str) -> Result<ModRecord, Error> {
+        let path = format!("{}.mods", rom_path);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        let mut mods = Vec::new();
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            if !line.starts_with("mod ") {
+                return Err(Error::new(ErrorKind::InvalidData, format!("invalid line in {}: {}", path, line)));
+            }
+            // names can have spaces, the assets start after the first comma-separated name
+            let mut parts = line[4..].split(',').map(|part| part.trim().to_string());
+            let name = parts.next().unwrap_or_default();
+            mods.push(InstalledMod { name, assets: parts.filter(|asset| !asset.is_empty()).collect() });
+        }
+        Ok(ModRecord { path, mods })
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let mut contents = String::from("# mods installed by sbrx, keep this next to the ROM\n");
+        for installed in self.mods.iter() {
+            contents.push_str(&format!("mod {}, {}\n", installed.name, installed.assets.join(", ")));
+        }
+        fs::write(&self.path, contents)
+    }
+
+    /// (mod, asset) of every installed mod that changes an asset the package changes too
+    pub fn conflicts(&self, package: &ModPackage) -> Vec<(String, String)> {
+        let assets = package.assets();
+        let mut conflicts = Vec::new();
+        for installed in self.mods.iter().filter(|installed| installed.name != package.name) {
+            for asset in installed.assets.iter().filter(|asset| assets.contains(asset)) {
+                conflicts.push((installed.name.clone(), asset.clone()));
+            }
+        }
+        conflicts
+    }
+
+    /// Where the assets a mod replaced are kept, names that only differ in characters that can't
+    /// be in a file name share it
+    fn saved_directory(&self, name: &str) -> PathBuf {
+        let safe: String = name.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect();
+        PathBuf::from(format!("{}.d", self.path)).join(safe)
+    }
+}
+
+/// Install a mod into the ROM the engine has open. Mods that change the same assets as one that's
+/// already installed are refused unless `force` is set.
+pub fn install(engine: &mut Engine, package: &ModPackage, force: bool) -> Result<(), Error> {
+    let rom_path = rom_path(engine)?;
+    let mut record = ModRecord::load(&rom_path)?;
+    if record.mods.iter().any(|installed| installed.name == package.name) {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} is already installed", package.name)));
+    }
+    let saved = record.saved_directory(&package.name);
+    if let Some(installed) = record.mods.iter().find(|installed| record.saved_directory(&installed.name) == saved) {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("{} is too close to the name of {}, which is installed", package.name, installed.name)));
+    }
+    let conflicts = record.conflicts(package);
+    if !conflicts.is_empty() && !force {
+        let list: Vec<String> = conflicts.iter().map(|(name, asset)| format!("{} ({})", asset, name)).collect();
+        return Err(Error::other(format!("{} changes assets other mods changed: {}", package.name, list.join(", "))));
+    }
+
+    let assets = package.assets();
+    if assets.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} doesn't change anything", package.name)));
+    }
+
+    // keep what's there now so the mod can be uninstalled, and so a failed install can be undone
+    let before = Snapshot::take(engine)?;
+    engine.before_write()?;
+    let installed = apply_mod(engine, package, &before, &saved).and_then(|_| {
+        record.mods.push(InstalledMod { name: package.name.clone(), assets });
+        record.save()
+    });
+    if let Err(error) = installed {
+        let _ = fs::remove_dir_all(&saved);
+        return match before.restore(engine) {
+            Ok(()) => Err(error),
+            Err(restore_error) => Err(Error::new(error.kind(), format!("{}, and the ROM couldn't be put back: {}", error, restore_error))),
+        };
+    }
+    println!("Installed {} {}", package.name, package.version);
+    Ok(())
+}
+
+/// The ROM and its free space record from before an install
+struct Snapshot {
+    rom: Vec<u8>,
+    free: Vec<(u64, u64)>,
+    allocations: HashMap<String, (u64, u64)>,
+    layouts: HashMap<String, Vec<(i32, i32)>>,
+}
+
+impl Snapshot {
+    fn take(engine: &Engine) -> Result<Snapshot, Error> {
+        Ok(Snapshot {
+            rom: read_rom(&mut engine.file.lock().unwrap())?,
+            free: engine.space_manager.free.clone(),
+            allocations: engine.space_manager.allocations.clone(),
+            layouts: engine.space_manager.layouts.clone(),
+        })
+    }
+
+    /// Put the ROM and the record back and read everything again
+    fn restore(&self, engine: &mut Engine) -> Result<(), Error> {
+        {
+            let mut file = engine.file.lock().unwrap();
+            write_changes(&mut file, 0, &self.rom[..])?;
+            file.set_len(self.rom.len() as u64)?;
+        }
+        engine.space_manager.free = self.free.clone();
+        engine.space_manager.allocations = self.allocations.clone();
+        engine.space_manager.layouts = self.layouts.clone();
+        engine.space_manager.save_record()?;
+        Ok(engine.start()?)
+    }
+}
+
+/// Write a mod's assets and keep what they replaced in `saved`
+fn apply_mod(engine: &mut Engine, package: &ModPackage, before: &Snapshot, saved: &Path) -> Result<(), Error> {
+    apply_assets(engine, &package.directory)?;
+    engine.save_all()?;
+
+    let after = read_rom(&mut engine.file.lock().unwrap())?;
+    fs::create_dir_all(saved)?;
+    fs::write(saved.join("rom.patch"), encode_patch(&before.rom[..], &after[..]))?;
+    fs::write(saved.join("space"), format_space(&before.allocations, &before.layouts, &engine.space_manager))
+}
+
+/// Put back what a mod replaced. Mods installed after it that change the same assets have to be
+/// uninstalled first.
+pub fn uninstall(engine: &mut Engine, name: &str) -> Result<(), Error> {
+    let rom_path = rom_path(engine)?;
+    let mut record = ModRecord::load(&rom_path)?;
+    let index = record.mods.iter().position(|installed| installed.name == name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} isn't installed", name)))?;
+
+    let installed = record.mods[index].clone();
+    for later in record.mods[index + 1..].iter() {
+        if let Some(asset) = later.assets.iter().find(|asset| installed.assets.contains(asset)) {
+            return Err(Error::other(format!("{} also changes {}, uninstall it first", later.name, asset)));
+        }
+    }
+
+    let saved = record.saved_directory(name);
+    let patch = decode_patch(&fs::read(saved.join("rom.patch"))?)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} is damaged", saved.join("rom.patch").display())))?;
+    let space = fs::read_to_string(saved.join("space"))?;
+
+    // anything else that wrote over the mod would be lost
+    let rom = read_rom(&mut engine.file.lock().unwrap())?;
+    for &(offset, _, ref installed) in patch.iter() {
+        if rom.get(offset as usize..offset as usize + installed.len()) != Some(&installed[..]) {
+            return Err(Error::other(format!("{:#X} changed since {} was installed", offset, name)));
+        }
+    }
+
+    engine.before_write()?;
+    for &(offset, ref original, _) in patch.iter() {
+        write_changes(&mut engine.file.lock().unwrap(), offset, &original[..])?;
+    }
+    restore_space(&mut engine.space_manager, &space)?;
+    engine.space_manager.save_record()?;
+    engine.start()?;
+    fs::remove_dir_all(&saved)?;
+
+    record.mods.remove(index);
+    record.save()?;
+    println!("Uninstalled {}", name);
+    Ok(())
+}
+
+fn rom_path(engine: &Engine) -> Result<String, Error> {
+    engine.path.clone().ok_or_else(|| Error::other("the ROM has no path to record mods next to"))
+}
+
+/// Every run of bytes that's different in `after` as (offset, length, bytes before, bytes after)
+fn encode_patch(before: &[u8], after: &[u8]) -> Vec<u8> {
+    let mut patch = Vec::new();
+    for (start, end) in changed_ranges(before, after) {
+        for &number in [start as u32, (end - start) as u32].iter() {
+            patch.extend_from_slice(&[number as u8, (number >> 8) as u8, (number >> 16) as u8, (number >> 24) as u8]);
+        }
+        patch.extend_from_slice(&before[start..end]);
+        patch.extend_from_slice(&after[start..end]);
+    }
+    patch
+}
+
+/// (offset, bytes before, bytes after) of a run of changed bytes
+type PatchRun = (u64, Vec<u8>, Vec<u8>);
+
+/// Every run in a patch, None if it's cut short
+fn decode_patch(patch: &[u8]) -> Option<Vec<PatchRun>> {
+    let number = |at: usize| patch.get(at..at + 4).map(|b| (b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24) as usize);
+    let mut runs = Vec::new();
+    let mut at = 0;
+    while at < patch.len() {
+        let (offset, length) = (number(at)?, number(at + 4)?);
+        let original = patch.get(at + 8..at + 8 + length)?.to_vec();
+        let installed = patch.get(at + 8 + length..at + 8 + length * 2)?.to_vec();
+        runs.push((offset as u64, original, installed));
+        at += 8 + length * 2;
+    }
+    Some(runs)
+}
+
+/// The allocations and layouts from before an install that the install changed
+fn format_space(allocations: &HashMap<String, (u64, u64)>, layouts: &HashMap<String, Vec<(i32, i32)>>, space_manager: &SpaceManager) -> String {
+    let mut contents = String::new();
+    let mut names: Vec<&String> = allocations.keys().chain(space_manager.allocations.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names.into_iter().filter(|&name| allocations.get(name) != space_manager.allocations.get(name)) {
+        match allocations.get(name) {
+            Some(&(offset, length)) => contents.push_str(&format!("alloc {} {:X} {:X}\n", name, offset, length)),
+            None => contents.push_str(&format!("alloc {} -\n", name)),
+        }
+    }
+    let mut characters: Vec<&String> = layouts.keys().chain(space_manager.layouts.keys()).collect();
+    characters.sort();
+    characters.dedup();
+    for character in characters.into_iter().filter(|&character| layouts.get(character) != space_manager.layouts.get(character)) {
+        match layouts.get(character) {
+            Some(layout) => {
+                let entries: Vec<String> = layout.iter().map(|&(offset, frames)| format!("{:X}:{}", offset, frames)).collect();
+                contents.push_str(&format!("layout {} {}\n", character, entries.join(" ")));
+            }
+            None => contents.push_str(&format!("layout {} -\n", character)),
+        }
+    }
+    contents
+}
+
+/// Put back the allocations and layouts saved by `format_space`
+fn restore_space(space_manager: &mut SpaceManager, contents: &str) -> Result<(), Error> {
+    let invalid = |line: &str| Error::new(ErrorKind::InvalidData, format!("invalid saved space record: {}", line));
+    let hex = |text: &str| u64::from_str_radix(text, 16).ok();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match (parts[0], parts.len()) {
+            ("alloc", 3) if parts[2] == "-" => space_manager.set_allocation(parts[1], None),
+            ("alloc", 4) => {
+                let allocation = match (hex(parts[2]), hex(parts[3])) {
+                    (Some(offset), Some(length)) => (offset, length),
+                    _ => return Err(invalid(line)),
+                };
+                space_manager.set_allocation(parts[1], Some(allocation));
+            }
+            ("layout", 3) if parts[2] == "-" => {
+                space_manager.layouts.remove(parts[1]);
+            }
+            ("layout", _) if parts.len() >= 3 => {
+                let mut layout = Vec::new();
+                for entry in parts[2..].iter() {
+                    let mut split = entry.split(':');
+                    match (split.next().and_then(hex), split.next().and_then(|frames| frames.parse().ok())) {
+                        (Some(offset), Some(frames)) => layout.push((offset as i32, frames)),
+                        _ => return Err(invalid(line)),
+                    }
+                }
+                space_manager.layouts.insert(parts[1].to_string(), layout);
+            }
+            _ => return Err(invalid(line)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+    use ::data::*;
+    use ::manager::palette::PaletteManager;
+    use ::project::format_palette;
+    use ::rom::tests::{noise_rom, TempRom};
+    use ::settings::Settings;
+
+    #[test]
+    fn patches_round_trip() {
+        let before = [1, 2, 3, 4, 5, 6];
+        let after = [1, 9, 9, 4, 5, 7];
+        let patch = decode_patch(&encode_patch(&before, &after)).unwrap();
+        assert_eq!(patch, vec![(1, vec![2, 3], vec![9, 9]), (5, vec![6], vec![7])]);
+        assert!(decode_patch(&encode_patch(&before, &after)[..10]).is_none());
+    }
+
+    #[test]
+    fn uninstalling_puts_back_the_rom() {
+        let rom = noise_rom();
+        let temp = TempRom::new("mods", &rom);
+        let rom_path = &temp.path;
+        let package_directory = temp.directory.join("blue");
+        fs::create_dir_all(package_directory.join("palettes")).unwrap();
+        fs::write(package_directory.join(MOD_MANIFEST), "name = Blue\n").unwrap();
+        fs::write(package_directory.join("palettes").join(format!("{}.pal", CHARACTERS[0].name)), format_palette(&[0x7C00; 16])).unwrap();
+
+        let settings = Settings { backup_keep: 0, ..Settings::default() };
+        let mut engine = Engine::open_with_settings(rom_path, settings).unwrap();
+        let package = ModPackage::load(&package_directory).unwrap();
+        install(&mut engine, &package, false).unwrap();
+        let installed = fs::read(rom_path).unwrap();
+        let palette = CHARACTERS[0].palette_offset as usize;
+        assert_eq!(&installed[palette..palette + 2], &[0x00, 0x7C]);
+
+        uninstall(&mut engine, "Blue").unwrap();
+        assert!(fs::read(rom_path).unwrap() == rom);
+        assert!(ModRecord::load(rom_path).unwrap().mods.is_empty());
+    }
+
+    #[test]
+    fn a_failed_install_changes_nothing() {
+        let rom = noise_rom();
+        let temp = TempRom::new("mods-failed", &rom);
+        let rom_path = &temp.path;
+        let package_directory = temp.directory.join("blue");
+        fs::create_dir_all(package_directory.join("palettes")).unwrap();
+        fs::write(package_directory.join(MOD_MANIFEST), "name = Blue\n").unwrap();
+        fs::write(package_directory.join("palettes").join(format!("{}.pal", CHARACTERS[0].name)), format_palette(&[0x7C00; 16])).unwrap();
+        // what the mod replaced can't be kept, which only fails after the palette was written
+        fs::write(format!("{}.mods.d", rom_path), "").unwrap();
+
+        let settings = Settings { backup_keep: 0, ..Settings::default() };
+        let mut engine = Engine::open_with_settings(rom_path, settings).unwrap();
+        let package = ModPackage::load(&package_directory).unwrap();
+        assert!(install(&mut engine, &package, false).is_err());
+        assert!(fs::read(rom_path).unwrap() == rom);
+        assert!(ModRecord::load(rom_path).unwrap().mods.is_empty());
+        assert!(!engine.is_dirty());
+        let palette = engine.registry.get::<PaletteManager>().unwrap().load_palette_i32(CHARACTERS[0].name.to_string());
+        assert!(palette.iter().all(|&color| color != 0x7C00));
+    }
+
+    #[test]
+    fn names_kept_in_the_same_place_collide() {
+        let temp = TempRom::new("mods-names", &[0; 0x100]);
+        let package_directory = temp.directory.join("a_b");
+        fs::create_dir_all(package_directory.join("palettes")).unwrap();
+        fs::write(package_directory.join(MOD_MANIFEST), "name = a_b\n").unwrap();
+        fs::write(package_directory.join("palettes").join(format!("{}.pal", CHARACTERS[0].name)), format_palette(&[0x7C00; 16])).unwrap();
+        fs::write(format!("{}.mods", temp.path), "mod a b, Shadow palette\n").unwrap();
+
+        let record = ModRecord::load(&temp.path).unwrap();
+        assert_eq!(record.saved_directory("a b"), record.saved_directory("a_b"));
+        assert!(record.saved_directory("a-b") != record.saved_directory("a_b"));
+
+        let file = OpenOptions::new().read(true).write(true).open(&temp.path).unwrap();
+        let mut engine = Engine::new(Arc::new(Mutex::new(file)));
+        engine.path = Some(temp.path.clone());
+        let error = install(&mut engine, &ModPackage::load(&package_directory).unwrap(), true).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Instant;
+
+use ::manager::animation::*;
+
+/// the GBA draws 60 frames per second
+pub const TICKS_PER_SECOND: f32 = 60.0;
+
+/// sprite frames are held for 4 ticks unless the game says otherwise
+pub const DEFAULT_FRAME_TICKS: u32 = 4;
+
+/// Plays back an animation script step by step
+pub struct AnimationPlayer {
+    pub animation_index: usize,
+    pub step_index: usize,
+    pub playing: bool,
+    pub looping: bool,
+    pub speed: f32,
+    ticks: f32,
+    last_update: Instant,
+}
+
+impl AnimationPlayer {
+    pub fn new(animation_index: usize, script: &AnimationScript) -> AnimationPlayer {
+        AnimationPlayer {
+            animation_index,
+            step_index: 0,
+            playing: false,
+            looping: script.loop_start.is_some(),
+            speed: 1.0,
+            ticks: 0.0,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// The script command that is currently shown
+    pub fn current<'a>(&self, script: &'a AnimationScript) -> Option<&'a ScriptFrame> {
+        script.frames.get(self.step_index)
+    }
+
+    pub fn toggle_playing(&mut self, script: &AnimationScript) {
+        // restart a finished animation instead of sitting on the last frame
+        if !self.playing && !self.looping && self.step_index + 1 >= script.frames.len() {
+            self.step_index = 0;
+        }
+        self.playing = !self.playing;
+        self.ticks = 0.0;
+        self.last_update = Instant::now();
+    }
+
+    /// Move to the next step, returns false if the animation ended
+    pub fn step(&mut self, script: &AnimationScript) -> bool {
+        if script.frames.is_empty() {
+            return false;
+        }
+        if self.step_index + 1 < script.frames.len() {
+            self.step_index += 1;
+            true
+        } else if self.looping {
+            self.step_index = script.loop_start.unwrap_or(0);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Advance the animation by the time passed since the last update, returns true if the frame
+    /// changed
+    pub fn update(&mut self, script: &AnimationScript) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update);
+        self.last_update = now;
+        if !self.playing {
+            return false;
+        }
+        if self.step_index >= script.frames.len() {
+            self.step_index = 0;
+        }
+
+        let seconds = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
+        self.ticks += seconds * TICKS_PER_SECOND * self.speed;
+
+        let mut changed = false;
+        loop {
+            let duration = match self.current(script) {
+                Some(frame) => (frame.duration as f32).max(1.0),
+                None => DEFAULT_FRAME_TICKS as f32,
+            };
+            if self.ticks < duration {
+                break;
+            }
+            self.ticks -= duration;
+            if self.step(script) {
+                changed = true;
+            } else {
+                self.playing = false;
+                self.ticks = 0.0;
+                break;
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::{self, create_dir_all};
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use image::open;
+
+use ::data::*;
+use ::engine::*;
+use ::rom::*;
+use ::color::*;
+use ::settings::Settings;
+use ::manager::palette::PaletteManager;
+use ::manager::sprite::SpriteManager;
+
+/*
+ * Projects
+ *
+ * A mod kept as files instead of a ROM. The manifest, `sbrx.project`, names the clean ROM the mod
+ * is built on and its checksum:
+ *
+ *     base = ../roms/sonic-battle.gba
+ *     crc32 = 0123ABCD
+ *     output = build/mod.gba
+ *
+ * Everything else is optional and found by name, paths are relative to the project directory:
+ *
+ *     sprites/<character>.png    spritesheet, the same as the editor exports
+ *     palettes/<character>.pal   16 GBA colors as hex, one per line, the spritesheet is drawn
+ *                                with these colors when both are there
+ *     text/<character>.bin       the character's text block, as raw bytes
+ */
+
+pub const MANIFEST_NAME: &'static str = "sbrx.project";
+
+pub struct Project {
+    pub directory: PathBuf,
+    /// the clean ROM
+    pub base: PathBuf,
+    pub crc32: u32,
+    /// where the built ROM goes
+    pub output: PathBuf,
+}
+
+impl Project {
+    /// Read a project's manifest
+    pub fn load(directory: &Path) -> Result<Project, Error> {
+        let manifest = directory.join(MANIFEST_NAME);
+        let contents = fs::read_to_string(&manifest)?;
+
+        let invalid = |line: &str| Error::new(ErrorKind::InvalidData, format!("invalid line in {}: {}", manifest.display(), line));
+        let (mut base, mut crc, mut output) = (None, None, None);
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(invalid(line)),
+            };
+            match key {
+                "base" => base = Some(directory.join(value)),
+                "crc32" => crc = Some(u32::from_str_radix(value, 16).map_err(|_| invalid(line))?),
+                "output" => output = Some(directory.join(value)),
+                _ => return Err(invalid(line)),
+            }
+        }
+
+        let missing = |key: &str| Error::new(ErrorKind::InvalidData, format!("{} has no {}", manifest.display(), key));
+        Ok(Project {
+            directory: directory.to_path_buf(),
+            base: base.ok_or_else(|| missing("base"))?,
+            crc32: crc.ok_or_else(|| missing("crc32"))?,
+            output: output.unwrap_or_else(|| directory.join("build").join("mod.gba")),
+        })
+    }
+
+    /// Start a project from a clean ROM, every character's sprites, palette and text are exported
+    /// so they can be edited
+    pub fn create(directory: &Path, base: &Path) -> Result<Project, Error> {
+        if directory.join(MANIFEST_NAME).exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already has a project", directory.display())));
+        }
+        let base_path = base.to_str().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the ROM path isn't valid unicode"))?;
+        let mut engine = Engine::open_read_only(base_path)?;
+        let rom = read_rom(&mut engine.file.lock().unwrap())?;
+
+        for character in CHARACTERS.iter() {
+            export_assets(&mut engine, directory, character)?;
+        }
+
+        // the base ROM is referenced relative to the project so the project can be moved along
+        // with it
+        let base_name = relative_path(&fs::canonicalize(directory)?, &fs::canonicalize(base)?);
+        let project = Project {
+            directory: directory.to_path_buf(),
+            base: directory.join(&base_name),
+            crc32: crc32(&rom[..]),
+            output: directory.join("build").join("mod.gba"),
+        };
+        fs::write(directory.join(MANIFEST_NAME), format!(
+            "# sbrx project\nbase = {}\ncrc32 = {:08X}\noutput = build/mod.gba\n", base_name.display(), project.crc32))?;
+        println!("Created a project in {} from {}", directory.display(), base.display());
+        Ok(project)
+    }
+
+    /// Copy the base ROM to the output and apply every asset in the project to it, the same
+    /// project always builds the same ROM
+    pub fn build(&self) -> Result<(), Error> {
+        let base = fs::read(&self.base)?;
+        let crc = crc32(&base[..]);
+        if crc != self.crc32 {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} has checksum {:08X} but the project was made from a ROM with {:08X}", self.base.display(), crc, self.crc32)));
+        }
+
+        if let Some(parent) = self.output.parent() {
+            create_dir_all(parent)?;
+        }
+        fs::write(&self.output, &base[..])?;
+        // a free space record from an earlier build would move assets somewhere else
+        let output = self.output.to_str().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the output path isn't valid unicode"))?;
+        let record = format!("{}.sbrx", output);
+        if Path::new(&record).exists() {
+            fs::remove_file(&record)?;
+        }
+
+        // the output is made from scratch every time, there's nothing to back up
+        let settings = Settings { backup_keep: 0, ..Settings::default() };
+        let mut engine = Engine::open_with_settings(output, settings)?;
+        apply_assets(&mut engine, &self.directory)?;
+        let written = engine.save_all()?;
+
+        println!("Built {} ({} bytes of sprites and palettes changed)", self.output.display(), written);
+        Ok(())
+    }
+}
+
+/// Assets the files in a directory change, named like the asset map. A spritesheet changes the
+/// palette too when there's no palette file since the colors come from the image.
+pub fn asset_files(directory: &Path) -> Vec<String> {
+    let mut assets = Vec::new();
+    for character in CHARACTERS.iter() {
+        let sprites = directory.join("sprites").join(format!("{}.png", character.name)).exists();
+        if sprites {
+            assets.push(format!("{} sprites", character.name));
+        }
+        if sprites || directory.join("palettes").join(format!("{}.pal", character.name)).exists() {
+            assets.push(format!("{} palette", character.name));
+        }
+        if character.text_offsets.0 >= 0 && directory.join("text").join(format!("{}.bin", character.name)).exists() {
+            assets.push(format!("{} text", character.name));
+        }
+    }
+    assets
+}
+
+/// Store the sprites and palettes in a directory laid out like a project, they still have to be
+/// saved. Text is written to the ROM straight away.
+pub fn apply_assets(engine: &mut Engine, directory: &Path) -> Result<(), Error> {
+    for character in CHARACTERS.iter() {
+        let mut palettes = engine.registry.get_mut::<PaletteManager>()?;
+        let mut sprites_manager = engine.registry.get_mut::<SpriteManager>()?;
+        let palette = directory.join("palettes").join(format!("{}.pal", character.name));
+        let colors = match palette.exists() {
+            true => Some(parse_palette(&fs::read_to_string(&palette)?)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} isn't 16 hex colors", palette.display())))?),
+            false => None,
+        };
+
+        let sprites = directory.join("sprites").join(format!("{}.png", character.name));
+        if sprites.exists() {
+            let mut image = open(&sprites).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", sprites.display(), e)))?.to_rgb();
+            match colors {
+                // the pixels are indices into the palette file, not a palette of their own
+                Some(ref colors) => {
+                    let mut cache = GBAColorCache::new();
+                    let rgb: Vec<Color> = colors.iter().map(|&color| cache.gba_to_rgb(color)).collect();
+                    sprites_manager.store_image_with_colors(&mut image, character, &rgb[..])
+                        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {} ({})", sprites.display(), e, palette.display())))?;
+                }
+                None => sprites_manager.store_image(&mut palettes, &mut image, character)?,
+            }
+        }
+
+        if let Some(colors) = colors {
+            palettes.store_palette_i32(character.name.to_string(), colors);
+        }
+        drop(palettes);
+        drop(sprites_manager);
+
+        let text = directory.join("text").join(format!("{}.bin", character.name));
+        if character.text_offsets.0 >= 0 && text.exists() {
+            engine.write_text(character, &fs::read(&text)?[..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Save a character's assets as files laid out like a project
+pub fn export_assets(engine: &mut Engine, directory: &Path, character: &Character) -> Result<(), Error> {
+    for name in ["sprites", "palettes", "text"].iter() {
+        create_dir_all(directory.join(name))?;
+    }
+    let mut palettes = engine.registry.get_mut::<PaletteManager>()?;
+    let palette = palettes.load_palette_colors(character.name.to_string());
+    engine.registry.get::<SpriteManager>()?.load_spritesheet(character)?.to_labelled_img(&palette[..], character)
+        .save(directory.join("sprites").join(format!("{}.png", character.name)))?;
+
+    let colors = palettes.load_palette_i32(character.name.to_string());
+    fs::write(directory.join("palettes").join(format!("{}.pal", character.name)), format_palette(&colors[..]))?;
+
+    if let Some(text) = engine.read_text(character)? {
+        fs::write(directory.join("text").join(format!("{}.bin", character.name)), text)?;
+    }
+    Ok(())
+}
+
+/// A palette as a text file, one color per line
+pub fn format_palette(colors: &[i32]) -> String {
+    colors.iter().map(|color| format!("{:04X}\n", color)).collect()
+}
+
+pub fn parse_palette(text: &str) -> Option<Vec<i32>> {
+    let colors = text.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| i32::from_str_radix(line, 16).ok().filter(|&color| color <= 0x7FFF))
+        .collect::<Option<Vec<i32>>>()?;
+    if colors.len() == 16 { Some(colors) } else { None }
+}
+
+/// `to` as a path relative to the directory `from`, both have to be absolute
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component.as_os_str());
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rom::tests::{noise_rom, TempRom};
+
+    #[test]
+    fn an_unedited_project_builds_the_base_rom() {
+        let rom = noise_rom();
+        let base = TempRom::new("project", &rom);
+        let directory = &base.directory;
+
+        let project = Project::create(&directory.join("mod"), Path::new(&base.path)).unwrap();
+        let manifest = fs::read_to_string(directory.join("mod").join(MANIFEST_NAME)).unwrap();
+        assert!(manifest.contains(&format!("base = {}", Path::new("..").join("rom.gba").display())));
+
+        project.build().unwrap();
+        assert!(fs::read(&project.output).unwrap() == rom);
+
+        // the project still builds from its manifest
+        Project::load(&directory.join("mod")).unwrap().build().unwrap();
+        assert!(fs::read(&project.output).unwrap() == rom);
+    }
+
+    #[test]
+    fn palettes_are_15_bit() {
+        let colors: String = (0..16).map(|i| format!("{:04X}\n", i * 0x7FF)).collect();
+        assert_eq!(parse_palette(&colors), Some((0..16).map(|i| i * 0x7FF).collect()));
+        assert_eq!(parse_palette(&colors.replace("0000", "8000")), None);
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path(Path::new("/a/b"), Path::new("/a/b/c.gba")), PathBuf::from("c.gba"));
+        assert_eq!(relative_path(Path::new("/a/b"), Path::new("/a/c/d.gba")), PathBuf::from("../c/d.gba"));
+        assert_eq!(relative_path(Path::new("/a/b/c"), Path::new("/d.gba")), PathBuf::from("../../../d.gba"));
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use ::data::*;
+use ::engine::*;
+use ::manager::palette::{PaletteManager, variant_name};
+use ::manager::sprite::{SpriteManager, Spritesheet};
+
+/*
+ * Palette remapping
+ *
+ * Moves palette indices around in a character's whole spritesheet and every one of its palettes at
+ * once, so the sheet looks the same afterwards. Remaps are written as text:
+ *
+ *     swap 3 5           indices 3 and 5 trade places
+ *     merge 7 2          everything drawn with 7 uses 2 instead, 7 is free afterwards
+ *     0 2 1 3 4 ... 15   a full mapping, the n-th number is where index n goes
+ *
+ * Index 0 is transparent, so it can't move and nothing can be merged into it. Undoing a remap maps
+ * the indices back instead of restoring a copy of the sheet, so edits made after it are kept.
+ */
+
+const PALETTE_SIZE: usize = 16;
+
+/// undo steps kept for remaps
+const MAX_UNDO: usize = 20;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PaletteRemap {
+    /// `mapping[old] = new`
+    pub mapping: [u8; PALETTE_SIZE],
+}
+
+impl PaletteRemap {
+    pub fn identity() -> PaletteRemap {
+        let mut mapping = [0; PALETTE_SIZE];
+        for (i, slot) in mapping.iter_mut().enumerate() {
+            *slot = i as u8;
+        }
+        PaletteRemap { mapping }
+    }
+
+    pub fn swap(a: usize, b: usize) -> PaletteRemap {
+        let mut remap = PaletteRemap::identity();
+        remap.mapping.swap(a, b);
+        remap
+    }
+
+    pub fn merge(from: usize, into: usize) -> PaletteRemap {
+        let mut remap = PaletteRemap::identity();
+        remap.mapping[from] = into as u8;
+        remap
+    }
+
+    pub fn parse(text: &str) -> Result<PaletteRemap, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid remap \"{}\", use swap <a> <b>, merge <from> <into> or 16 indices", text));
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let index = |word: &str| word.parse::<usize>().ok().filter(|&index| index < PALETTE_SIZE);
+
+        match words.first() {
+            Some(&"swap") if words.len() == 3 => Ok(PaletteRemap::swap(index(words[1]).ok_or_else(invalid)?, index(words[2]).ok_or_else(invalid)?)),
+            Some(&"merge") if words.len() == 3 => Ok(PaletteRemap::merge(index(words[1]).ok_or_else(invalid)?, index(words[2]).ok_or_else(invalid)?)),
+            _ if words.len() == PALETTE_SIZE => {
+                let mut remap = PaletteRemap::identity();
+                for (i, word) in words.iter().enumerate() {
+                    remap.mapping[i] = index(word).ok_or_else(invalid)? as u8;
+                }
+                Ok(remap)
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Index 0 is transparent, it has to stay where it is
+    pub fn check(&self) -> Result<(), Error> {
+        if self.mapping[0] != 0 || self.mapping[1..].contains(&0) {
+            return Err(Error::new(ErrorKind::InvalidInput, "index 0 is transparent, it can't be moved or merged into"));
+        }
+        Ok(())
+    }
+
+    /// The index whose color ends up in `new`: an index that stays where it is wins over ones merged
+    /// into it, otherwise the first one mapped to it. None if nothing is mapped to it.
+    pub fn source(&self, new: usize) -> Option<usize> {
+        if self.mapping.get(new) == Some(&(new as u8)) {
+            Some(new)
+        } else {
+            (0..PALETTE_SIZE).find(|&old| self.mapping[old] as usize == new)
+        }
+    }
+
+    /// The palette after the remap. Each slot gets the color of the first index mapped to it, slots
+    /// nothing is mapped to keep their color.
+    pub fn apply_to_palette(&self, colors: &[i32]) -> Vec<i32> {
+        let mut remapped = colors.to_vec();
+        for (new, color) in remapped.iter_mut().enumerate() {
+            if let Some(old) = self.source(new).filter(|&old| old < colors.len()) {
+                *color = colors[old];
+            }
+        }
+        remapped
+    }
+}
+
+/// Call `f` with every pixel of a spritesheet and its (animation, frame, pixel) position
+fn for_each_pixel<F: FnMut((usize, usize, usize), &mut u8)>(spritesheet: &mut Spritesheet, mut f: F) {
+    for (animation_index, animation) in spritesheet.animations.iter_mut().enumerate() {
+        for (frame_index, frame) in animation.frames.iter_mut().enumerate() {
+            let pixels = frame.sections.iter_mut().flat_map(|section| section.bytes.iter_mut()).flat_map(|row| row.iter_mut());
+            for (pixel_index, pixel) in pixels.enumerate() {
+                f((animation_index, frame_index, pixel_index), pixel);
+            }
+        }
+    }
+}
+
+/// What's needed to map a character's sprites and palettes back after a remap. Only what the
+/// mapping can't give back is kept: pixels of indices that were merged into another one and
+/// colors that were overwritten.
+struct RemapUndo {
+    character: Character,
+    remap: PaletteRemap,
+    /// (animation, frame, pixel) and index of every pixel the mapping can't bring back
+    merged: HashMap<(usize, usize, usize), u8>,
+    /// (variant, index, color) of every color the remap dropped
+    dropped: Vec<(usize, usize, i32)>,
+}
+
+/// Applies remaps and keeps what's needed to undo them
+pub struct RemapHistory {
+    undo: Vec<RemapUndo>,
+}
+
+impl RemapHistory {
+    pub fn new() -> RemapHistory {
+        RemapHistory { undo: Vec::new() }
+    }
+
+    /// Remap a character's sprites and every palette variant
+    pub fn apply(&mut self, engine: &mut Engine, character: &Character, remap: &PaletteRemap) -> Result<(), Error> {
+        remap.check()?;
+        let mut palette_manager = engine.registry.get_mut::<PaletteManager>()?;
+        let mut sprite_manager = engine.registry.get_mut::<SpriteManager>()?;
+        let variants = palette_manager.variant_count(character);
+        let palettes: Vec<Vec<i32>> = (0..variants).map(|variant| palette_manager.load_palette_i32(variant_name(character, variant))).collect();
+        let spritesheet = sprite_manager.load_spritesheet_mut(character)?;
+
+        // an index that isn't the source of its new index shares it with another one afterwards
+        let kept = |old: usize| old >= PALETTE_SIZE || remap.source(remap.mapping[old] as usize) == Some(old);
+        let mut merged = HashMap::new();
+        for_each_pixel(spritesheet, |position, pixel| {
+            if !kept(*pixel as usize) {
+                merged.insert(position, *pixel);
+            }
+        });
+        let dropped = palettes.iter().enumerate()
+            .flat_map(|(variant, colors)| colors.iter().enumerate().filter(|&(old, _)| !kept(old)).map(move |(old, &color)| (variant, old, color)))
+            .collect();
+        self.undo.push(RemapUndo { character: *character, remap: *remap, merged, dropped });
+        if self.undo.len() > MAX_UNDO {
+            self.undo.remove(0);
+        }
+
+        spritesheet.remap_indices(&remap.mapping);
+        for (variant, colors) in palettes.iter().enumerate() {
+            palette_manager.store_palette_i32(variant_name(character, variant), remap.apply_to_palette(colors));
+        }
+        sprite_manager.mark_all_dirty(character);
+        Ok(())
+    }
+
+    /// Undo the last remap by mapping every index back, returns the character it was for
+    pub fn undo(&mut self, engine: &mut Engine) -> Option<Character> {
+        let mut palette_manager = engine.registry.get_mut::<PaletteManager>().ok()?;
+        let mut sprite_manager = engine.registry.get_mut::<SpriteManager>().ok()?;
+        let undo = self.undo.pop()?;
+        let character = undo.character;
+        let mapping = undo.remap.mapping;
+
+        // indices nothing was mapped to were drawn after the remap and stay as they are
+        let mut inverse = [0; PALETTE_SIZE];
+        for (new, slot) in inverse.iter_mut().enumerate() {
+            *slot = undo.remap.source(new).unwrap_or(new) as u8;
+        }
+        if let Ok(spritesheet) = sprite_manager.load_spritesheet_mut(&character) {
+            for_each_pixel(spritesheet, |position, pixel| {
+                *pixel = match undo.merged.get(&position) {
+                    Some(&old) if mapping[old as usize] == *pixel => old,
+                    _ => inverse.get(*pixel as usize).cloned().unwrap_or(*pixel),
+                };
+            });
+        }
+
+        for variant in 0..palette_manager.variant_count(&character) {
+            let name = variant_name(&character, variant);
+            let colors = palette_manager.load_palette_i32(name.clone());
+            let mut restored: Vec<i32> = (0..colors.len())
+                .map(|old| colors.get(mapping.get(old).map_or(old, |&new| new as usize)).cloned().unwrap_or(colors[old]))
+                .collect();
+            for &(_, old, color) in undo.dropped.iter().filter(|&&(dropped_variant, _, _)| dropped_variant == variant) {
+                restored[old] = color;
+            }
+            palette_manager.store_palette_i32(name, restored);
+        }
+        sprite_manager.mark_all_dirty(&character);
+        Some(character)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rom::tests::{noise_rom, TempRom};
+
+    #[test]
+    fn index_0_stays_put() {
+        assert!(PaletteRemap::swap(1, 2).check().is_ok());
+        assert!(PaletteRemap::swap(0, 2).check().is_err());
+        assert!(PaletteRemap::merge(3, 0).check().is_err());
+        assert!(PaletteRemap::merge(0, 3).check().is_err());
+    }
+
+    #[test]
+    fn palettes_follow_the_mapping() {
+        let colors: Vec<i32> = (0..16).collect();
+        let swapped = PaletteRemap::swap(1, 2).apply_to_palette(&colors);
+        assert_eq!(&swapped[..4], &[0, 2, 1, 3]);
+        // a merged index keeps its color, the index it's merged into keeps its own
+        assert_eq!(PaletteRemap::merge(3, 5).apply_to_palette(&colors), colors);
+        assert_eq!(PaletteRemap::merge(3, 5).source(5), Some(5));
+        assert_eq!(PaletteRemap::merge(3, 5).source(3), None);
+    }
+
+    #[test]
+    fn undo_keeps_later_edits() {
+        let rom = TempRom::new("remap", &noise_rom());
+        let mut engine = Engine::open_read_only(&rom.path).unwrap();
+        let character = CHARACTERS[0];
+        let palette = engine.registry.get::<PaletteManager>().unwrap().load_palette_i32(character.name.to_string());
+        let pixels = |engine: &mut Engine| {
+            let mut pixels = Vec::new();
+            for_each_pixel(engine.registry.get_mut::<SpriteManager>().unwrap().load_spritesheet_mut(&character).unwrap(), |_, pixel| pixels.push(*pixel));
+            pixels
+        };
+        let before = pixels(&mut engine);
+
+        let mut history = RemapHistory::new();
+        history.apply(&mut engine, &character, &PaletteRemap::parse("0 2 1 3 4 5 6 7 8 9 10 11 12 13 14 15").unwrap()).unwrap();
+        history.apply(&mut engine, &character, &PaletteRemap::merge(3, 5)).unwrap();
+        assert!(history.apply(&mut engine, &character, &PaletteRemap::swap(0, 4)).is_err());
+
+        // an edit after the remaps
+        let edited = before.iter().position(|&index| index == 9).unwrap();
+        let mut count = 0;
+        for_each_pixel(engine.registry.get_mut::<SpriteManager>().unwrap().load_spritesheet_mut(&character).unwrap(), |_, pixel| {
+            if count == edited {
+                *pixel = 7;
+            }
+            count += 1;
+        });
+
+        history.undo(&mut engine).unwrap();
+        history.undo(&mut engine).unwrap();
+        assert!(!history.can_undo());
+        let after = pixels(&mut engine);
+        let mut expected = before.clone();
+        expected[edited] = 7;
+        assert!(after == expected);
+        assert_eq!(engine.registry.get::<PaletteManager>().unwrap().load_palette_i32(character.name.to_string()), palette);
+    }
+}
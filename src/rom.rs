@@ -0,0 +1,216 @@
+// Helpers for reading values and pointers out of a ROM image
+
+use std::fs::File;
+use std::io::{SeekFrom, Seek, Read, Error, Write};
+
+use ::data::*;
+use ::error::SbrxError;
+
+/// the ROM is mapped to 0x08000000, so that's where pointers into it start
+pub const ROM_BASE: u32 = 0x08000000;
+
+/// Read the whole ROM into memory
+pub fn read_rom(file: &mut File) -> Result<Vec<u8>, Error> {
+    let mut rom = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+/// Read a little endian word
+pub fn read_u32(rom: &[u8], offset: usize) -> Option<u32> {
+    if offset + 4 > rom.len() {
+        return None;
+    }
+    Some(rom[offset] as u32 | (rom[offset + 1] as u32) << 8 | (rom[offset + 2] as u32) << 16 | (rom[offset + 3] as u32) << 24)
+}
+
+/// Convert a pointer to an offset in the ROM, None if it points somewhere else
+pub fn pointer_to_offset(pointer: u32, rom_length: usize) -> Option<usize> {
+    if pointer < ROM_BASE || (pointer - ROM_BASE) as usize >= rom_length {
+        return None;
+    }
+    Some((pointer - ROM_BASE) as usize)
+}
+
+/// Convert an offset in the ROM to a pointer, None if it's too far to be pointed to
+pub fn offset_to_pointer(offset: usize) -> Option<u32> {
+    if offset as u64 > u32::max_value() as u64 {
+        return None;
+    }
+    ROM_BASE.checked_add(offset as u32)
+}
+
+/// Offsets of every aligned word in the ROM that points to `offset`
+pub fn find_pointers(rom: &[u8], offset: usize) -> Vec<usize> {
+    let pointer = match offset_to_pointer(offset) {
+        Some(pointer) => pointer,
+        None => return Vec::new(),
+    };
+    (0..rom.len() / 4).map(|i| i * 4).filter(|&i| read_u32(rom, i) == Some(pointer)).collect()
+}
+
+/// (start, end) of every run of bytes that differ between `old` and `new`
+pub fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for i in (0..new.len()).filter(|&i| old.get(i) != Some(&new[i])) {
+        match ranges.last_mut() {
+            Some(last) if last.1 == i => last.1 = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+    ranges
+}
+
+/// Write only the bytes that are different from what's already in the file, returns how many
+/// bytes were written
+pub fn write_changes(file: &mut File, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    let mut old = Vec::new();
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(bytes.len() as u64).read_to_end(&mut old)?;
+
+    let mut written = 0;
+    for (start, end) in changed_ranges(&old[..], bytes) {
+        file.seek(SeekFrom::Start(offset + start as u64))?;
+        file.write_all(&bytes[start..end])?;
+        written += end - start;
+    }
+    Ok(written)
+}
+
+/// CRC-32 as used by zip and png, to tell ROMs apart
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for i in 0..256 {
+        let mut value = i as u32;
+        for _ in 0..8 {
+            value = if value & 1 == 1 { 0xEDB88320 ^ (value >> 1) } else { value >> 1 };
+        }
+        table[i] = value;
+    }
+    !data.iter().fold(!0, |crc, &byte| table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+pub fn rom_length(file: &File) -> Result<u64, Error> {
+    Ok(file.metadata()?.len())
+}
+
+/// Check a write before it happens. It has to stay inside the ROM and inside `extent`, the
+/// (offset, length) the asset is allowed to use, and can't touch any asset in `assets` other than
+/// the one named `asset`.
+pub fn check_write(asset: &str, offset: u64, length: u64, extent: (u64, u64), rom_length: u64, assets: &[AssetRegion]) -> Result<(), SbrxError> {
+    let end = offset + length;
+    if end > rom_length {
+        return Err(SbrxError::BoundsViolation(format!(
+            "writing {} at {:#X}-{:#X} would go past the end of the ROM at {:#X}", asset, offset, end, rom_length)));
+    }
+    if offset < extent.0 || end > extent.0 + extent.1 {
+        return Err(SbrxError::BoundsViolation(format!(
+            "{} has {:#X} bytes at {:#X} but {:#X} bytes would be written at {:#X}", asset, extent.1, extent.0, length, offset)));
+    }
+    for other in assets.iter().filter(|other| other.name != asset && other.length > 0) {
+        if offset < other.offset + other.length && other.offset < end {
+            return Err(SbrxError::BoundsViolation(format!(
+                "writing {} at {:#X}-{:#X} would overwrite {} at {:#X}-{:#X}",
+                asset, offset, end, other.name, other.offset, other.offset + other.length)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A ROM of noise, big enough for every offset in data.rs
+    pub fn noise_rom() -> Vec<u8> {
+        let mut state = 0x2545F491u32;
+        (0..0x1000000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    /// A ROM written to its own directory in the temp directory. The directory goes when it's
+    /// dropped, with whatever sbrx kept next to the ROM, even if the test failed.
+    pub struct TempRom {
+        pub directory: PathBuf,
+        pub path: String,
+    }
+
+    impl TempRom {
+        pub fn new(name: &str, rom: &[u8]) -> TempRom {
+            let directory = ::std::env::temp_dir().join(format!("sbrx-{}-{}", name, ::std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            let path = directory.join("rom.gba").to_string_lossy().into_owned();
+            fs::write(&path, rom).unwrap();
+            TempRom { directory, path }
+        }
+    }
+
+    impl Drop for TempRom {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn changed_ranges_of_equal_data() {
+        assert_eq!(changed_ranges(&[], &[]), vec![]);
+        assert_eq!(changed_ranges(&[1, 2, 3], &[1, 2, 3]), vec![]);
+    }
+
+    #[test]
+    fn changed_ranges_merge_neighbours() {
+        assert_eq!(changed_ranges(&[0, 0, 0, 0, 0, 0], &[1, 1, 0, 0, 1, 0]), vec![(0, 2), (4, 5)]);
+        assert_eq!(changed_ranges(&[0, 0, 0], &[0, 1, 1]), vec![(1, 3)]);
+    }
+
+    #[test]
+    fn changed_ranges_of_different_lengths() {
+        // bytes past the end of the old data are always written
+        assert_eq!(changed_ranges(&[], &[5, 6]), vec![(0, 2)]);
+        assert_eq!(changed_ranges(&[1, 2], &[1, 2, 3, 4]), vec![(2, 4)]);
+        assert_eq!(changed_ranges(&[1, 9], &[1, 2, 3]), vec![(1, 3)]);
+        // old bytes past the end of the new data are left alone
+        assert_eq!(changed_ranges(&[1, 2, 3, 4], &[1, 2]), vec![]);
+        assert_eq!(changed_ranges(&[1, 2, 3], &[]), vec![]);
+    }
+
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+        assert_ne!(crc32(&[0]), crc32(&[0, 0]));
+    }
+
+    #[test]
+    fn pointers_and_offsets() {
+        assert_eq!(offset_to_pointer(0x1234), Some(0x08001234));
+        assert_eq!(offset_to_pointer(u64::max_value() as usize), None);
+        assert_eq!(pointer_to_offset(0x08001234, 0x2000), Some(0x1234));
+        assert_eq!(pointer_to_offset(0x08002000, 0x2000), None);
+        assert_eq!(pointer_to_offset(0x03000000, 0x2000), None);
+        assert_eq!(read_u32(&[0x34, 0x12, 0x00, 0x08], 0), Some(0x08001234));
+        assert_eq!(read_u32(&[0x34, 0x12, 0x00], 0), None);
+        assert_eq!(find_pointers(&[0, 0, 0, 0, 0x04, 0, 0, 0x08, 0x04, 0, 0, 0x08], 4), vec![4, 8]);
+    }
+
+    #[test]
+    fn check_write_bounds() {
+        let assets = asset_map();
+        assert!(check_write("test", 0x10, 0x10, (0x10, 0x10), 0x100, &assets).is_ok());
+        assert!(check_write("test", 0xF8, 0x10, (0xF8, 0x10), 0x100, &assets).is_err());
+        assert!(check_write("test", 0x10, 0x11, (0x10, 0x10), 0x100, &assets).is_err());
+        assert!(check_write("test", 0x0C, 0x04, (0x10, 0x10), 0x100, &assets).is_err());
+        // inside its extent but on top of Sonic's palette
+        let palette = SONIC_DATA.palette_offset;
+        assert!(check_write("test", palette, 4, (palette, 4), 0x1000000, &assets).is_err());
+        assert!(check_write("Sonic palette", palette, 4, (palette, 4), 0x1000000, &assets).is_ok());
+    }
+}
@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::io::Error;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use rhai::{self, Array, Blob, Dynamic, EvalAltResult, Scope, INT};
+
+use ::data::*;
+use ::color::*;
+use ::engine::*;
+use ::export::{self, ExportOptions};
+use ::manager::palette::{PaletteManager, variant_name};
+use ::manager::sprite::{Frame, SpriteManager, Spritesheet};
+
+/*
+ * Scripts
+ *
+ * Batch edits written in Rhai (https://rhai.rs). The ROM is in the variable `rom`, nothing is
+ * written to it until `rom.save()` is called, except for text:
+ *
+ *     for name in characters() {
+ *         let colors = rom.palette(name);
+ *         colors[1] = gba(255, 0, 0);
+ *         rom.set_palette(name, colors);
+ *     }
+ *     rom.save();
+ *
+ * rom.palette(character [, variant])           16 GBA colors
+ * rom.set_palette(character, [variant,] colors)
+ * rom.variant_count(character)
+ * rom.spritesheet(character)                   a copy, changes are kept with rom.set_spritesheet
+ * rom.set_spritesheet(character, sheet)
+ * rom.animations(character)                    animation labels, like "02 walk"
+ * rom.text(character)                          the text block as a blob, () if there is none
+ * rom.set_text(character, blob)                written to the ROM straight away
+ * rom.export(character, directory)             the same files as Export All
+ * rom.export_all(directory)
+ * rom.unsaved()                                what would be written by rom.save()
+ * rom.save()                                   write every change, returns the bytes written
+ * rom.revert()                                 throw away every change that wasn't saved
+ *
+ * sheet.animation_count, sheet.frame_count(animation)
+ * sheet.frame(animation, frame), sheet.set_frame(animation, frame, frame)
+ * sheet.remap(indices)                         `indices[old] = new`
+ * frame.width, frame.height, frame.get_pixel(x, y), frame.set_pixel(x, y, index), frame.shift(dx, dy)
+ *
+ * characters(), rgb(color) -> [r, g, b], gba(r, g, b) -> color
+ */
+
+/// stop scripts that run away, a pass over every pixel of every character is well below this
+pub const MAX_OPERATIONS: u64 = 2_000_000_000;
+
+/// the editor's console runs scripts on the UI thread, so it gives up after about a second.
+/// Longer scripts can be run with `sbrx run`.
+pub const CONSOLE_MAX_OPERATIONS: u64 = 20_000_000;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// The ROM as scripts see it
+#[derive(Clone)]
+struct Rom(Rc<RefCell<Engine>>);
+
+/// Run a script on a ROM, it's stopped after `max_operations`. Everything the script prints is
+/// passed to `output`. The engine is handed back whether the script worked or not, changes it
+/// made before failing are kept.
+pub fn run<F>(engine: Engine, name: &str, source: &str, max_operations: u64, output: F) -> (Engine, Result<(), Error>)
+    where F: Fn(&str) + 'static {
+    let rom = Rom(Rc::new(RefCell::new(engine)));
+    let output = Rc::new(output);
+
+    let mut script_engine = script_engine();
+    script_engine.set_max_operations(max_operations);
+    let print = output.clone();
+    script_engine.on_print(move |text| print(text));
+    let debug = output.clone();
+    script_engine.on_debug(move |text, _, position| debug(&format!("{:?} {}", position, text)));
+
+    let result = {
+        let mut scope = Scope::new();
+        scope.push("rom", rom.clone());
+        script_engine.run_with_scope(&mut scope, source)
+            .map_err(|error| match *error {
+                EvalAltResult::ErrorTooManyOperations(_) => Error::other(format!(
+                    "{}: stopped after {} operations, run longer scripts with sbrx run", name, max_operations)),
+                _ => Error::other(format!("{}: {}", name, error)),
+            })
+    };
+
+    // the scope and the script engine are gone, nothing else should hold on to the ROM. If
+    // something does it gets an empty engine so ours can still be handed back.
+    drop(script_engine);
+    match Rc::try_unwrap(rom.0) {
+        Ok(engine) => (engine.into_inner(), result),
+        Err(shared) => {
+            let file = shared.borrow().file.clone();
+            let engine = mem::replace(&mut *shared.borrow_mut(), Engine::new(file));
+            (engine, result.and(Err(Error::other(format!("{}: the script kept the ROM after it finished", name)))))
+        }
+    }
+}
+
+/// Run a script file
+pub fn run_file<F>(engine: Engine, path: &Path, output: F) -> (Engine, Result<(), Error>)
+    where F: Fn(&str) + 'static {
+    match ::std::fs::read_to_string(path) {
+        Ok(source) => run(engine, &path.display().to_string(), &source, MAX_OPERATIONS, output),
+        Err(error) => (engine, Err(error)),
+    }
+}
+
+fn script_engine() -> rhai::Engine {
+    let mut engine = rhai::Engine::new();
+
+    engine.register_type_with_name::<Rom>("Rom");
+    engine.register_type_with_name::<Spritesheet>("Spritesheet");
+    engine.register_type_with_name::<Frame>("Frame");
+
+    engine.register_fn("characters", || -> Array {
+        CHARACTERS.iter().map(|character| Dynamic::from(character.name.to_string())).collect()
+    });
+    engine.register_fn("rgb", |color: INT| -> Array {
+        let color = GBAColorCache::new().gba_to_rgb(color as i32);
+        vec![Dynamic::from(color.r as INT), Dynamic::from(color.g as INT), Dynamic::from(color.b as INT)]
+    });
+    engine.register_fn("gba", |r: INT, g: INT, b: INT| -> INT {
+        GBAColorCache::new().rgb_to_gba(Color { r: r as i32, g: g as i32, b: b as i32 }) as INT
+    });
+
+    engine.register_fn("palette", |rom: &mut Rom, character: &str| rom.palette(character, 0));
+    engine.register_fn("palette", |rom: &mut Rom, character: &str, variant: INT| rom.palette(character, variant));
+    engine.register_fn("set_palette", |rom: &mut Rom, character: &str, colors: Array| rom.set_palette(character, 0, colors));
+    engine.register_fn("set_palette", |rom: &mut Rom, character: &str, variant: INT, colors: Array| rom.set_palette(character, variant, colors));
+    engine.register_fn("variant_count", |rom: &mut Rom, character: &str| -> ScriptResult<INT> {
+        let character = character_arg(character)?;
+        let engine = rom.0.borrow();
+        let palettes = engine.registry.get::<PaletteManager>().map_err(script_error)?;
+        Ok(palettes.variant_count(&character) as INT)
+    });
+    engine.register_fn("spritesheet", |rom: &mut Rom, character: &str| -> ScriptResult<Spritesheet> {
+        let character = character_arg(character)?;
+        let engine = rom.0.borrow();
+        let sprites = engine.registry.get::<SpriteManager>().map_err(script_error)?;
+        sprites.load_spritesheet(&character).cloned().map_err(script_error)
+    });
+    engine.register_fn("set_spritesheet", |rom: &mut Rom, character: &str, spritesheet: Spritesheet| -> ScriptResult<()> {
+        let character = character_arg(character)?;
+        let engine = rom.0.borrow();
+        let mut sprites = engine.registry.get_mut::<SpriteManager>().map_err(script_error)?;
+        *sprites.load_spritesheet_mut(&character).map_err(script_error)? = spritesheet;
+        sprites.mark_all_dirty(&character);
+        Ok(())
+    });
+    engine.register_fn("animations", |rom: &mut Rom, character: &str| -> ScriptResult<Array> {
+        let character = character_arg(character)?;
+        let engine = rom.0.borrow();
+        let sprites = engine.registry.get::<SpriteManager>().map_err(script_error)?;
+        let count = sprites.load_spritesheet(&character).map_err(script_error)?.animations.len();
+        Ok((0..count).map(|index| Dynamic::from(character.animation_label(index))).collect())
+    });
+    engine.register_fn("text", |rom: &mut Rom, character: &str| -> ScriptResult<Dynamic> {
+        let character = character_arg(character)?;
+        Ok(rom.0.borrow().read_text(&character).map_err(script_error)?.map_or(Dynamic::UNIT, Dynamic::from_blob))
+    });
+    engine.register_fn("set_text", |rom: &mut Rom, character: &str, text: Blob| -> ScriptResult<INT> {
+        let character = character_arg(character)?;
+        let mut engine = rom.0.borrow_mut();
+        engine.before_write().map_err(script_error)?;
+        engine.write_text(&character, &text[..]).map(|written| written as INT).map_err(script_error)
+    });
+    engine.register_fn("export", |rom: &mut Rom, character: &str, directory: &str| -> ScriptResult<INT> {
+        let character = character_arg(character)?;
+        export::export_character(&mut rom.0.borrow_mut(), Path::new(directory), &character, &ExportOptions::default())
+            .map(|written| written as INT).map_err(script_error)
+    });
+    engine.register_fn("export_all", |rom: &mut Rom, directory: &str| -> ScriptResult<INT> {
+        export::export_all(&mut rom.0.borrow_mut(), Path::new(directory), &ExportOptions::default())
+            .map(|written| written as INT).map_err(script_error)
+    });
+    engine.register_fn("unsaved", |rom: &mut Rom| -> Array {
+        rom.0.borrow().unsaved().into_iter().map(Dynamic::from).collect()
+    });
+    engine.register_fn("revert", |rom: &mut Rom| -> ScriptResult<()> {
+        rom.0.borrow_mut().revert_all().map_err(script_error)
+    });
+    engine.register_fn("save", |rom: &mut Rom| -> ScriptResult<INT> {
+        rom.0.borrow_mut().save_all().map(|written| written as INT).map_err(script_error)
+    });
+
+    engine.register_get("animation_count", |spritesheet: &mut Spritesheet| spritesheet.animations.len() as INT);
+    engine.register_fn("frame_count", |spritesheet: &mut Spritesheet, animation: INT| -> ScriptResult<INT> {
+        let animation = index_arg(animation, spritesheet.animations.len(), "animation")?;
+        Ok(spritesheet.animations[animation].frames.len() as INT)
+    });
+    engine.register_fn("frame", |spritesheet: &mut Spritesheet, animation: INT, frame: INT| -> ScriptResult<Frame> {
+        let animation = index_arg(animation, spritesheet.animations.len(), "animation")?;
+        let frames = &spritesheet.animations[animation].frames;
+        Ok(frames[index_arg(frame, frames.len(), "frame")?].clone())
+    });
+    engine.register_fn("set_frame", |spritesheet: &mut Spritesheet, animation: INT, index: INT, frame: Frame| -> ScriptResult<()> {
+        let animation = index_arg(animation, spritesheet.animations.len(), "animation")?;
+        let frames = &mut spritesheet.animations[animation].frames;
+        let index = index_arg(index, frames.len(), "frame")?;
+        if frame.width() != frames[index].width() || frame.height() != frames[index].height() {
+            return Err(script_error("the frame is a different size"));
+        }
+        frames[index] = frame;
+        Ok(())
+    });
+    engine.register_fn("remap", |spritesheet: &mut Spritesheet, indices: Array| -> ScriptResult<()> {
+        let mapping = indices.into_iter().map(color_index).collect::<ScriptResult<Vec<u8>>>()?;
+        if mapping.len() != 16 {
+            return Err(script_error("a remap needs 16 indices"));
+        }
+        spritesheet.remap_indices(&mapping[..]);
+        Ok(())
+    });
+
+    engine.register_get("width", |frame: &mut Frame| frame.width() as INT);
+    engine.register_get("height", |frame: &mut Frame| frame.height() as INT);
+    engine.register_fn("get_pixel", |frame: &mut Frame, x: INT, y: INT| -> ScriptResult<INT> {
+        let (x, y) = (index_arg(x, frame.width(), "x")?, index_arg(y, frame.height(), "y")?);
+        Ok(frame.get_pixel(x, y) as INT)
+    });
+    engine.register_fn("set_pixel", |frame: &mut Frame, x: INT, y: INT, index: INT| -> ScriptResult<()> {
+        let (x, y) = (index_arg(x, frame.width(), "x")?, index_arg(y, frame.height(), "y")?);
+        frame.set_pixel(x, y, color_index(Dynamic::from(index))?);
+        Ok(())
+    });
+    engine.register_fn("shift", |frame: &mut Frame, dx: INT, dy: INT| frame.shift(dx as i32, dy as i32));
+
+    engine
+}
+
+impl Rom {
+    fn palette(&self, character: &str, variant: INT) -> ScriptResult<Array> {
+        let character = character_arg(character)?;
+        let engine = self.0.borrow();
+        let palettes = engine.registry.get::<PaletteManager>().map_err(script_error)?;
+        let variant = index_arg(variant, palettes.variant_count(&character), "palette variant")?;
+        Ok(palettes.load_palette_i32(variant_name(&character, variant)).into_iter()
+            .map(|color| Dynamic::from(color as INT))
+            .collect())
+    }
+
+    fn set_palette(&self, character: &str, variant: INT, colors: Array) -> ScriptResult<()> {
+        let character = character_arg(character)?;
+        let engine = self.0.borrow();
+        let mut palettes = engine.registry.get_mut::<PaletteManager>().map_err(script_error)?;
+        let variant = index_arg(variant, palettes.variant_count(&character), "palette variant")?;
+        let colors = colors.into_iter()
+            .map(|color| color.as_int().ok().filter(|color| (0..=0x7FFF).contains(color)).map(|color| color as i32))
+            .collect::<Option<Vec<i32>>>()
+            .filter(|colors| colors.len() == 16)
+            .ok_or_else(|| script_error("a palette is 16 GBA colors from 0 to 0x7FFF"))?;
+        palettes.store_palette_i32(variant_name(&character, variant), colors);
+        Ok(())
+    }
+}
+
+fn script_error<E: ToString>(error: E) -> Box<EvalAltResult> {
+    error.to_string().into()
+}
+
+fn character_arg(name: &str) -> ScriptResult<Character> {
+    find_character(name).ok_or_else(|| script_error(format!("unknown character {}", name)))
+}
+
+fn index_arg(index: INT, length: usize, name: &str) -> ScriptResult<usize> {
+    if index >= 0 && (index as usize) < length {
+        Ok(index as usize)
+    } else {
+        Err(script_error(format!("{} {} is out of range, there are {}", name, index, length)))
+    }
+}
+
+fn color_index(index: Dynamic) -> ScriptResult<u8> {
+    index.as_int().ok().filter(|index| (0..16).contains(index)).map(|index| index as u8)
+        .ok_or_else(|| script_error("palette indices go from 0 to 15"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let path = ::std::env::temp_dir().join(format!("sbrx-script-{}.gba", ::std::process::id()));
+        fs::write(&path, b"rom").unwrap();
+        let engine = Engine::new(Arc::new(Mutex::new(File::open(&path).unwrap())));
+        let (_engine, result) = run(engine, "console", "loop { }", 1000, |_| ());
+        assert!(result.unwrap_err().to_string().contains("stopped after 1000 operations"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+/*
+ * Settings
+ *
+ * Read from `sbrx.cfg` in the working directory, one `key = value` per line. Everything has a
+ * default so the file is optional:
+ *
+ *     backup_dir = roms/backups
+ *     backup_keep = 10
+ *     backup_every_write = no
+ */
+
+pub const SETTINGS_PATH: &'static str = "sbrx.cfg";
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// where ROM backups go
+    pub backup_dir: String,
+    /// how many backups of each ROM to keep, 0 turns backups off
+    pub backup_keep: usize,
+    /// back up before every write instead of only the first one of a session
+    pub backup_every_write: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            backup_dir: "roms/backups".to_string(),
+            backup_keep: 10,
+            backup_every_write: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings file, or the defaults if there isn't one
+    pub fn load() -> Result<Settings, Error> {
+        let mut settings = Settings::default();
+        let contents = match fs::read_to_string(SETTINGS_PATH) {
+            Ok(contents) => contents,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => return Ok(settings),
+            Err(error) => return Err(error),
+        };
+
+        let invalid = |line: &str| Error::new(ErrorKind::InvalidData, format!("invalid line in {}: {}", SETTINGS_PATH, line));
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(invalid(line)),
+            };
+            match key {
+                "backup_dir" => settings.backup_dir = value.to_string(),
+                "backup_keep" => settings.backup_keep = value.parse().map_err(|_| invalid(line))?,
+                "backup_every_write" => settings.backup_every_write = parse_bool(value).ok_or_else(|| invalid(line))?,
+                _ => return Err(invalid(line)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+fn parse_bool(text: &str) -> Option<bool> {
+    match text {
+        "yes" | "true" | "1" => Some(true),
+        "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
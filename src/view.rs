@@ -0,0 +1,229 @@
+use image::{ImageBuffer, Rgb};
+
+use ::color::*;
+use ::manager::sprite::*;
+
+pub const MIN_ZOOM: u32 = 1;
+pub const MAX_ZOOM: u32 = 16;
+
+const SECTION_GRID: [u8; 3] = [90, 90, 90];
+const FRAME_GRID: [u8; 3] = [255, 255, 255];
+const BACKGROUND: [u8; 3] = [40, 40, 40];
+
+/// A pixel perfect window into a spritesheet image, zoomed by whole numbers
+pub struct SheetView {
+    pub zoom: u32,
+    pub grid: bool,
+    /// top left corner of the view in sheet pixels
+    pub pan: (f64, f64),
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What's under the mouse in the spritesheet view
+pub struct HoverInfo {
+    pub animation: usize,
+    pub frame: usize,
+    pub section: usize,
+    pub x: usize,
+    pub y: usize,
+    pub palette_index: Option<u8>,
+}
+
+impl SheetView {
+    pub fn new(width: u32, height: u32) -> SheetView {
+        SheetView {
+            zoom: 2,
+            grid: false,
+            pan: (0.0, 0.0),
+            width,
+            height,
+        }
+    }
+
+    /// Zoom in or out while keeping the sheet pixel under (x, y) in place
+    pub fn zoom_around(&mut self, zoom: u32, x: f64, y: f64) {
+        let zoom = zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        let (sheet_x, sheet_y) = self.to_sheet(x, y);
+        self.zoom = zoom;
+        self.pan = (sheet_x - x / zoom as f64, sheet_y - y / zoom as f64);
+    }
+
+    /// Move the view by a distance in screen pixels
+    pub fn drag(&mut self, dx: f64, dy: f64, sheet_width: u32, sheet_height: u32) {
+        self.pan.0 -= dx / self.zoom as f64;
+        self.pan.1 -= dy / self.zoom as f64;
+        self.clamp(sheet_width, sheet_height);
+    }
+
+    /// Keep at least part of the sheet in view
+    pub fn clamp(&mut self, sheet_width: u32, sheet_height: u32) {
+        let max_x = (sheet_width as f64 - self.width as f64 / self.zoom as f64).max(0.0);
+        let max_y = (sheet_height as f64 - self.height as f64 / self.zoom as f64).max(0.0);
+        self.pan.0 = self.pan.0.max(0.0).min(max_x);
+        self.pan.1 = self.pan.1.max(0.0).min(max_y);
+    }
+
+    /// Convert a position in the view (from the top left) to sheet pixels
+    pub fn to_sheet(&self, x: f64, y: f64) -> (f64, f64) {
+        (self.pan.0 + x / self.zoom as f64, self.pan.1 + y / self.zoom as f64)
+    }
+
+    /// Sheet pixel at a position in the view, None outside of the sheet
+    pub fn pixel_at(&self, x: f64, y: f64, sheet_width: u32, sheet_height: u32) -> Option<(u32, u32)> {
+        let (sheet_x, sheet_y) = self.to_sheet(x, y);
+        if sheet_x < 0.0 || sheet_y < 0.0 || sheet_x >= sheet_width as f64 || sheet_y >= sheet_height as f64 {
+            return None;
+        }
+        Some((sheet_x as u32, sheet_y as u32))
+    }
+
+    /// Render the visible part of a sheet of `frame_width` x `frame_height` frames at the view's size
+    pub fn render(&self, sheet: &ImageBuffer<Rgb<u8>, Vec<u8>>, frame_width: u32, frame_height: u32) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut image = ImageBuffer::from_pixel(self.width, self.height, Rgb { data: BACKGROUND });
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (sx, sy) = match self.pixel_at(x as f64, y as f64, sheet.width(), sheet.height()) {
+                    Some(pixel) => pixel,
+                    None => continue,
+                };
+
+                // grid lines go on the first screen pixel of a sheet pixel
+                let first_x = self.pixel_at(x as f64 - 1.0, y as f64, sheet.width(), sheet.height()).map_or(true, |p| p.0 != sx);
+                let first_y = self.pixel_at(x as f64, y as f64 - 1.0, sheet.width(), sheet.height()).map_or(true, |p| p.1 != sy);
+                let data = if self.grid && ((first_x && sx % frame_width == 0) || (first_y && sy % frame_height == 0)) {
+                    FRAME_GRID
+                } else if self.grid && self.zoom > 1 && ((first_x && sx % SECTION_SIZE as u32 == 0) || (first_y && sy % SECTION_SIZE as u32 == 0)) {
+                    SECTION_GRID
+                } else {
+                    sheet.get_pixel(sx, sy).data
+                };
+                image.get_pixel_mut(x, y).data = data;
+            }
+        }
+        image
+    }
+}
+
+/// Find the animation, frame, section and palette index at a pixel of a spritesheet image
+pub fn hover_info(spritesheet: &Spritesheet, x: u32, y: u32) -> HoverInfo {
+    let (frame_width, frame_height) = (spritesheet.shape.pixel_width(), spritesheet.shape.pixel_height());
+    let (x, y) = (x as usize, y as usize);
+    let animation = x / frame_width;
+    let frame = y / frame_height;
+    let (px, py) = (x % frame_width, y % frame_height);
+
+    let palette_index = spritesheet.animations.get(animation)
+        .and_then(|a| a.frames.get(frame))
+        .map(|f| f.get_pixel(px, py));
+
+    HoverInfo {
+        animation,
+        frame,
+        section: (py / SECTION_SIZE) * spritesheet.shape.width() + px / SECTION_SIZE,
+        x: px,
+        y: py,
+        palette_index,
+    }
+}
+
+/// earlier frames are tinted red and later ones blue in onion skins
+const BEFORE_TINT: Color = Color { r: 255, g: 60, b: 60 };
+const AFTER_TINT: Color = Color { r: 60, g: 120, b: 255 };
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CompareMode {
+    SideBySide,
+    Difference,
+}
+
+fn blend(under: [u8; 3], over: Color, opacity: f32) -> [u8; 3] {
+    let mix = |a: u8, b: i32| (a as f32 * (1.0 - opacity) + b as f32 * opacity) as u8;
+    [mix(under[0], over.r), mix(under[1], over.g), mix(under[2], over.b)]
+}
+
+fn tint(color: Color, tint: Color) -> Color {
+    Color { r: (color.r + tint.r) / 2, g: (color.g + tint.g) / 2, b: (color.b + tint.b) / 2 }
+}
+
+/// Draw a frame over faded, tinted copies of the frames around it. `before` and `after` are
+/// ordered from the nearest frame outwards and fade out the further away they are.
+pub fn onion_skin(current: &Frame, before: &[&Frame], after: &[&Frame], opacity: f32, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let background = palette[0];
+    let mut image = ImageBuffer::from_pixel(current.width() as u32, current.height() as u32,
+                                            Rgb { data: [background.r as u8, background.g as u8, background.b as u8] });
+
+    let layers = before.iter().enumerate().map(|(i, frame)| (i, frame, BEFORE_TINT))
+        .chain(after.iter().enumerate().map(|(i, frame)| (i, frame, AFTER_TINT)))
+        .collect::<Vec<_>>();
+
+    // furthest frames first so the nearer ones end up on top
+    for distance in (0..before.len().max(after.len())).rev() {
+        let layer_opacity = opacity * (1.0 - distance as f32 / (before.len().max(after.len()) + 1) as f32);
+        for &(_, frame, color) in layers.iter().filter(|&&(i, _, _)| i == distance) {
+            for y in 0..frame.height() {
+                for x in 0..frame.width() {
+                    let index = frame.get_pixel(x, y) as usize;
+                    if index == 0 {
+                        continue;
+                    }
+                    let pixel = image.get_pixel_mut(x as u32, y as u32);
+                    pixel.data = blend(pixel.data, tint(palette[index], color), layer_opacity);
+                }
+            }
+        }
+    }
+
+    for y in 0..current.height() {
+        for x in 0..current.width() {
+            let index = current.get_pixel(x, y) as usize;
+            if index != 0 {
+                let c = palette[index];
+                image.get_pixel_mut(x as u32, y as u32).data = [c.r as u8, c.g as u8, c.b as u8];
+            }
+        }
+    }
+    image
+}
+
+/// Compare two frames, either next to each other or as a difference image where unchanged pixels
+/// are greyed out, pixels only in `a` are red, pixels only in `b` are green and pixels in both
+/// that changed color are yellow
+pub fn compare_frames(a: &Frame, b: &Frame, mode: CompareMode, palette: &[Color]) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    match mode {
+        CompareMode::SideBySide => {
+            let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new((a.width() + b.width()) as u32, a.height().max(b.height()) as u32);
+            for (offset, frame) in [(0, a), (a.width(), b)].iter() {
+                for y in 0..frame.height() {
+                    for x in 0..frame.width() {
+                        let c = palette[frame.get_pixel(x, y) as usize];
+                        image.get_pixel_mut((offset + x) as u32, y as u32).data = [c.r as u8, c.g as u8, c.b as u8];
+                    }
+                }
+            }
+            image
+        }
+        CompareMode::Difference => {
+            let mut image = ImageBuffer::<Rgb<u8>, Vec<u8>>::new(a.width() as u32, a.height() as u32);
+            for y in 0..a.height() {
+                for x in 0..a.width() {
+                    let (ia, ib) = (a.get_pixel(x, y), b.get_pixel(x, y));
+                    let data = if ia == ib {
+                        let c = palette[ia as usize];
+                        let grey = ((c.r + c.g + c.b) / 6) as u8;
+                        [grey, grey, grey]
+                    } else if ib == 0 {
+                        [255, 0, 0]
+                    } else if ia == 0 {
+                        [0, 255, 0]
+                    } else {
+                        [255, 255, 0]
+                    };
+                    image.get_pixel_mut(x as u32, y as u32).data = data;
+                }
+            }
+            image
+        }
+    }
+}